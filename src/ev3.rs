@@ -1,2 +1,13 @@
-pub mod parser;
-pub mod project;
+pub(crate) mod builder;
+pub(crate) mod edit;
+pub(crate) mod error;
+pub(crate) mod graph;
+pub(crate) mod layout;
+pub(crate) mod manifest;
+pub(crate) mod parser;
+pub(crate) mod preserved;
+pub(crate) mod project;
+pub(crate) mod terminal;
+pub(crate) mod variables;
+pub(crate) mod walk;
+pub(crate) mod writer;
@@ -0,0 +1,351 @@
+//! Building programs in code instead of parsing them
+
+use super::error::{Error, Result};
+use super::parser::{
+    Block, BlockType, Diagram, Id, Item, MotorDuration, MotorSize, Port, SequenceBlock,
+    SequenceBlockType, Wait, Wire, WireEnd,
+};
+use super::project::{File, Version};
+use quick_xml::events::BytesDecl;
+
+/// Builds a program one block at a time, each running after the one before it:
+///
+/// ```
+/// use mindstormer::{FileBuilder, Port};
+///
+/// let file = FileBuilder::new()
+///     .start()
+///     .move_steering((Port::B, Port::C), 0.0, 50.0)
+///     .wait_seconds(1.0)
+///     .build("Program.ev3p");
+/// assert_eq!(file.diagram.blocks.len(), 3);
+/// ```
+///
+/// Ids are numbered the way the EV3 software does, with blocks and wires sharing the count, and
+/// blocks are laid out left to right with [`File::lay_out`].
+#[derive(Clone, Debug, Default)]
+pub struct FileBuilder {
+    diagram: Diagram,
+    /// Last id handed out
+    count: usize,
+    /// The block the next one is wired after
+    last: Option<Id>,
+}
+
+impl FileBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a start block, which the blocks after it are wired to. Adding another one starts a
+    /// sequence running alongside the first
+    pub fn start(mut self) -> Self {
+        let block = Block {
+            ty: BlockType::Start,
+            bounds: Default::default(),
+            sequence_in: None,
+            sequence_out: Some(sequence(SequenceBlockType::Out)),
+            data_wires: Default::default(),
+            layer: 1,
+            extra: Default::default(),
+        };
+        let id = self.add(block);
+        self.last = Some(id);
+        self
+    }
+
+    /// Adds a block that runs after the last one. Blocks added before any start block never
+    /// run. Fails for start blocks, which are added with [`FileBuilder::start`], for structures
+    /// like switches and loops along with the sensor compare blocks driving them, since they need
+    /// diagrams of their own, and for unknown blocks, which only parsing can keep
+    pub fn block(self, ty: BlockType) -> Result<Self> {
+        let kind = match ty {
+            BlockType::Start => "start",
+            BlockType::Switch { .. } => "switch",
+            BlockType::Loop { .. } => "loop",
+            BlockType::SensorCompare { .. } => "sensor compare",
+            BlockType::Unknown { .. } => "unknown",
+            _ => return Ok(self.push(ty)),
+        };
+        Err(Error::UnbuildableBlock(kind.into()))
+    }
+
+    /// Adds a block that isn't a structure after the last one, wiring it up
+    fn push(mut self, ty: BlockType) -> Self {
+        let block = Block {
+            ty,
+            bounds: Default::default(),
+            sequence_in: Some(sequence(SequenceBlockType::In)),
+            sequence_out: Some(sequence(SequenceBlockType::Out)),
+            data_wires: Default::default(),
+            layer: 1,
+            extra: Default::default(),
+        };
+        let id = self.add(block);
+        if let Some(last) = self.last.replace(id.clone()) {
+            let wire_id = self.next_id("w");
+            self.diagram.blocks.get_mut(&last).unwrap().sequence_out =
+                Some(wired(SequenceBlockType::Out, &wire_id));
+            self.diagram.blocks.get_mut(&id).unwrap().sequence_in =
+                Some(wired(SequenceBlockType::In, &wire_id));
+            let wire = Wire {
+                output: WireEnd {
+                    node: last,
+                    terminal: "SequenceOut".into(),
+                },
+                input: WireEnd {
+                    node: id,
+                    terminal: "SequenceIn".into(),
+                },
+                joints: vec![],
+                branches: vec![],
+                extra: Default::default(),
+            };
+            self.diagram.order.push(Item::Wire(wire_id.clone()));
+            self.diagram.wires.insert(wire_id, wire);
+        }
+        self
+    }
+
+    /// Adds a Move Steering block running until the next block stops it
+    pub fn move_steering(self, ports: (Port, Port), steering: f64, speed: f64) -> Self {
+        self.move_steering_for(ports, steering, speed, MotorDuration::Unlimited)
+    }
+
+    pub fn move_steering_for(
+        self,
+        ports: (Port, Port),
+        steering: f64,
+        speed: f64,
+        duration: MotorDuration,
+    ) -> Self {
+        self.push(BlockType::MotorMove {
+            ports,
+            steering,
+            speed,
+            duration,
+        })
+    }
+
+    /// Adds a Move Tank block running until the next block stops it
+    pub fn move_tank(self, ports: (Port, Port), left_speed: f64, right_speed: f64) -> Self {
+        self.move_tank_for(ports, left_speed, right_speed, MotorDuration::Unlimited)
+    }
+
+    pub fn move_tank_for(
+        self,
+        ports: (Port, Port),
+        left_speed: f64,
+        right_speed: f64,
+        duration: MotorDuration,
+    ) -> Self {
+        self.push(BlockType::MotorTank {
+            ports,
+            left_speed,
+            right_speed,
+            duration,
+        })
+    }
+
+    /// Adds a Move Tank block stopping both motors
+    pub fn move_tank_off(self, ports: (Port, Port), brake: bool) -> Self {
+        self.push(BlockType::MotorTankOff { ports, brake })
+    }
+
+    /// Adds a Large Motor or Medium Motor block
+    pub fn motor(self, size: MotorSize, port: Port, speed: f64, duration: MotorDuration) -> Self {
+        self.push(BlockType::Motor {
+            size,
+            port,
+            speed,
+            duration,
+        })
+    }
+
+    pub fn motor_off(self, size: MotorSize, port: Port, brake: bool) -> Self {
+        self.push(BlockType::MotorOff { size, port, brake })
+    }
+
+    pub fn wait(self, wait: Wait) -> Self {
+        self.push(BlockType::Wait(wait))
+    }
+
+    pub fn wait_seconds(self, seconds: f64) -> Self {
+        self.wait(Wait::Time { seconds })
+    }
+
+    /// The program, named like `Program.ev3p`
+    pub fn build(self, name: impl Into<String>) -> File {
+        let mut file = File {
+            decl: BytesDecl::new("1.0", Some("utf-8"), None),
+            version: Version {
+                number: "1.0.2.10".into(),
+                namespace: "http://www.ni.com/SourceModel.xsd".into(),
+            },
+            name: name.into(),
+            diagram: self.diagram,
+            extra: None,
+        };
+        file.lay_out();
+        file
+    }
+
+    /// Adds the block after the last one added and gives it a fresh id
+    fn add(&mut self, block: Block) -> Id {
+        let id = self.next_id("n");
+        self.diagram.order.push(Item::Block(id.clone()));
+        self.diagram.blocks.insert(id.clone(), block);
+        id
+    }
+
+    fn next_id(&mut self, prefix: &str) -> Id {
+        self.count += 1;
+        Id::new(format!("{prefix}{}", self.count))
+    }
+}
+
+fn sequence(ty: SequenceBlockType) -> SequenceBlock {
+    SequenceBlock { ty, wire_id: None }
+}
+
+fn wired(ty: SequenceBlockType, wire: &Id) -> SequenceBlock {
+    SequenceBlock {
+        ty,
+        wire_id: Some(wire.clone()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FileBuilder;
+    use crate::ev3::error::Error;
+    use crate::ev3::parser::{BlockType, Id, MotorDuration, MotorSize, Port, Wait};
+    use crate::ev3::project::File;
+    use std::fs;
+
+    #[test]
+    fn builds_what_the_ev3_software_does() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/examples/2blocks.ev3p");
+        let parsed = File::new("2blocks.ev3p", fs::read(path).unwrap()).unwrap();
+        let built = FileBuilder::new()
+            .start()
+            .move_steering((Port::A, Port::B), 0.0, 50.0)
+            .move_steering_for(
+                (Port::B, Port::C),
+                0.0,
+                50.0,
+                MotorDuration::Rotations {
+                    rotations: 1.0,
+                    brake: true,
+                },
+            )
+            .build("2blocks.ev3p");
+        assert_eq!(built, parsed);
+        assert_eq!(File::new("2blocks.ev3p", built.to_bytes()).unwrap(), parsed);
+    }
+
+    #[test]
+    fn tank_blocks_round_trip() {
+        let ports = (Port::B, Port::C);
+        let file = FileBuilder::new()
+            .start()
+            .move_tank(ports, 50.0, -50.0)
+            .move_tank_for(
+                ports,
+                30.0,
+                30.0,
+                MotorDuration::Rotations {
+                    rotations: 2.5,
+                    brake: true,
+                },
+            )
+            .move_tank_for(
+                ports,
+                30.0,
+                40.0,
+                MotorDuration::Degrees {
+                    degrees: 360.0,
+                    brake: false,
+                },
+            )
+            .move_tank_for(
+                ports,
+                75.0,
+                25.0,
+                MotorDuration::Seconds {
+                    seconds: 1.5,
+                    brake: true,
+                },
+            )
+            .move_tank_off(ports, true)
+            .build("Tank.ev3p");
+        let bytes = file.to_bytes();
+        let contents = String::from_utf8_lossy(&bytes);
+        for target in [
+            "MoveTankUnlimited",
+            "MoveTankDistanceRotations",
+            "MoveTankDistance\\",
+            "MoveTankTime",
+            "MoveTankStop",
+        ] {
+            assert!(contents.contains(target), "Missing {target}");
+        }
+        assert_eq!(File::new("Tank.ev3p", bytes).unwrap(), file);
+    }
+
+    #[test]
+    fn motor_blocks_round_trip() {
+        let durations = [
+            MotorDuration::Unlimited,
+            MotorDuration::Seconds {
+                seconds: 2.0,
+                brake: true,
+            },
+            MotorDuration::Degrees {
+                degrees: 90.0,
+                brake: false,
+            },
+            MotorDuration::Rotations {
+                rotations: 1.5,
+                brake: true,
+            },
+        ];
+        for size in [MotorSize::Large, MotorSize::Medium] {
+            let mut builder = FileBuilder::new().start();
+            for duration in durations.clone() {
+                builder = builder.motor(size, Port::A, 75.0, duration);
+            }
+            let file = builder.motor_off(size, Port::D, false).build("Motor.ev3p");
+            assert_eq!(File::new("Motor.ev3p", file.to_bytes()).unwrap(), file);
+        }
+    }
+
+    #[test]
+    fn keeps_the_layer_of_ports() {
+        let mut file = FileBuilder::new()
+            .start()
+            .motor(MotorSize::Medium, Port::B, 50.0, MotorDuration::Unlimited)
+            .build("Layer.ev3p");
+        // The start block doesn't have ports to keep it in
+        file.diagram.blocks.get_mut(&Id::new("n2")).unwrap().layer = 3;
+        let bytes = file.to_bytes();
+        assert!(String::from_utf8_lossy(&bytes).contains("Value=\"3.B\""));
+        assert_eq!(File::new("Layer.ev3p", bytes).unwrap(), file);
+    }
+
+    #[test]
+    fn only_builds_blocks_without_diagrams() {
+        let builder = FileBuilder::new().start();
+        let err = builder.clone().block(BlockType::Start).unwrap_err();
+        assert!(matches!(err, Error::UnbuildableBlock(kind) if kind == "start"));
+        let unknown = BlockType::Unknown {
+            target: "PlaySound\\.vix".into(),
+        };
+        assert!(builder.clone().block(unknown).is_err());
+        let file = builder
+            .block(BlockType::Wait(Wait::Time { seconds: 1.0 }))
+            .unwrap()
+            .build("Program.ev3p");
+        assert_eq!(file.diagram.wires.len(), 1);
+    }
+}
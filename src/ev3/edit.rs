@@ -0,0 +1,450 @@
+//! Editing the sequence of a diagram, keeping its blocks, wires and the terminals connecting them
+//! consistent
+
+use super::error::{Error, Result};
+use super::parser::{Block, BlockType, Diagram, Id, Item, SequenceBlock, SequenceBlockType};
+use super::parser::{Wire, WireEnd};
+use super::project::File;
+
+impl Diagram {
+    /// Adds `block` to run right after the block `after`, before whatever ran after it. The
+    /// block's own wiring is replaced, and it's given a fresh id which is returned
+    pub fn insert_after(&mut self, after: &Id, block: Block) -> Result<Id> {
+        let id = self.fresh_id("n");
+        self.insert_as(after, id.clone(), block)?;
+        Ok(id)
+    }
+
+    /// Removes the block, wiring the blocks before and after it together. Every other wire
+    /// connected to it is removed along with it. A sensor compare block and the switch it's paired
+    /// with are removed together, and only the one asked for is returned
+    pub fn remove_block(&mut self, id: &Id) -> Result<Block> {
+        let mut removed = self.remove_unit(id)?;
+        let index = removed.iter().position(|(other, _)| other == id).unwrap();
+        Ok(removed.swap_remove(index).1)
+    }
+
+    /// Moves the block to run right after the block `after`, keeping its id. Data wires connected
+    /// to it are removed, like when removing it. A sensor compare block and the switch it's
+    /// paired with move together
+    pub fn move_after(&mut self, id: &Id, after: &Id) -> Result<()> {
+        let unit = self.unit(id)?;
+        if unit.contains(after) {
+            return Err(invalid(id, "it can't run after itself"));
+        }
+        // Checked before removing it, so that nothing changes when it can't go there
+        self.check_after(after)?;
+        if let [id] = unit.as_slice() {
+            check_insertable(id, &self.blocks[id].ty)?;
+        }
+        let blocks = self.remove_unit(id)?;
+        self.insert_unit(after, blocks);
+        Ok(())
+    }
+
+    /// Changes what the block does, returning what it did before. Data wires connected to
+    /// terminals the block doesn't have anymore are removed
+    pub fn replace_type(&mut self, id: &Id, ty: BlockType) -> Result<BlockType> {
+        let block = self.block(id)?;
+        check_insertable(id, &block.ty)?;
+        check_insertable(id, &ty)?;
+        let block = self.blocks.get_mut(id).unwrap();
+        let old = std::mem::replace(&mut block.ty, ty);
+        // What was kept of the old terminals and children doesn't fit the new type
+        block.extra.terminals.clear();
+        block.extra.children.clear();
+        let terminals: Vec<_> = block.terminals().into_iter().map(|t| t.id).collect();
+        let stale: Vec<_> = block
+            .data_wires
+            .iter()
+            .filter(|(terminal, _)| !terminals.contains(terminal))
+            .map(|(_, wire)| wire.clone())
+            .collect();
+        for wire in stale {
+            self.remove_wire(&wire);
+        }
+        Ok(old)
+    }
+
+    /// The blocks that go wherever the block goes, in the order the sequence runs through them,
+    /// which is the block itself unless it's a sensor compare block or the switch paired with one
+    fn unit(&self, id: &Id) -> Result<Vec<Id>> {
+        let block = self.block(id)?;
+        let Some(paired) = block.ty.paired() else {
+            return Ok(vec![id.clone()]);
+        };
+        if !self.is_block(paired) {
+            return Err(invalid(id, "the block it's paired with is missing"));
+        }
+        Ok(match block.ty {
+            BlockType::SensorCompare { .. } => vec![id.clone(), paired.clone()],
+            _ => vec![paired.clone(), id.clone()],
+        })
+    }
+
+    /// Removes the block along with the ones that go wherever it goes, wiring the blocks before
+    /// and after them together
+    fn remove_unit(&mut self, id: &Id) -> Result<Vec<(Id, Block)>> {
+        let unit = self.unit(id)?;
+        let (first, last) = (&unit[0], &unit[unit.len() - 1]);
+        let incoming = self.sequence_wire(self.blocks[first].sequence_in.as_ref());
+        let outgoing = self.sequence_wire(self.blocks[last].sequence_out.as_ref());
+        // Wires coming from the entry of a structure's diagram or going to its exit have to stay,
+        // since the nodes there refer to them
+        match (incoming, outgoing) {
+            (Some((incoming, previous)), Some((outgoing, _))) if self.is_block(&previous) => {
+                self.remove_wire(&incoming);
+                self.reconnect_output(&outgoing, &previous);
+            }
+            (Some((incoming, _)), Some((outgoing, next))) if self.is_block(&next) => {
+                self.remove_wire(&outgoing);
+                self.reconnect_input(&incoming, &next);
+            }
+            (Some((_, end)), Some(_)) | (Some((_, end)), None) | (None, Some((_, end)))
+                if !self.is_block(&end) =>
+            {
+                return Err(invalid(id, "it's the only block in its structure"));
+            }
+            (incoming, outgoing) => {
+                for (wire, _) in incoming.into_iter().chain(outgoing) {
+                    self.remove_wire(&wire);
+                }
+            }
+        }
+
+        let wires: Vec<_> = self
+            .wires
+            .iter()
+            .filter(|(_, wire)| {
+                unit.contains(&wire.output.node)
+                    || wire.inputs().any(|input| unit.contains(&input.node))
+            })
+            .map(|(wire, _)| wire.clone())
+            .collect();
+        for wire in wires {
+            self.remove_wire(&wire);
+        }
+        self.order
+            .retain(|item| !matches!(item, Item::Block(block) if unit.contains(block)));
+        Ok(unit
+            .into_iter()
+            .map(|id| {
+                let block = self.blocks.remove(&id).unwrap();
+                (id, block)
+            })
+            .collect())
+    }
+
+    /// Checks that a block can run right after the block `after`
+    fn check_after(&self, after: &Id) -> Result<()> {
+        let previous = self.block(after)?;
+        if matches!(previous.ty, BlockType::SensorCompare { .. }) {
+            return Err(invalid(
+                after,
+                "the sequence carries on through the structure it's paired with",
+            ));
+        }
+        if previous.sequence_out.is_none() {
+            return Err(invalid(after, "nothing can run after it"));
+        }
+        Ok(())
+    }
+
+    fn insert_as(&mut self, after: &Id, id: Id, mut block: Block) -> Result<()> {
+        self.check_after(after)?;
+        check_insertable(&id, &block.ty)?;
+        block.sequence_in = Some(SequenceBlock {
+            ty: SequenceBlockType::In,
+            wire_id: None,
+        });
+        block.sequence_out = Some(SequenceBlock {
+            ty: SequenceBlockType::Out,
+            wire_id: None,
+        });
+        self.insert_unit(after, vec![(id, block)]);
+        Ok(())
+    }
+
+    /// Adds the blocks to run right after the block `after`, the sequence going into the first
+    /// one and coming out of the last one
+    fn insert_unit(&mut self, after: &Id, blocks: Vec<(Id, Block)>) {
+        let outgoing = self.sequence_wire(self.blocks[after].sequence_out.as_ref());
+        let first = blocks[0].0.clone();
+        let last = blocks[blocks.len() - 1].0.clone();
+        let mut position = self.position(&Item::Block(after.clone()));
+        for (id, mut block) in blocks {
+            // Its wires are in the diagram or the place it came from
+            for sequence in [&mut block.sequence_in, &mut block.sequence_out]
+                .into_iter()
+                .flatten()
+            {
+                sequence.wire_id = None;
+            }
+            block.data_wires.clear();
+            self.blocks.insert(id.clone(), block);
+            self.order.insert(position, Item::Block(id));
+            position += 1;
+        }
+
+        // The wire to whatever ran next now comes out of the last block, which keeps the other
+        // end as it is even when it's the exit of a structure's diagram
+        if let Some((outgoing, _)) = outgoing {
+            self.reconnect_output(&outgoing, &last);
+        }
+        self.connect(after, &first);
+    }
+
+    /// Adds a new sequence wire from the block `from` to the block `to`, placed after `to`
+    fn connect(&mut self, from: &Id, to: &Id) {
+        let id = self.fresh_id("w");
+        set_wire(&mut self.blocks.get_mut(from).unwrap().sequence_out, &id);
+        set_wire(&mut self.blocks.get_mut(to).unwrap().sequence_in, &id);
+        let wire = Wire {
+            output: WireEnd {
+                node: from.clone(),
+                terminal: "SequenceOut".into(),
+            },
+            input: WireEnd {
+                node: to.clone(),
+                terminal: "SequenceIn".into(),
+            },
+            joints: vec![],
+            branches: vec![],
+            extra: Default::default(),
+        };
+        let position = self.position(&Item::Block(to.clone()));
+        self.order.insert(position, Item::Wire(id.clone()));
+        self.wires.insert(id, wire);
+    }
+
+    /// Makes the wire come out of the sequence terminal of `block` instead
+    fn reconnect_output(&mut self, wire: &Id, block: &Id) {
+        let wire_id = wire;
+        let wire = self.wires.get_mut(wire_id).unwrap();
+        wire.output = WireEnd {
+            node: block.clone(),
+            terminal: "SequenceOut".into(),
+        };
+        // The bends were for where it was before
+        wire.joints.clear();
+        set_wire(
+            &mut self.blocks.get_mut(block).unwrap().sequence_out,
+            wire_id,
+        );
+    }
+
+    /// Makes the wire go into the sequence terminal of `block` instead
+    fn reconnect_input(&mut self, wire: &Id, block: &Id) {
+        let wire_id = wire;
+        let wire = self.wires.get_mut(wire_id).unwrap();
+        wire.input = WireEnd {
+            node: block.clone(),
+            terminal: "SequenceIn".into(),
+        };
+        wire.joints.clear();
+        set_wire(
+            &mut self.blocks.get_mut(block).unwrap().sequence_in,
+            wire_id,
+        );
+    }
+
+    /// Removes the wire along with whatever refers to it
+    fn remove_wire(&mut self, id: &Id) {
+        let Some(wire) = self.wires.remove(id) else {
+            return;
+        };
+        self.order.retain(|item| item != &Item::Wire(id.clone()));
+        for end in std::iter::once(&wire.output).chain(wire.inputs()) {
+            let Some(block) = self.blocks.get_mut(&end.node) else {
+                continue;
+            };
+            for sequence in [&mut block.sequence_in, &mut block.sequence_out]
+                .into_iter()
+                .flatten()
+            {
+                if sequence.wire_id.as_ref() == Some(id) {
+                    sequence.wire_id = None;
+                }
+            }
+            block.data_wires.retain(|_, wire| wire != id);
+        }
+    }
+
+    /// The existing wire connected to a sequence terminal, along with the node at its other end
+    fn sequence_wire(&self, sequence: Option<&SequenceBlock>) -> Option<(Id, Id)> {
+        let sequence = sequence?;
+        let id = sequence.wire_id.as_ref()?;
+        let wire = self.wires.get(id)?;
+        let other = match sequence.ty {
+            SequenceBlockType::In => &wire.output.node,
+            SequenceBlockType::Out => &wire.input.node,
+        };
+        Some((id.clone(), other.clone()))
+    }
+
+    fn block(&self, id: &Id) -> Result<&Block> {
+        self.blocks
+            .get(id)
+            .ok_or_else(|| Error::MissingBlock(id.clone()))
+    }
+
+    fn is_block(&self, id: &Id) -> bool {
+        self.blocks.contains_key(id)
+    }
+
+    /// Index in `order` right after the item, or the end if it isn't there
+    fn position(&self, item: &Item) -> usize {
+        self.order
+            .iter()
+            .position(|other| other == item)
+            .map_or(self.order.len(), |index| index + 1)
+    }
+
+    /// An id that isn't used anywhere in the diagram or the ones nested in it, numbered after
+    /// the highest one like the EV3 software does
+    fn fresh_id(&self, prefix: &str) -> Id {
+        Id::new(format!("{prefix}{}", self.highest_id() + 1))
+    }
+
+    fn highest_id(&self) -> usize {
+        let number = |id: &Id| {
+            let digits = id
+                .as_str()
+                .trim_start_matches(|c: char| !c.is_ascii_digit());
+            digits.parse().unwrap_or(0)
+        };
+        let mut highest = self.blocks.keys().chain(self.wires.keys()).map(number);
+        let highest = highest.by_ref().max().unwrap_or(0);
+        let nested = self.blocks.values().flat_map(|block| match &block.ty {
+            BlockType::Switch { cases, .. } => cases.iter().map(|case| &case.diagram).collect(),
+            BlockType::Loop { diagram, .. } => vec![diagram],
+            _ => vec![],
+        });
+        nested.map(Diagram::highest_id).fold(highest, usize::max)
+    }
+}
+
+/// Start blocks and paired blocks can't go anywhere else in a sequence
+fn check_insertable(id: &Id, ty: &BlockType) -> Result<()> {
+    if matches!(ty, BlockType::Start) {
+        return Err(invalid(id, "start blocks can't be moved into a sequence"));
+    }
+    if ty.paired().is_some() {
+        return Err(invalid(id, "it's paired with another block"));
+    }
+    Ok(())
+}
+
+fn set_wire(sequence: &mut Option<SequenceBlock>, wire: &Id) {
+    if let Some(sequence) = sequence {
+        sequence.wire_id = Some(wire.clone());
+    }
+}
+
+fn invalid(block: &Id, message: &str) -> Error {
+    Error::InvalidEdit {
+        block: block.clone(),
+        message: message.into(),
+    }
+}
+
+/// Edits of the root diagram, see [`Diagram`] for editing the diagrams of structures
+impl File {
+    pub fn insert_after(&mut self, after: &Id, block: Block) -> Result<Id> {
+        self.diagram.insert_after(after, block)
+    }
+
+    pub fn remove_block(&mut self, id: &Id) -> Result<Block> {
+        self.diagram.remove_block(id)
+    }
+
+    pub fn move_after(&mut self, id: &Id, after: &Id) -> Result<()> {
+        self.diagram.move_after(id, after)
+    }
+
+    pub fn replace_type(&mut self, id: &Id, ty: BlockType) -> Result<BlockType> {
+        self.diagram.replace_type(id, ty)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ev3::error::Error;
+    use crate::ev3::parser::{BlockType, Id, Wait};
+    use crate::ev3::project::File;
+    use std::fs;
+
+    fn read(name: &str) -> File {
+        let path = format!("{}/examples/{name}", env!("CARGO_MANIFEST_DIR"));
+        File::new(name, fs::read(path).unwrap()).unwrap()
+    }
+
+    fn order(file: &File) -> Vec<String> {
+        file.sequence().order().iter().map(Id::to_string).collect()
+    }
+
+    #[test]
+    fn edits_keep_the_wiring_consistent() {
+        let mut file = read("2blocks.ev3p");
+        let mut wait = file.diagram.blocks[&Id::new("n2")].clone();
+        wait.ty = BlockType::Wait(Wait::Time { seconds: 1.0 });
+        let id = file.insert_after(&Id::new("n1"), wait).unwrap();
+        assert_eq!(id, Id::new("n6"));
+        assert_eq!(order(&file), ["n1", "n6", "n2", "n4"]);
+        assert!(file.validate().is_empty());
+
+        file.move_after(&Id::new("n4"), &Id::new("n6")).unwrap();
+        assert_eq!(order(&file), ["n1", "n6", "n4", "n2"]);
+        assert!(file.validate().is_empty());
+        file.move_after(&Id::new("n2"), &Id::new("n6")).unwrap();
+
+        file.remove_block(&Id::new("n6")).unwrap();
+        assert_eq!(order(&file), ["n1", "n2", "n4"]);
+        assert!(file.validate().is_empty());
+        assert_eq!(File::new("2blocks.ev3p", file.to_bytes()).unwrap(), file);
+
+        let mut file = read("2blockandif.ev3p");
+        let BlockType::Switch { cases, .. } = &mut file.diagram.blocks.get_mut(&Id::new("n0")).unwrap().ty
+        else {
+            panic!("n0 isn't a switch");
+        };
+        assert!(matches!(
+            cases[0].diagram.remove_block(&Id::new("n11")),
+            Err(Error::InvalidEdit { .. })
+        ));
+
+        // Nothing runs after the sensor compare block but its switch
+        let mut file = read("2blockandif.ev3p");
+        assert!(matches!(
+            file.move_after(&Id::new("n2"), &Id::new("n6")),
+            Err(Error::InvalidEdit { .. })
+        ));
+        assert_eq!(file, read("2blockandif.ev3p"));
+    }
+
+    #[test]
+    fn sensor_compare_blocks_go_with_their_switch() {
+        let mut file = read("2blockandif.ev3p");
+        file.move_after(&Id::new("n0"), &Id::new("n1")).unwrap();
+        assert_eq!(order(&file), ["n1", "n6", "n0", "n2", "n4"]);
+        assert!(file.validate().is_empty());
+        assert_eq!(
+            File::new("2blockandif.ev3p", file.to_bytes()).unwrap(),
+            file
+        );
+
+        file.move_after(&Id::new("n6"), &Id::new("n4")).unwrap();
+        assert_eq!(order(&file), ["n1", "n2", "n4", "n6", "n0"]);
+        assert!(file.validate().is_empty());
+
+        let removed = file.remove_block(&Id::new("n6")).unwrap();
+        assert!(matches!(removed.ty, BlockType::SensorCompare { .. }));
+        assert!(!file.diagram.blocks.contains_key(&Id::new("n0")));
+        assert_eq!(order(&file), ["n1", "n2", "n4"]);
+        assert!(file.validate().is_empty());
+        assert_eq!(
+            File::new("2blockandif.ev3p", file.to_bytes()).unwrap(),
+            file
+        );
+    }
+}
@@ -0,0 +1,178 @@
+//! Errors from reading and writing projects, which say what went wrong and where
+
+use super::parser::Id;
+use std::path::PathBuf;
+
+/// Where something is in a file, written like `12:5`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Position {
+    /// Line, starting at 1
+    pub line: usize,
+    /// Character in the line, starting at 1
+    pub column: usize,
+    /// Byte offset from the start of the file
+    pub offset: usize,
+}
+
+impl std::fmt::Display for Position {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(fmt, "{}:{}", self.line, self.column)
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// The file isn't well-formed XML
+    #[error("{file}:{position}: malformed XML")]
+    Xml {
+        file: String,
+        position: Position,
+        #[source]
+        source: quick_xml::Error,
+    },
+    /// A block whose target, or mode, isn't supported. Parsing while preserving keeps these
+    /// blocks instead
+    #[error("{file}:{position}: unknown target `{target}` in block {block}")]
+    UnknownTarget {
+        file: String,
+        block: Id,
+        target: String,
+        position: Position,
+    },
+    /// Anything else that doesn't look like what the EV3 software writes, like unknown elements
+    /// or missing attributes
+    #[error("{file}:{position}: {message}{}", in_block(.block))]
+    Invalid {
+        file: String,
+        /// The innermost block being parsed, if any
+        block: Option<Id>,
+        position: Position,
+        /// What went wrong, along with what was being parsed at the time
+        message: String,
+    },
+    /// A file every project has, like `___ProjectTitle`, isn't in the archive
+    #[error("Missing {0} in project")]
+    MissingEntry(String),
+    /// A file in the archive whose contents don't make sense, like a title that isn't UTF-8
+    #[error("Invalid {entry} in project: {message}")]
+    InvalidEntry { entry: String, message: String },
+    /// An edit refers to a block that isn't in the diagram
+    #[error("No block {0} in the diagram")]
+    MissingBlock(Id),
+    /// An edit that would leave the diagram inconsistent, like separating a sensor compare
+    /// block from the structure it drives
+    #[error("Can't edit block {block}: {message}")]
+    InvalidEdit { block: Id, message: String },
+    /// A block [`FileBuilder::block`](crate::FileBuilder::block) can't add, named like `loop`
+    #[error("Can't build {0} blocks")]
+    UnbuildableBlock(String),
+    #[error("Invalid zip archive")]
+    Zip(#[from] zip::result::ZipError),
+    #[error("Failed accessing {}", .path.display())]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+}
+
+fn in_block(block: &Option<Id>) -> String {
+    match block {
+        Some(block) => format!(" in block {block}"),
+        None => String::new(),
+    }
+}
+
+/// Failures the parser raises that are reported as something more specific than
+/// [`Error::Invalid`], once it's known where they happened
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum Cause {
+    #[error("Malformed XML at {position}")]
+    Xml {
+        position: Position,
+        #[source]
+        source: quick_xml::Error,
+    },
+    #[error("Unknown target `{0}`")]
+    UnknownTarget(String),
+}
+
+impl Error {
+    /// Turns a failure from the parser into an error, `block` and `position` being where the
+    /// parser was at the time
+    pub(crate) fn parse(
+        file: &str,
+        block: Option<Id>,
+        position: Position,
+        err: anyhow::Error,
+    ) -> Self {
+        let file = file.to_owned();
+        match err.downcast::<Cause>() {
+            Ok(Cause::Xml { position, source }) => Error::Xml {
+                file,
+                position,
+                source,
+            },
+            Ok(Cause::UnknownTarget(target)) => match block {
+                Some(block) => Error::UnknownTarget {
+                    file,
+                    block,
+                    target,
+                    position,
+                },
+                None => Error::Invalid {
+                    file,
+                    block,
+                    position,
+                    message: format!("Unknown target `{target}`"),
+                },
+            },
+            Err(err) => Error::Invalid {
+                file,
+                block,
+                position,
+                message: format!("{err:#}"),
+            },
+        }
+    }
+
+    pub(crate) fn io(path: impl Into<PathBuf>) -> impl FnOnce(std::io::Error) -> Self {
+        let path = path.into();
+        move |source| Error::Io { path, source }
+    }
+}
+
+/// How bad a problem found while diagnosing a file is
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    /// Something that isn't supported yet, like an unknown block, which is kept as it is
+    Warning,
+    /// Something that's wrong. The block it's in is kept as it is, and if it's not in a block
+    /// parsing stops there
+    Error,
+}
+
+impl std::fmt::Display for Severity {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            Severity::Warning => write!(fmt, "warning"),
+            Severity::Error => write!(fmt, "error"),
+        }
+    }
+}
+
+/// A problem found while diagnosing a file, which is recorded instead of stopping at it
+#[derive(Debug)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// What the problem is and where it is
+    pub error: Error,
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(fmt, "{}: {}", self.severity, self.error)
+    }
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
@@ -0,0 +1,506 @@
+//! The order blocks run in, resolved from the sequence wires between them, and checks that
+//! those wires make sense
+
+use super::error::Severity;
+use super::parser::{BlockType, Diagram, Id, Item, SequenceBlock, SequenceNode, WireEnd};
+use super::project::File;
+use std::collections::{HashMap, HashSet};
+
+/// Ids the entry and exit of a structure's diagram have in the wires inside it
+const ENTRY: &str = "Output";
+const EXIT: &str = "Input";
+
+/// Something wrong with how the blocks of a diagram are wired together
+#[derive(Clone, Debug, PartialEq)]
+pub enum ProblemKind {
+    /// A sequence terminal, or the entry or exit of a structure's diagram, is connected to a
+    /// wire the diagram doesn't have
+    MissingWire { block: Id, wire: Id },
+    /// An end of a wire isn't on a terminal that's connected to it, like one on a block that
+    /// doesn't exist
+    DanglingWire { wire: Id, end: WireEnd },
+    /// A block the sequence never gets to, so it never runs
+    UnreachableBlock(Id),
+    /// The program has more than one start block
+    MultipleStarts(Vec<Id>),
+    /// Blocks whose sequence wires go around in a circle, in the order they're wired
+    Cycle(Vec<Id>),
+}
+
+impl std::fmt::Display for ProblemKind {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            ProblemKind::MissingWire { block, wire } => {
+                write!(fmt, "{block} is connected to missing wire {wire}")
+            }
+            ProblemKind::DanglingWire { wire, end } => {
+                write!(fmt, "wire {wire} isn't connected to {end}")
+            }
+            ProblemKind::UnreachableBlock(block) => write!(fmt, "block {block} is never run"),
+            ProblemKind::MultipleStarts(blocks) => {
+                write!(fmt, "multiple start blocks {}", join(blocks, ", "))
+            }
+            ProblemKind::Cycle(blocks) => {
+                write!(fmt, "sequence goes around through {}", join(blocks, " -> "))
+            }
+        }
+    }
+}
+
+/// A problem found in one of the diagrams of a program
+#[derive(Clone, Debug, PartialEq)]
+pub struct Problem {
+    /// Structures the diagram is in, outermost first, each followed by the case the diagram is
+    /// for if it's a switch. Empty for the root diagram
+    pub scope: Vec<Id>,
+    pub kind: ProblemKind,
+}
+
+impl Problem {
+    /// Blocks that never run are allowed, everything else isn't
+    pub fn severity(&self) -> Severity {
+        match self.kind {
+            ProblemKind::UnreachableBlock(_) => Severity::Warning,
+            _ => Severity::Error,
+        }
+    }
+}
+
+impl std::fmt::Display for Problem {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(fmt, "{}: {}", self.severity(), self.kind)?;
+        if !self.scope.is_empty() {
+            write!(fmt, " in {}", join(&self.scope, "/"))?;
+        }
+        Ok(())
+    }
+}
+
+fn join(ids: &[Id], separator: &str) -> String {
+    ids.iter()
+        .map(Id::as_str)
+        .collect::<Vec<_>>()
+        .join(separator)
+}
+
+/// The sequence of a diagram, resolved from its wires
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SequenceGraph {
+    /// Blocks the sequence starts at, which are the start blocks in the root diagram and the
+    /// block after the entry in the diagram of a structure
+    pub starts: Vec<Id>,
+    /// The block each block hands the sequence to, a sensor compare block handing it to the
+    /// structure it's paired with
+    pub next: HashMap<Id, Id>,
+    /// Graphs of the diagrams inside structures by the structure's id, which are one per case
+    /// for switches and the body for loops
+    pub nested: HashMap<Id, Vec<SequenceGraph>>,
+    /// Problems in this diagram, not counting the ones nested in it
+    pub problems: Vec<Problem>,
+}
+
+/// The entry and exit of a structure's diagram
+struct Ends<'a> {
+    entry: &'a SequenceNode,
+    exit: &'a SequenceNode,
+    /// A block wires inside can be connected to which isn't in the diagram, like the method
+    /// call checking a loop's condition
+    hidden: Option<&'a Id>,
+}
+
+impl SequenceGraph {
+    /// Resolves the sequence of a program's root diagram, along with the diagrams nested in it
+    pub fn new(diagram: &Diagram) -> Self {
+        Self::build(diagram, vec![], None)
+    }
+
+    fn build(diagram: &Diagram, scope: Vec<Id>, ends: Option<Ends>) -> Self {
+        let mut graph = SequenceGraph::default();
+        let mut problems = vec![];
+        let mut blocks: Vec<_> = diagram.blocks.keys().collect();
+        blocks.sort_by(|a, b| a.cmp_natural(b));
+        let mut wires: Vec<_> = diagram.wires.keys().collect();
+        wires.sort_by(|a, b| a.cmp_natural(b));
+
+        let mut terminals = vec![];
+        for &id in &blocks {
+            let block = &diagram.blocks[id];
+            for terminal in [&block.sequence_in, &block.sequence_out] {
+                terminals.push((id.clone(), terminal.as_ref().and_then(wire_id)));
+            }
+        }
+        if let Some(ends) = &ends {
+            terminals.push((Id::new(ENTRY), ends.entry.wire_id.as_ref()));
+            terminals.push((Id::new(EXIT), ends.exit.wire_id.as_ref()));
+        }
+        for (block, wire) in terminals {
+            if let Some(wire) = wire.filter(|wire| !diagram.wires.contains_key(wire)) {
+                problems.push(ProblemKind::MissingWire {
+                    block,
+                    wire: wire.clone(),
+                });
+            }
+        }
+
+        for &id in &wires {
+            let wire = &diagram.wires[id];
+            let mut connected = true;
+            for end in std::iter::once(&wire.output).chain(wire.inputs()) {
+                if !connects(diagram, ends.as_ref(), end, id) {
+                    connected = false;
+                    problems.push(ProblemKind::DanglingWire {
+                        wire: id.clone(),
+                        end: end.clone(),
+                    });
+                }
+            }
+            let between_blocks = diagram.blocks.contains_key(&wire.output.node)
+                && diagram.blocks.contains_key(&wire.input.node);
+            if connected && between_blocks && wire.output.terminal == "SequenceOut" {
+                graph
+                    .next
+                    .insert(wire.output.node.clone(), wire.input.node.clone());
+            }
+        }
+        for &id in &blocks {
+            if let BlockType::SensorCompare { paired, .. } = &diagram.blocks[id].ty {
+                if diagram.blocks.contains_key(paired) && !graph.next.contains_key(id) {
+                    graph.next.insert(id.clone(), paired.clone());
+                }
+            }
+        }
+
+        match &ends {
+            None => {
+                graph.starts = blocks
+                    .iter()
+                    .filter(|&&id| diagram.blocks[id].ty == BlockType::Start)
+                    .map(|&id| id.clone())
+                    .collect();
+                if graph.starts.len() > 1 {
+                    problems.push(ProblemKind::MultipleStarts(graph.starts.clone()));
+                }
+            }
+            Some(ends) => {
+                let first = ends
+                    .entry
+                    .wire_id
+                    .as_ref()
+                    .and_then(|wire| diagram.wires.get(wire))
+                    .map(|wire| &wire.input.node)
+                    .filter(|&id| diagram.blocks.contains_key(id));
+                graph.starts.extend(first.cloned());
+            }
+        }
+
+        problems.extend(graph.cycles(&blocks).into_iter().map(ProblemKind::Cycle));
+        let reached: HashSet<_> = graph.order().into_iter().collect();
+        for &id in &blocks {
+            if !reached.contains(id) {
+                problems.push(ProblemKind::UnreachableBlock(id.clone()));
+            }
+        }
+
+        for &id in &blocks {
+            let nested = |inner: Vec<Id>, diagram, ends| {
+                let mut scope = scope.clone();
+                scope.push(id.clone());
+                scope.extend(inner);
+                Self::build(diagram, scope, Some(ends))
+            };
+            let graphs = match &diagram.blocks[id].ty {
+                BlockType::Switch { cases, .. } => cases
+                    .iter()
+                    .map(|case| {
+                        let ends = Ends {
+                            entry: &case.entry,
+                            exit: &case.exit,
+                            hidden: None,
+                        };
+                        nested(vec![case.id.clone()], &case.diagram, ends)
+                    })
+                    .collect(),
+                BlockType::Loop {
+                    condition_id,
+                    entry,
+                    exit,
+                    diagram,
+                    ..
+                } => {
+                    let ends = Ends {
+                        entry,
+                        exit,
+                        hidden: Some(condition_id),
+                    };
+                    vec![nested(vec![], diagram, ends)]
+                }
+                _ => continue,
+            };
+            graph.nested.insert(id.clone(), graphs);
+        }
+
+        graph.problems = problems
+            .into_iter()
+            .map(|kind| Problem {
+                scope: scope.clone(),
+                kind,
+            })
+            .collect();
+        graph
+    }
+
+    /// Every circle the sequence goes around, each starting at the block of it that comes first
+    /// in `blocks`
+    fn cycles(&self, blocks: &[&Id]) -> Vec<Vec<Id>> {
+        let mut cycles = vec![];
+        let mut done = HashSet::new();
+        for &block in blocks {
+            let mut path: Vec<&Id> = vec![];
+            let mut current = Some(block);
+            while let Some(id) = current {
+                if done.contains(id) {
+                    break;
+                }
+                if let Some(start) = path.iter().position(|&other| other == id) {
+                    cycles.push(path[start..].iter().map(|&id| id.clone()).collect());
+                    break;
+                }
+                path.push(id);
+                current = self.next.get(id);
+            }
+            done.extend(path);
+        }
+        cycles
+    }
+
+    /// Blocks in the order they run, following the sequence from each start in turn. Blocks
+    /// are only listed once, even when the sequence goes around
+    pub fn order(&self) -> Vec<Id> {
+        let mut order = vec![];
+        let mut seen = HashSet::new();
+        for start in &self.starts {
+            let mut current = Some(start);
+            while let Some(id) = current.filter(|&id| seen.insert(id)) {
+                order.push(id.clone());
+                current = self.next.get(id);
+            }
+        }
+        order
+    }
+
+    /// Blocks of `diagram`, which this graph was resolved from, that never run because the
+    /// sequence doesn't get to them. Everything inside a structure that never runs doesn't
+    /// either. Blocks are listed by their path, which is the block's id after its
+    /// [`Problem::scope`]
+    pub fn floating_blocks(&self, diagram: &Diagram) -> Vec<Vec<Id>> {
+        let mut floating = vec![];
+        self.collect_floating(diagram, &mut vec![], true, &mut floating);
+        floating
+    }
+
+    fn collect_floating(
+        &self,
+        diagram: &Diagram,
+        scope: &mut Vec<Id>,
+        runs: bool,
+        floating: &mut Vec<Vec<Id>>,
+    ) {
+        let reached: HashSet<_> = self.order().into_iter().collect();
+        let mut blocks: Vec<_> = diagram.blocks.keys().collect();
+        blocks.sort_by(|a, b| a.cmp_natural(b));
+        for id in blocks {
+            let runs = runs && reached.contains(id);
+            scope.push(id.clone());
+            if !runs {
+                floating.push(scope.clone());
+            }
+            let graphs = self.nested.get(id).map(Vec::as_slice).unwrap_or_default();
+            match &diagram.blocks[id].ty {
+                BlockType::Switch { cases, .. } => {
+                    for (case, graph) in cases.iter().zip(graphs) {
+                        scope.push(case.id.clone());
+                        graph.collect_floating(&case.diagram, scope, runs, floating);
+                        scope.pop();
+                    }
+                }
+                BlockType::Loop { diagram, .. } => {
+                    for graph in graphs {
+                        graph.collect_floating(diagram, scope, runs, floating);
+                    }
+                }
+                _ => {}
+            }
+            scope.pop();
+        }
+    }
+
+    /// Problems in this diagram followed by the ones nested in it, by structure
+    pub fn all_problems(&self) -> Vec<&Problem> {
+        let mut problems: Vec<_> = self.problems.iter().collect();
+        let mut structures: Vec<_> = self.nested.keys().collect();
+        structures.sort_by(|a, b| a.cmp_natural(b));
+        for id in structures {
+            for graph in &self.nested[id] {
+                problems.extend(graph.all_problems());
+            }
+        }
+        problems
+    }
+}
+
+fn wire_id(terminal: &SequenceBlock) -> Option<&Id> {
+    terminal.wire_id.as_ref()
+}
+
+/// Whether `end` of the wire `wire` is on a terminal that's connected back to it
+fn connects(diagram: &Diagram, ends: Option<&Ends>, end: &WireEnd, wire: &Id) -> bool {
+    if let Some(block) = diagram.blocks.get(&end.node) {
+        return match end.terminal.as_str() {
+            "SequenceIn" => block.sequence_in.as_ref().and_then(wire_id) == Some(wire),
+            "SequenceOut" => block.sequence_out.as_ref().and_then(wire_id) == Some(wire),
+            // Not every data terminal is modeled, like the tunnels of structures, so the block
+            // being there is enough
+            _ => true,
+        };
+    }
+    if let Some(ends) = ends {
+        match end.node.as_str() {
+            ENTRY => return ends.entry.wire_id.as_ref() == Some(wire),
+            EXIT => return ends.exit.wire_id.as_ref() == Some(wire),
+            _ if ends.hidden == Some(&end.node) => return true,
+            _ => {}
+        }
+    }
+    // Elements that are kept as they are, like blocks that failed to parse while diagnosing
+    diagram.order.iter().any(|item| match item {
+        Item::Node(node) => node
+            .attributes
+            .iter()
+            .any(|(key, value)| key == "Id" && value == end.node.as_str()),
+        _ => false,
+    })
+}
+
+impl File {
+    /// Resolves the order the program's blocks run in
+    pub fn sequence(&self) -> SequenceGraph {
+        SequenceGraph::new(&self.diagram)
+    }
+
+    /// Blocks that aren't wired into the sequence from a start block, which the EV3 software
+    /// silently skips. See [`SequenceGraph::floating_blocks`]
+    pub fn floating_blocks(&self) -> Vec<Vec<Id>> {
+        self.sequence().floating_blocks(&self.diagram)
+    }
+
+    /// Checks the wires of every diagram in the program, see [`ProblemKind`] for what's checked
+    pub fn validate(&self) -> Vec<Problem> {
+        self.sequence()
+            .all_problems()
+            .into_iter()
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Problem, ProblemKind};
+    use crate::ev3::parser::{Id, WireEnd};
+    use crate::ev3::project::File;
+    use std::fs;
+
+    fn open(name: &str, edit: impl Fn(String) -> String) -> File {
+        let path = format!("{}/examples/{name}", env!("CARGO_MANIFEST_DIR"));
+        let contents = edit(fs::read_to_string(path).unwrap());
+        File::new(name, contents.into_bytes()).unwrap()
+    }
+
+    #[test]
+    fn follows_the_sequence_into_structures() {
+        let file = open("2blockandif.ev3p", |contents| contents);
+        let graph = file.sequence();
+        let ids = |ids: &[&str]| ids.iter().map(|&id| Id::new(id)).collect::<Vec<_>>();
+        assert_eq!(graph.order(), ids(&["n1", "n2", "n4", "n6", "n0"]));
+        let cases = &graph.nested[&Id::new("n0")];
+        assert_eq!(cases.len(), 2);
+        assert!(cases.iter().all(|case| case.order() == ids(&["n11"])));
+        assert_eq!(file.validate(), []);
+    }
+
+    #[test]
+    fn finds_floating_blocks() {
+        let file = open("1block-unconnected.ev3p", |contents| contents);
+        assert_eq!(file.floating_blocks(), [[Id::new("n2")]]);
+        let file = open("1block-connected.ev3p", |contents| contents);
+        assert!(file.floating_blocks().is_empty());
+
+        // Cutting the wire into a switch leaves everything in it floating
+        let file = open("2blockandif.ev3p", |contents| {
+            contents.replace(
+                "N(n4:SequenceOut) N(n6:SequenceIn)",
+                "N(n4:SequenceOut) N(n7:SequenceIn)",
+            )
+        });
+        let path = |ids: &[&str]| ids.iter().map(|&id| Id::new(id)).collect::<Vec<_>>();
+        assert_eq!(
+            file.floating_blocks(),
+            [
+                path(&["n0"]),
+                path(&["n0", "D5", "n11"]),
+                path(&["n0", "d1", "n11"]),
+                path(&["n6"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_broken_wiring() {
+        let file = open("2blocks.ev3p", |contents| {
+            contents.replace(r#"Wire="w5""#, r#"Wire="w6""#).replace(
+                "N(n1:SequenceOut) N(n2:SequenceIn)",
+                "N(n1:SequenceOut) N(n9:SequenceIn)",
+            )
+        });
+        let problem = |kind| Problem {
+            scope: vec![],
+            kind,
+        };
+        assert_eq!(
+            file.validate(),
+            [
+                problem(ProblemKind::MissingWire {
+                    block: Id::new("n2"),
+                    wire: Id::new("w6"),
+                }),
+                problem(ProblemKind::MissingWire {
+                    block: Id::new("n4"),
+                    wire: Id::new("w6"),
+                }),
+                problem(ProblemKind::DanglingWire {
+                    wire: Id::new("w3"),
+                    end: WireEnd {
+                        node: Id::new("n9"),
+                        terminal: "SequenceIn".into(),
+                    },
+                }),
+                problem(ProblemKind::DanglingWire {
+                    wire: Id::new("w5"),
+                    end: WireEnd {
+                        node: Id::new("n2"),
+                        terminal: "SequenceOut".into(),
+                    },
+                }),
+                problem(ProblemKind::DanglingWire {
+                    wire: Id::new("w5"),
+                    end: WireEnd {
+                        node: Id::new("n4"),
+                        terminal: "SequenceIn".into(),
+                    },
+                }),
+                problem(ProblemKind::UnreachableBlock(Id::new("n2"))),
+                problem(ProblemKind::UnreachableBlock(Id::new("n4"))),
+            ]
+        );
+    }
+}
@@ -0,0 +1,299 @@
+//! Positioning and sizing blocks the way the EV3 software draws them, so that generated and
+//! edited programs look tidy when they're opened
+
+use super::graph::SequenceGraph;
+use super::parser::{Block, BlockType, Bounds, Diagram, Id, Item, Joint, SequenceNode, WireEnd};
+use super::project::File;
+use super::terminal::{self, terminal_bounds};
+use std::collections::{HashMap, HashSet};
+
+/// Where the EV3 software puts the start block of a new program
+const ORIGIN: (f64, f64) = (147.0, 203.0);
+const BLOCK_HEIGHT: f64 = 91.0;
+/// Sensor compare blocks are drawn as a tab on the left of the structure they're paired with,
+/// which is as wide no matter how many terminals they show
+const COMPARE_WIDTH: f64 = 85.0;
+/// Room kept in a structure's diagram that doesn't have any blocks, enough for a start block
+const EMPTY_WIDTH: f64 = 70.0;
+/// Space between rows of blocks, each of which runs from a different start
+const ROW_GAP: f64 = 40.0;
+/// Size of the sequence nodes a structure's diagram is entered and left through
+const NODE_SIZE: f64 = 18.0;
+/// Where the first row of blocks is in a structure's diagram, leaving room for its title
+const DIAGRAM_TOP: f64 = 40.0;
+/// Space below the last row of blocks in a structure's diagram, which is also how far the
+/// sequence nodes are below the top of the first row
+const DIAGRAM_BOTTOM: f64 = 33.0;
+/// Where the cases of a switch go inside it, and the space between them
+const CASE_MARGIN: (f64, f64) = (10.0, 4.0);
+const CASE_GAP: f64 = 7.0;
+/// Room for the condition of a loop, which is drawn at its right end
+const LOOP_CONDITION_WIDTH: f64 = 80.0;
+/// How far data wires go below the blocks they connect before going across
+const WIRE_DROP: f64 = 20.0;
+
+/// The space taken by the blocks of a diagram, in its own coordinates
+struct Extent {
+    right: f64,
+    bottom: f64,
+    /// Top of the plain blocks in the first row, which structures are centered on
+    line: f64,
+}
+
+impl File {
+    /// Positions and sizes every block. The sequence from each start block goes in a row, left
+    /// to right, with the blocks that never run in a row below them. Structures are sized to
+    /// fit their diagrams, which are laid out the same way, and data wires go under the blocks
+    /// they connect. Sequence wires are straight, so their joints are cleared
+    pub fn lay_out(&mut self) {
+        let graph = self.sequence();
+        lay_out(&mut self.diagram, &graph, ORIGIN.0, Some(ORIGIN.1));
+    }
+}
+
+/// How wide the EV3 software draws a block that isn't a structure, which fits its visible
+/// terminals side by side
+fn block_width(block: &Block) -> f64 {
+    if let BlockType::SensorCompare { .. } = block.ty {
+        return COMPARE_WIDTH;
+    }
+    let visible = block
+        .method_terminals()
+        .iter()
+        .filter(|terminal| terminal.visible)
+        .count();
+    terminal::block_width(visible)
+}
+
+/// Lays out the diagram with its left edge at `left`. The first row goes at `line` when it's
+/// given, otherwise right below `DIAGRAM_TOP`
+fn lay_out(diagram: &mut Diagram, graph: &SequenceGraph, left: f64, line: Option<f64>) -> Extent {
+    let default = SequenceGraph::default();
+    for (id, block) in &mut diagram.blocks {
+        let graphs = graph.nested.get(id).map(Vec::as_slice).unwrap_or_default();
+        let (width, height) = size(block, graphs, &default);
+        block.bounds.width = width;
+        block.bounds.height = height;
+    }
+
+    let mut extent = Extent {
+        right: left + EMPTY_WIDTH,
+        bottom: DIAGRAM_TOP + BLOCK_HEIGHT,
+        line: line.unwrap_or(DIAGRAM_TOP),
+    };
+    let mut top = DIAGRAM_TOP;
+    for (index, row) in rows(diagram, graph).into_iter().enumerate() {
+        // Structures are taller than the blocks around them, and stick out above and below
+        let above = row
+            .iter()
+            .map(|id| (diagram.blocks[id].bounds.height - BLOCK_HEIGHT) / 2.0)
+            .fold(0.0, f64::max);
+        let row_line = match (index, line) {
+            (0, Some(line)) => line,
+            _ => top + above,
+        };
+        let mut x = left;
+        let mut bottom = row_line + BLOCK_HEIGHT;
+        for id in row {
+            let bounds = &mut diagram.blocks.get_mut(&id).unwrap().bounds;
+            bounds.x = x;
+            bounds.y = (row_line + (BLOCK_HEIGHT - bounds.height) / 2.0).floor();
+            x += bounds.width;
+            bottom = bottom.max(bounds.y + bounds.height);
+        }
+        if index == 0 {
+            extent = Extent {
+                right: x,
+                bottom,
+                line: row_line,
+            };
+        }
+        extent.right = extent.right.max(x);
+        extent.bottom = bottom;
+        top = bottom + ROW_GAP;
+    }
+
+    route_wires(diagram);
+    extent
+}
+
+/// Size of the block, laying out the diagrams inside it first when it's a structure
+fn size(block: &mut Block, graphs: &[SequenceGraph], default: &SequenceGraph) -> (f64, f64) {
+    match &mut block.ty {
+        BlockType::Switch { cases, .. } => {
+            let extents: Vec<_> = cases
+                .iter_mut()
+                .enumerate()
+                .map(|(index, case)| {
+                    let graph = graphs.get(index).unwrap_or(default);
+                    lay_out(&mut case.diagram, graph, NODE_SIZE, None)
+                })
+                .collect();
+            // Cases are as wide as the widest one, since they're drawn one above the other
+            let content = extents
+                .iter()
+                .map(|extent| extent.right)
+                .fold(NODE_SIZE + EMPTY_WIDTH, f64::max);
+            let width = content + NODE_SIZE + 1.0;
+            let mut y = CASE_MARGIN.1;
+            for (case, extent) in cases.iter_mut().zip(extents) {
+                let height = extent.bottom + DIAGRAM_BOTTOM;
+                case.bounds = Bounds {
+                    x: CASE_MARGIN.0,
+                    y,
+                    width,
+                    height,
+                };
+                place_nodes(&mut case.entry, &mut case.exit, content, &extent);
+                y += height + CASE_GAP;
+            }
+            let height = y - CASE_GAP + CASE_MARGIN.1;
+            (width + 2.0 * CASE_MARGIN.0, height)
+        }
+        BlockType::Loop {
+            entry,
+            exit,
+            diagram,
+            ..
+        } => {
+            let extent = lay_out(diagram, graphs.first().unwrap_or(default), NODE_SIZE, None);
+            place_nodes(entry, exit, extent.right, &extent);
+            let width = extent.right + NODE_SIZE + LOOP_CONDITION_WIDTH;
+            (width, extent.bottom + DIAGRAM_BOTTOM)
+        }
+        _ => (block_width(block), BLOCK_HEIGHT),
+    }
+}
+
+/// Puts the entry of a structure's diagram on its left edge and the exit at `right`, both level
+/// with the first row of blocks
+fn place_nodes(entry: &mut SequenceNode, exit: &mut SequenceNode, right: f64, extent: &Extent) {
+    let y = extent.line + DIAGRAM_BOTTOM;
+    for (node, x) in [(entry, 0.0), (exit, right)] {
+        node.bounds = Bounds {
+            x,
+            y,
+            width: NODE_SIZE,
+            height: NODE_SIZE,
+        };
+    }
+}
+
+/// The blocks of each row, in the order they run from each start, followed by the blocks
+/// that never run
+fn rows(diagram: &Diagram, graph: &SequenceGraph) -> Vec<Vec<Id>> {
+    let mut rows = vec![];
+    let mut seen = HashSet::new();
+    for start in &graph.starts {
+        let mut row = vec![];
+        let mut current = Some(start);
+        while let Some(id) = current.filter(|&id| seen.insert(id.clone())) {
+            if diagram.blocks.contains_key(id) {
+                row.push(id.clone());
+            }
+            current = graph.next.get(id);
+        }
+        rows.push(row);
+    }
+    let floating: Vec<_> = diagram
+        .order
+        .iter()
+        .filter_map(|item| match item {
+            Item::Block(id) if !seen.contains(id) => Some(id.clone()),
+            _ => None,
+        })
+        .collect();
+    rows.push(floating);
+    rows.retain(|row| !row.is_empty());
+    rows
+}
+
+/// Routes the data wires between visible terminals down from the terminal they come out of,
+/// across under both blocks and up into the other terminal. Joints are relative, with the last
+/// segment going into the terminal left out. Branches go on the same way from the input before
+/// them
+fn route_wires(diagram: &mut Diagram) {
+    let Diagram { blocks, wires, .. } = diagram;
+    for wire in wires.values_mut() {
+        let from = terminal_point(blocks, &wire.output);
+        let to = terminal_point(blocks, &wire.input);
+        wire.joints = route(from, to);
+        let mut from = to;
+        for branch in &mut wire.branches {
+            let to = terminal_point(blocks, &branch.input);
+            branch.joints = route(from, to);
+            from = to;
+        }
+    }
+}
+
+fn route(from: Option<(f64, f64)>, to: Option<(f64, f64)>) -> Vec<Joint> {
+    match (from, to) {
+        (Some(from), Some(to)) => vec![
+            Joint::Vertical(from.1.max(to.1) + WIRE_DROP - from.1),
+            Joint::Horizontal(to.0 - from.0),
+        ],
+        _ => vec![],
+    }
+}
+
+/// Where a wire goes into a visible terminal, which is the middle of its bottom edge
+fn terminal_point(blocks: &HashMap<Id, Block>, end: &WireEnd) -> Option<(f64, f64)> {
+    let block = blocks.get(&end.node)?;
+    let index = block
+        .method_terminals()
+        .into_iter()
+        .filter(|terminal| terminal.visible)
+        .position(|terminal| terminal.id == end.terminal)?;
+    let terminal = terminal_bounds(index);
+    Some((
+        block.bounds.x + terminal.x + terminal.width / 2.0,
+        block.bounds.y + terminal.y + terminal.height,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ev3::parser::{BlockType, Bounds, Id};
+    use crate::ev3::project::File;
+    use std::fs;
+
+    #[test]
+    fn lays_out_like_the_ev3_software() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/examples/2blockandif.ev3p");
+        let original = File::new("2blockandif.ev3p", fs::read(path).unwrap()).unwrap();
+        let mut file = File::new("2blockandif.ev3p", fs::read(path).unwrap()).unwrap();
+        for block in file.diagram.blocks.values_mut() {
+            block.bounds = Bounds::default();
+        }
+        file.lay_out();
+
+        let bounds = |file: &File, id: &str| file.diagram.blocks[&Id::new(id)].bounds;
+        for id in ["n1", "n2", "n4", "n6"] {
+            assert_eq!(bounds(&file, id), bounds(&original, id));
+        }
+        let switch = bounds(&file, "n0");
+        assert_eq!((switch.x, switch.width), (628.0, 251.0));
+        let BlockType::Switch { cases, .. } = &file.diagram.blocks[&Id::new("n0")].ty else {
+            panic!("n0 isn't a switch");
+        };
+        let BlockType::Switch { cases: original, .. } = &original.diagram.blocks[&Id::new("n0")].ty
+        else {
+            panic!("n0 isn't a switch");
+        };
+        // The first case is drawn exactly like it is in the file, the second one was resized
+        assert_eq!(cases[0].bounds, original[0].bounds);
+        assert_eq!(cases[0].entry.bounds, original[0].entry.bounds);
+        assert_eq!(cases[0].exit.bounds, original[0].exit.bounds);
+        let n11 = Id::new("n11");
+        assert_eq!(
+            cases[0].diagram.blocks[&n11].bounds,
+            original[0].diagram.blocks[&n11].bounds
+        );
+        assert_eq!(cases[1].bounds.y, original[1].bounds.y);
+        assert_eq!(
+            switch.height,
+            cases[1].bounds.y + cases[1].bounds.height + 4.0
+        );
+    }
+}
@@ -0,0 +1,485 @@
+//! The project's `Project.lvprojx`, which lists the programs and other files in the archive for
+//! the EV3 software, along with the project's variables and settings
+
+use super::error::{Error, Position, Result};
+use super::parser::{DataType, ValueKind};
+use super::preserved::Node;
+use super::writer::{merge_attributes, FileWriter, Value};
+use crate::utils::xml::{
+    collect_to_vec, extract_name_from_qname, parse_attributes, qualified_name,
+};
+use crate::utils::VecReadWrapper;
+use anyhow::{bail, Context};
+use quick_xml::events::{BytesDecl, BytesStart, Event};
+use quick_xml::reader::Reader;
+use std::collections::HashSet;
+
+/// Name of the manifest in the archive
+pub(crate) const MANIFEST: &str = "Project.lvprojx";
+/// What a program's `SourceFileReference` says it is
+const PROGRAM_TYPE: &str = "X3VIDocument";
+const PROGRAM_DOCUMENT_TYPE: &str = "NationalInstruments.LabVIEW.VI.Modeling.VirtualInstrument";
+
+/// The parsed `Project.lvprojx`. Everything that isn't modeled is kept as it is, so writing it
+/// back out reproduces it exactly
+#[derive(Clone, Debug, PartialEq)]
+pub struct Manifest {
+    pub decl: BytesDecl<'static>,
+    /// What the project is made of, in the order the project's target lists it
+    pub references: Vec<Reference>,
+    /// Global variables the programs share, from the `NamedGlobalData` setting
+    pub variables: Vec<Variable>,
+    /// Whether the programs run on bricks daisy chained together
+    pub daisy_chain: bool,
+    /// The whole document, whose modeled parts are replaced when writing it back out
+    document: Node,
+}
+
+/// Something the project's target refers to
+#[derive(Clone, Debug, PartialEq)]
+pub enum Reference {
+    /// A program in the archive, like `Program.ev3p`
+    Program {
+        name: String,
+        /// Attributes of its `SourceFileReference`, kept so that it's written back as it was
+        attributes: Vec<(String, String)>,
+    },
+    /// Anything else, like the libraries the programs run with and the other files in the
+    /// archive
+    Other(Node),
+}
+
+impl Reference {
+    pub fn program(name: impl Into<String>) -> Self {
+        Reference::Program {
+            name: name.into(),
+            attributes: vec![],
+        }
+    }
+
+    fn from_node(node: Node) -> Self {
+        let attribute = |key| {
+            node.attributes
+                .iter()
+                .find(|(k, _)| k == key)
+                .map(|(_, v)| v.as_str())
+        };
+        match (
+            node.name.as_str(),
+            attribute("OverridingDocumentTypeIdentifier"),
+            attribute("StoragePath"),
+        ) {
+            ("SourceFileReference", Some(PROGRAM_TYPE), Some(name)) => Reference::Program {
+                name: name.into(),
+                attributes: node.attributes,
+            },
+            _ => Reference::Other(node),
+        }
+    }
+
+    fn to_node(&self) -> Node {
+        let (name, attributes) = match self {
+            Reference::Program { name, attributes } => (name, attributes),
+            Reference::Other(node) => return node.clone(),
+        };
+        let escaped = escape_name(name);
+        let attributes = merge_attributes(
+            &[
+                ("StoragePath", Value::Model(Some(name))),
+                ("RelativeStoragePath", Value::Model(Some(name))),
+                (
+                    "OverridingDocumentTypeIdentifier",
+                    Value::Default(PROGRAM_TYPE),
+                ),
+                (
+                    "DocumentTypeIdentifier",
+                    Value::Default(PROGRAM_DOCUMENT_TYPE),
+                ),
+                ("Name", Value::Model(Some(&escaped))),
+            ],
+            attributes,
+        );
+        element("SourceFileReference", &attributes)
+    }
+}
+
+/// A global variable, which programs read and write with variable blocks
+#[derive(Clone, Debug, PartialEq)]
+pub struct Variable {
+    pub name: String,
+    pub kind: ValueKind,
+}
+
+impl Manifest {
+    /// Parses the contents of a `Project.lvprojx`
+    pub fn new(contents: Vec<u8>) -> Result<Self> {
+        let mut xml = Reader::from_reader(VecReadWrapper::new(contents));
+        xml.trim_text(true);
+        let mut position = Position::default();
+        Self::parse(xml, &mut position).map_err(|err| Error::parse(MANIFEST, None, position, err))
+    }
+
+    fn parse(xml: Reader<VecReadWrapper>, position: &mut Position) -> anyhow::Result<Self> {
+        let mut events = collect_to_vec(xml)?.into_iter();
+        let mut decl = None;
+        let mut document = None;
+        while let Some((event, at)) = events.next() {
+            *position = at;
+            match event {
+                Event::Decl(d) => decl = Some(d),
+                Event::Start(t) if document.is_none() => {
+                    document = Some(read_element(&t, false, &mut events, position)?)
+                }
+                Event::Eof => break,
+                _ => bail!("Unexpected event at the top of the manifest"),
+            }
+        }
+        let decl = decl.context("Missing XML declaration")?;
+        let mut document = document.context("Missing SourceFile element")?;
+
+        let project = project(&mut document).context("Missing Project element")?;
+        let target = child(project, "Target").context("Missing Target element")?;
+        let references = std::mem::take(&mut target.children)
+            .into_iter()
+            .map(Reference::from_node)
+            .collect();
+        let mut variables = vec![];
+        let mut daisy_chain = false;
+        if let Some(settings) = child(project, "ProjectSettings") {
+            if let Some(data) = child(settings, "NamedGlobalData") {
+                for datum in std::mem::take(&mut data.children) {
+                    variables.push(parse_variable(&datum)?);
+                }
+            }
+            if let Some(mode) = child(settings, "DaisyChainMode") {
+                daisy_chain = match attribute(mode, "On") {
+                    Some("True") => true,
+                    Some("False") => false,
+                    value => bail!("Invalid daisy chain mode `{}`", value.unwrap_or_default()),
+                };
+            }
+        }
+
+        Ok(Self {
+            decl,
+            references,
+            variables,
+            daisy_chain,
+            document,
+        })
+    }
+
+    /// Names of the programs the project lists
+    pub fn programs(&self) -> impl Iterator<Item = &str> {
+        self.references
+            .iter()
+            .filter_map(|reference| match reference {
+                Reference::Program { name, .. } => Some(name.as_str()),
+                Reference::Other(_) => None,
+            })
+    }
+
+    /// Renames the program, keeping its place in the list. A program that already had the new
+    /// name is replaced by it. Returns whether it was listed
+    pub fn rename_program(&mut self, name: &str, new_name: impl Into<String>) -> bool {
+        let new_name = new_name.into();
+        if !self.programs().any(|program| program == name) {
+            return false;
+        }
+        if new_name != name {
+            self.references.retain(|reference| {
+                !matches!(reference, Reference::Program { name, .. } if *name == new_name)
+            });
+        }
+        for reference in &mut self.references {
+            match reference {
+                Reference::Program { name: program, .. } if program == name => {
+                    *program = new_name;
+                    break;
+                }
+                _ => {}
+            }
+        }
+        true
+    }
+
+    /// Makes the manifest list exactly the given programs once each, keeping the ones it already
+    /// lists where they are and adding the others after the last one
+    pub(crate) fn sync<'a>(&mut self, programs: impl IntoIterator<Item = &'a str>) {
+        let programs: Vec<_> = programs.into_iter().collect();
+        let mut listed = HashSet::new();
+        self.references.retain(|reference| match reference {
+            Reference::Program { name, .. } => {
+                programs.contains(&name.as_str()) && listed.insert(name.clone())
+            }
+            Reference::Other(_) => true,
+        });
+        for program in programs {
+            if self.programs().any(|name| name == program) {
+                continue;
+            }
+            let index = self
+                .references
+                .iter()
+                .rposition(|reference| matches!(reference, Reference::Program { .. }))
+                .map_or(self.references.len(), |index| index + 1);
+            self.references.insert(index, Reference::program(program));
+        }
+    }
+
+    /// Writes the manifest back out as the contents of a `Project.lvprojx`
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut document = self.document.clone();
+        let project = project(&mut document).expect("Parsing checked there's a project");
+        let target = child(project, "Target").expect("Parsing checked there's a target");
+        target.children = self.references.iter().map(Reference::to_node).collect();
+
+        if !self.variables.is_empty() || child(project, "ProjectSettings").is_some() {
+            let settings = child_or_insert(project, "ProjectSettings", &[]);
+            let has_data = child(settings, "NamedGlobalData").is_some();
+            if has_data || !self.variables.is_empty() {
+                let data = child_or_insert(
+                    settings,
+                    "NamedGlobalData",
+                    &[("xmlns", "http://www.ni.com/X3NamedGlobalData.xsd")],
+                );
+                data.children = self.variables.iter().map(variable_node).collect();
+                if !data.children.is_empty() {
+                    data.text = None;
+                }
+            }
+            if let Some(mode) = child(settings, "DaisyChainMode") {
+                let on = if self.daisy_chain { "True" } else { "False" };
+                set_attribute(mode, "On", on);
+            } else if self.daisy_chain {
+                let mode = child_or_insert(
+                    settings,
+                    "DaisyChainMode",
+                    &[("xmlns", "http://www.ni.com/X3ProjectPropertiesModel.xsd")],
+                );
+                set_attribute(mode, "On", "True");
+            }
+        }
+
+        FileWriter::write_document(&self.decl, &document)
+    }
+}
+
+/// Reads the element `start` along with everything inside it
+fn read_element(
+    start: &BytesStart,
+    empty: bool,
+    events: &mut impl Iterator<Item = (Event<'static>, Position)>,
+    position: &mut Position,
+) -> anyhow::Result<Node> {
+    let (name, prefix) =
+        extract_name_from_qname(start.name()).context("Failed parsing tag name")?;
+    let name = qualified_name(&name, prefix.as_deref());
+    let attributes = parse_attributes(start)
+        .context(format!("Failed parsing attributes of `{name}`"))?
+        .iter()
+        .map(|attr| (attr.qualified_key(), attr.value.clone()))
+        .collect();
+    let mut node = Node {
+        name,
+        attributes,
+        children: vec![],
+        text: None,
+    };
+    if empty {
+        return Ok(node);
+    }
+    loop {
+        let (event, at) = events.next().context("Unexpected end of file")?;
+        *position = at;
+        match event {
+            Event::Start(t) => node
+                .children
+                .push(read_element(&t, false, events, position)?),
+            Event::Empty(t) => node
+                .children
+                .push(read_element(&t, true, events, position)?),
+            Event::Text(t) => {
+                node.text = Some(t.unescape().context("Invalid text")?.into_owned());
+            }
+            // The reader already checks that end tags match their start tags
+            Event::End(_) => break,
+            _ => bail!("Unexpected event inside `{}`", node.name),
+        }
+    }
+    if node.children.is_empty() && node.text.is_none() {
+        node.text = Some(String::new());
+    }
+    Ok(node)
+}
+
+fn parse_variable(datum: &Node) -> anyhow::Result<Variable> {
+    if datum.name != "Datum" {
+        bail!("Unknown global data `{}`", datum.name);
+    }
+    let name = attribute(datum, "Name").context("Missing variable name")?;
+    let data_type = attribute(datum, "Type").context("Missing variable type")?;
+    let data_type =
+        DataType::parse(data_type).context(format!("Failed parsing type of variable `{name}`"))?;
+    let kind = ValueKind::from_data_type(&data_type)
+        .context(format!("Variable `{name}` can't hold {data_type}"))?;
+    Ok(Variable {
+        name: name.into(),
+        kind,
+    })
+}
+
+fn variable_node(variable: &Variable) -> Node {
+    let data_type = variable.kind.data_type().to_string();
+    element(
+        "Datum",
+        &[
+            ("Name", variable.name.as_str()),
+            ("Type", data_type.as_str()),
+        ],
+    )
+}
+
+/// The project element, in the `Default` namespace
+fn project(document: &mut Node) -> Option<&mut Node> {
+    let namespace = document
+        .children
+        .iter_mut()
+        .find(|node| node.name == "Namespace" && attribute(node, "Name") == Some("Default"))?;
+    child(namespace, "Project")
+}
+
+fn child<'a>(node: &'a mut Node, name: &str) -> Option<&'a mut Node> {
+    node.children.iter_mut().find(|child| child.name == name)
+}
+
+fn child_or_insert<'a>(
+    node: &'a mut Node,
+    name: &str,
+    attributes: &[(&str, &str)],
+) -> &'a mut Node {
+    let index = match node.children.iter().position(|child| child.name == name) {
+        Some(index) => index,
+        None => {
+            node.children.push(element(name, attributes));
+            node.text = None;
+            node.children.len() - 1
+        }
+    };
+    &mut node.children[index]
+}
+
+fn attribute<'a>(node: &'a Node, key: &str) -> Option<&'a str> {
+    node.attributes
+        .iter()
+        .find(|(k, _)| k == key)
+        .map(|(_, v)| v.as_str())
+}
+
+fn set_attribute(node: &mut Node, key: &str, value: &str) {
+    match node.attributes.iter_mut().find(|(k, _)| k == key) {
+        Some((_, v)) => *v = value.into(),
+        None => node.attributes.insert(0, (key.into(), value.into())),
+    }
+}
+
+fn element(name: &str, attributes: &[(&str, &str)]) -> Node {
+    Node {
+        name: name.into(),
+        attributes: attributes
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect(),
+        children: vec![],
+        text: None,
+    }
+}
+
+/// Escapes a file name the way the manifest names things, like `Program\.ev3p`
+fn escape_name(name: &str) -> String {
+    let mut escaped = String::new();
+    for c in name.chars() {
+        if matches!(c, '.' | ' ' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Manifest, Reference};
+    use crate::ev3::builder::FileBuilder;
+    use crate::ev3::project::Project;
+    use std::fs;
+    use std::io::Read;
+
+    fn manifests() -> Vec<Vec<u8>> {
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/examples");
+        let mut manifests = vec![];
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension() != Some("ev3".as_ref()) {
+                continue;
+            }
+            let mut zip = zip::ZipArchive::new(fs::File::open(&path).unwrap()).unwrap();
+            let mut contents = vec![];
+            let mut entry = zip.by_name("Project.lvprojx").unwrap();
+            entry.read_to_end(&mut contents).unwrap();
+            manifests.push(contents);
+        }
+        manifests
+    }
+
+    #[test]
+    fn manifests_round_trip_byte_for_byte() {
+        for contents in manifests() {
+            let manifest = Manifest::new(contents.clone()).unwrap();
+            assert!(manifest.programs().count() > 0);
+            assert!(!manifest.daisy_chain);
+            assert!(manifest.to_bytes() == contents);
+        }
+    }
+
+    #[test]
+    fn keeps_programs_in_sync() {
+        let contents = manifests().remove(0);
+        let mut manifest = Manifest::new(contents).unwrap();
+        let programs: Vec<String> = manifest.programs().map(Into::into).collect();
+        let mut renamed = programs.clone();
+        renamed[0] = "Renamed program.ev3p".into();
+        renamed.push("New.ev3p".into());
+
+        manifest.rename_program(&programs[0], &renamed[0]);
+        manifest.sync(renamed.iter().map(String::as_str));
+        let written = String::from_utf8(manifest.to_bytes()).unwrap();
+        assert!(written.contains(
+            r#"StoragePath="Renamed program.ev3p" RelativeStoragePath="Renamed program.ev3p" OverridingDocumentTypeIdentifier="X3VIDocument" DocumentTypeIdentifier="NationalInstruments.LabVIEW.VI.Modeling.VirtualInstrument" Name="Renamed\ program\.ev3p""#
+        ));
+        let manifest = Manifest::new(written.into_bytes()).unwrap();
+        assert_eq!(manifest.programs().collect::<Vec<_>>(), renamed);
+        assert!(manifest
+            .references
+            .iter()
+            .any(|reference| matches!(reference, Reference::Other(_))));
+    }
+
+    #[test]
+    fn renaming_onto_another_program_replaces_it() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/examples/2blocks.ev3");
+        let mut project = Project::open(path).unwrap();
+        project.add_file(FileBuilder::new().start().build("Other.ev3p"));
+        assert!(project.rename_file("Program1.ev3p", "Other.ev3p"));
+        assert_eq!(project.files().len(), 1);
+        assert_eq!(
+            project.manifest().programs().collect::<Vec<_>>(),
+            ["Other.ev3p"]
+        );
+
+        let mut manifest = project.manifest().clone();
+        manifest.references.push(Reference::program("Other.ev3p"));
+        manifest.sync(["Other.ev3p"]);
+        assert_eq!(manifest.programs().count(), 1);
+    }
+}
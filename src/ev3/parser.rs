@@ -1,29 +1,59 @@
+use super::error::{Cause, Diagnostic, Error, Position, Severity};
+use super::preserved::{Extra, FileExtra, Node};
 use super::project::{File, Version};
+use super::terminal::{
+    Direction, Literal, Terminal, INTERRUPTS_TERMINAL, VALUE_IN_TERMINAL, VALUE_OUT_TERMINAL,
+    VARIABLE_NAME_TERMINAL,
+};
 use crate::utils::xml::{
-    collect_to_vec, extract_name_from_qname, parse_attributes, ParsedAttribute, XMLReader,
+    collect_to_vec, extract_name_from_qname, parse_attributes, qualified_name, ParsedAttribute,
+    XMLReader,
 };
-use anyhow::{bail, ensure, Context};
-use quick_xml::events::{BytesDecl, Event};
+use anyhow::{anyhow, bail, ensure, Context};
+use quick_xml::events::{BytesDecl, BytesStart, BytesText, Event};
 use std::collections::HashMap;
 
-#[allow(unused)]
-fn dump_tag(name: String, prefix: Option<String>, attributes: Vec<ParsedAttribute>) {
-    println!("Dumping tag");
-    println!("Name: {name}");
-    println!("Prefix: {prefix:?}");
-    match attributes.len() {
-        0 => println!("    No attributes"),
-        n => {
-            for attr in attributes {
-                println!("    Attr: {attr:?}");
-            }
-        }
+/// Elements around the block diagram that are skipped, unless they're being preserved
+fn is_skipped(name: &str) -> bool {
+    matches!(
+        name,
+        // TODO: Should these do something?
+        "FrontPanel" | "FrontPanelCanvas"
+        // I think it's safe to ignore these, as they don't really affect the program and
+        // aren't changeable inside the software, so we can just reproduce them later.
+        | "Icon"
+        | "IconPanel"
+        | "AnimationProperties.Animations"
+        | "EventProperties.Events"
+        | "AnimationsContainer"
+        | "EventContainer"
+    )
+}
+
+/// Id of a block, wire or case, like `n1` or `w3`. Only unique inside the [`Diagram`] it's in
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct Id(pub(crate) String);
+
+impl Id {
+    pub fn new(id: impl Into<String>) -> Self {
+        Self(id.into())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Orders ids so that `n2` comes before `n10`
+    pub(crate) fn cmp_natural(&self, other: &Id) -> std::cmp::Ordering {
+        (self.0.len(), &self.0).cmp(&(other.0.len(), &other.0))
     }
-    println!();
 }
 
-#[derive(PartialEq, Eq, Hash)]
-pub struct Id(String);
+impl std::fmt::Display for Id {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        fmt.write_str(&self.0)
+    }
+}
 
 impl std::fmt::Debug for Id {
     fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
@@ -31,67 +61,996 @@ impl std::fmt::Debug for Id {
     }
 }
 
-struct BlockAttribute {
-    id: String,
-    value: String,
+pub(crate) const SEQUENCE_DATA_TYPE: &str =
+    "NationalInstruments:SourceModel:DataTypes:X3SequenceWireDataType";
+
+/// Type of the values a terminal takes or gives, and so of the wires between them
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DataType {
+    Single,
+    Boolean,
+    Int32,
+    String,
+    /// Like `Single[]`
+    Array(Box<DataType>),
+    /// Sequence wires, which order blocks instead of carrying values
+    Sequence,
+}
+
+impl DataType {
+    pub(crate) fn parse(value: &str) -> anyhow::Result<Self> {
+        if let Some(item) = value.strip_suffix("[]") {
+            return Ok(DataType::Array(Box::new(Self::parse(item)?)));
+        }
+        Ok(match value {
+            "Single" => DataType::Single,
+            "Boolean" => DataType::Boolean,
+            "Int32" => DataType::Int32,
+            "String" => DataType::String,
+            SEQUENCE_DATA_TYPE => DataType::Sequence,
+            _ => bail!("Unknown data type `{value}`"),
+        })
+    }
+}
+
+impl std::fmt::Display for DataType {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            DataType::Single => write!(fmt, "Single"),
+            DataType::Boolean => write!(fmt, "Boolean"),
+            DataType::Int32 => write!(fmt, "Int32"),
+            DataType::String => write!(fmt, "String"),
+            DataType::Array(item) => write!(fmt, "{item}[]"),
+            DataType::Sequence => write!(fmt, "{SEQUENCE_DATA_TYPE}"),
+        }
+    }
 }
 
-#[derive(Debug)]
-enum SequenceBlockType {
+/// Which side of a block a sequence terminal is on
+#[derive(Clone, Debug, PartialEq)]
+pub enum SequenceBlockType {
     In,
     Out,
 }
 
-#[derive(Debug)]
-struct SequenceBlock {
-    ty: SequenceBlockType,
-    wire_id: Option<Id>,
+/// The terminal a block's sequence comes in or goes out of, and the wire connected to it
+#[derive(Clone, Debug, PartialEq)]
+pub struct SequenceBlock {
+    pub ty: SequenceBlockType,
+    pub wire_id: Option<Id>,
 }
 
-#[derive(Debug)]
-enum BlockType {
+/// What a block does, along with its configuration
+#[derive(Clone, Debug, PartialEq)]
+pub enum BlockType {
+    /// Where the program starts, there's one per program
     Start,
+    /// A Move Steering block
     MotorMove {
-        ports: (char, char),
-        steering: isize,
-        speed: usize,
+        ports: (Port, Port),
+        steering: f64,
+        speed: f64,
+        duration: MotorDuration,
+    },
+    /// A Move Tank block
+    MotorTank {
+        ports: (Port, Port),
+        left_speed: f64,
+        right_speed: f64,
+        duration: MotorDuration,
+    },
+    /// A Move Tank block in off mode
+    MotorTankOff {
+        ports: (Port, Port),
+        brake: bool,
+    },
+    /// A Large Motor or Medium Motor block, which drives a single motor
+    Motor {
+        size: MotorSize,
+        port: Port,
+        speed: f64,
+        duration: MotorDuration,
+    },
+    /// A Large Motor or Medium Motor block in off mode
+    MotorOff {
+        size: MotorSize,
+        port: Port,
+        brake: bool,
+    },
+    /// A `ConfigurableFlatCaseStructure`, which runs one of its cases depending on a value
+    Switch {
+        /// Type of the value the cases are matched against, like `Boolean`
+        data_type: DataType,
+        /// The block deciding which case runs, for switches driven by a sensor
+        paired: Option<Id>,
+        /// The input the value comes in through instead, for switches driven by a wire
+        selector: Option<Terminal>,
+        cases: Vec<Case>,
+    },
+    /// A `PairedConfigurableMethodCall`, which reads a sensor and hands the result to the
+    /// structure it's paired with
+    SensorCompare {
+        target: String,
+        port: Port,
+        /// The terminals picking what is compared, like `Pressed\,\ Released\ or\ Bumped`, and
+        /// the outputs with the reading, like `Result` and `Value`
+        terminals: Vec<Terminal>,
+        /// The structure deciding on the reading, like a switch
+        paired: Id,
+    },
+    Wait(Wait),
+    /// A Variable block, which reads or writes one of the project's variables by name
+    Variable {
+        name: String,
+        kind: ValueKind,
+        access: VariableAccess,
+    },
+    /// A Constant block, which gives the same value every time
+    Constant {
+        kind: ValueKind,
+        value: Literal,
+    },
+    /// A `ConfigurableWhileLoop`, which runs its diagram until its condition says to stop
+    Loop {
+        /// Name the Loop Interrupt block uses to break out of the loop, like `01`
+        interrupt_name: String,
+        condition: LoopCondition,
+        /// Id of the method call inside the loop that checks the condition
+        condition_id: Id,
+        /// Where the sequence enters the body, which is the `SequenceNode` named `Output`
+        entry: SequenceNode,
+        /// Where the sequence leaves the body, which is the `SequenceNode` named `Input`
+        exit: SequenceNode,
+        /// Blocks and wires of the body, whose ids can be the same as the ones outside of it
+        diagram: Diagram,
+    },
+    /// A block the parser doesn't know, only produced when preserving. Its terminals are kept in
+    /// the block's [`Extra`].
+    Unknown {
+        target: String,
+    },
+}
+
+/// What a [`BlockType::Variable`] does with its variable
+#[derive(Clone, Debug, PartialEq)]
+pub enum VariableAccess {
+    Read,
+    /// Writes the value, unless another one is wired into the block
+    Write(Literal),
+}
+
+/// Targets of the variable and constant blocks start with these, followed by the kind of value
+/// like `X3\.Lib:GlobalGetNumeric`
+pub(crate) const VARIABLE_READ_TARGET: &str = "X3\\.Lib:GlobalGet";
+pub(crate) const VARIABLE_WRITE_TARGET: &str = "X3\\.Lib:GlobalSet";
+pub(crate) const CONSTANT_TARGET: &str = "X3\\.Lib:GlobalConst";
+
+/// Kind of value a variable holds, and so what its variable and constant blocks read or write.
+/// The EV3 software has no other kinds, so there are no text arrays for example
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ValueKind {
+    Numeric,
+    Boolean,
+    String,
+    NumericArray,
+    BooleanArray,
+}
+
+/// The kinds as they're named in targets
+const VALUE_KINDS: [(&str, ValueKind); 5] = [
+    ("Numeric", ValueKind::Numeric),
+    ("Boolean", ValueKind::Boolean),
+    ("String", ValueKind::String),
+    ("NumericArray", ValueKind::NumericArray),
+    ("BooleanArray", ValueKind::BooleanArray),
+];
+
+impl ValueKind {
+    /// Type of the value terminals, and of the variable in the manifest
+    pub fn data_type(self) -> DataType {
+        let array = |item| DataType::Array(Box::new(item));
+        match self {
+            ValueKind::Numeric => DataType::Single,
+            ValueKind::Boolean => DataType::Boolean,
+            ValueKind::String => DataType::String,
+            ValueKind::NumericArray => array(DataType::Single),
+            ValueKind::BooleanArray => array(DataType::Boolean),
+        }
+    }
+
+    /// The kind holding values of the type, if variables can
+    pub fn from_data_type(data_type: &DataType) -> Option<Self> {
+        VALUE_KINDS
+            .iter()
+            .map(|(_, kind)| *kind)
+            .find(|kind| kind.data_type() == *data_type)
+    }
+
+    /// The end of variable and constant targets, like `NumericArray`
+    pub(crate) fn target_name(self) -> &'static str {
+        VALUE_KINDS
+            .iter()
+            .find(|(_, kind)| *kind == self)
+            .map(|(name, _)| *name)
+            .unwrap()
+    }
+
+    fn parse_target_name(name: &str) -> Option<Self> {
+        VALUE_KINDS
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, kind)| *kind)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MotorSize {
+    Large,
+    Medium,
+}
+
+/// A port on the brick, motors go in the output ports A to D and sensors in the input ports 1 to 4
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Port {
+    A,
+    B,
+    C,
+    D,
+    One,
+    Two,
+    Three,
+    Four,
+}
+
+impl Port {
+    fn parse(value: &str) -> anyhow::Result<Self> {
+        Ok(match value {
+            "A" => Port::A,
+            "B" => Port::B,
+            "C" => Port::C,
+            "D" => Port::D,
+            "1" => Port::One,
+            "2" => Port::Two,
+            "3" => Port::Three,
+            "4" => Port::Four,
+            _ => bail!("Unknown port `{value}`"),
+        })
+    }
+
+    pub fn is_output(self) -> bool {
+        matches!(self, Port::A | Port::B | Port::C | Port::D)
+    }
+}
+
+impl std::fmt::Display for Port {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Port::A => "A",
+            Port::B => "B",
+            Port::C => "C",
+            Port::D => "D",
+            Port::One => "1",
+            Port::Two => "2",
+            Port::Three => "3",
+            Port::Four => "4",
+        };
+        f.write_str(name)
+    }
+}
+
+impl BlockType {
+    /// The block this one is paired with, for sensor compare blocks and the structures they drive
+    pub fn paired(&self) -> Option<&Id> {
+        match self {
+            BlockType::SensorCompare { paired, .. } => Some(paired),
+            BlockType::Switch { paired, .. } => paired.as_ref(),
+            _ => None,
+        }
+    }
+}
+
+/// What a wait block waits for, which is picked with the block's mode
+#[derive(Clone, Debug, PartialEq)]
+pub enum Wait {
+    Time {
+        seconds: f64,
+    },
+    /// Waits until the reading of `sensor` compares to `threshold`
+    Compare {
+        sensor: WaitSensor,
+        comparison: Comparison,
+        threshold: f64,
+    },
+    /// Waits until the reading of `sensor` changes by `amount` in `direction`
+    Change {
+        sensor: WaitSensor,
+        direction: ChangeDirection,
+        amount: f64,
+    },
+    TouchCompare {
+        port: Port,
+        state: ButtonState,
+    },
+    /// Waits until the touch sensor is pressed or released
+    TouchChange {
+        port: Port,
+    },
+    /// Waits until the color sensor sees any of `colors`
+    ColorCompare {
+        port: Port,
+        colors: Vec<Color>,
+    },
+    ColorChange {
+        port: Port,
+    },
+    /// Waits until any of `buttons` on the brick is in `state`
+    ButtonsCompare {
+        buttons: Vec<BrickButton>,
+        state: ButtonState,
+    },
+    ButtonsChange,
+}
+
+/// A sensor reading that is a number, which waits can compare or watch for changes
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WaitSensor {
+    ReflectedLight(Port),
+    AmbientLight(Port),
+    UltrasonicCentimeters(Port),
+    UltrasonicInches(Port),
+    GyroAngle(Port),
+    GyroRate(Port),
+    MotorDegrees(Port),
+    MotorRotations(Port),
+    MotorPower(Port),
+    /// One of the 8 timers of the brick
+    Timer(u8),
+}
+
+impl WaitSensor {
+    /// Name of the sensor in the targets of wait blocks, like `GyroAngle` in
+    /// `WaitGyroAngleCompare\.vix`
+    pub fn name(self) -> &'static str {
+        match self {
+            WaitSensor::ReflectedLight(_) => "ColorReflected",
+            WaitSensor::AmbientLight(_) => "ColorAmbient",
+            WaitSensor::UltrasonicCentimeters(_) => "UltrasonicCentimeters",
+            WaitSensor::UltrasonicInches(_) => "UltrasonicInches",
+            WaitSensor::GyroAngle(_) => "GyroAngle",
+            WaitSensor::GyroRate(_) => "GyroRate",
+            WaitSensor::MotorDegrees(_) => "MotorDegrees",
+            WaitSensor::MotorRotations(_) => "MotorRotations",
+            WaitSensor::MotorPower(_) => "MotorPower",
+            WaitSensor::Timer(_) => "Timer",
+        }
+    }
+
+    /// The variant for the sensor with the given name, for the sensors on a port
+    fn with_port(name: &str) -> Option<fn(Port) -> Self> {
+        Some(match name {
+            "ColorReflected" => WaitSensor::ReflectedLight,
+            "ColorAmbient" => WaitSensor::AmbientLight,
+            "UltrasonicCentimeters" => WaitSensor::UltrasonicCentimeters,
+            "UltrasonicInches" => WaitSensor::UltrasonicInches,
+            "GyroAngle" => WaitSensor::GyroAngle,
+            "GyroRate" => WaitSensor::GyroRate,
+            "MotorDegrees" => WaitSensor::MotorDegrees,
+            "MotorRotations" => WaitSensor::MotorRotations,
+            "MotorPower" => WaitSensor::MotorPower,
+            _ => return None,
+        })
+    }
+
+    fn is_known(name: &str) -> bool {
+        name == "Timer" || Self::with_port(name).is_some()
+    }
+
+    fn new(name: &str, port: Option<Port>, timer: Option<u8>) -> anyhow::Result<Self> {
+        if name == "Timer" {
+            let timer = timer.context("Failed finding timer for wait")?;
+            ensure!((1..=8).contains(&timer), "Unknown timer {timer}");
+            return Ok(WaitSensor::Timer(timer));
+        }
+        let sensor = Self::with_port(name).context(format!("Unknown wait sensor `{name}`"))?;
+        let port = port.context(format!("Failed finding port for wait on {name}"))?;
+        let sensor = sensor(port);
+        let motor = matches!(
+            sensor,
+            WaitSensor::MotorDegrees(_) | WaitSensor::MotorRotations(_) | WaitSensor::MotorPower(_)
+        );
+        ensure!(
+            port.is_output() == motor,
+            "Wait on {name} can't use port {port}"
+        );
+        Ok(sensor)
+    }
+
+    pub fn port(self) -> Option<Port> {
+        match self {
+            WaitSensor::ReflectedLight(port)
+            | WaitSensor::AmbientLight(port)
+            | WaitSensor::UltrasonicCentimeters(port)
+            | WaitSensor::UltrasonicInches(port)
+            | WaitSensor::GyroAngle(port)
+            | WaitSensor::GyroRate(port)
+            | WaitSensor::MotorDegrees(port)
+            | WaitSensor::MotorRotations(port)
+            | WaitSensor::MotorPower(port) => Some(port),
+            WaitSensor::Timer(_) => None,
+        }
+    }
+}
+
+/// Declares an enum whose variants are stored as numbers in terminals
+macro_rules! coded_enum {
+    ($(#[$meta:meta])* $name:ident { $($variant:ident = $code:literal,)* }) => {
+        $(#[$meta])*
+        #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+        pub enum $name {
+            $($variant,)*
+        }
+
+        impl $name {
+            pub fn code(self) -> u8 {
+                match self {
+                    $($name::$variant => $code,)*
+                }
+            }
+
+            fn parse(value: &Literal) -> anyhow::Result<Self> {
+                let code = value
+                    .number()
+                    .context(format!("Failed parsing {}", stringify!($name)))?;
+                $(if code == $code as f64 {
+                    return Ok($name::$variant);
+                })*
+                bail!("Unknown {} `{value}`", stringify!($name))
+            }
+        }
+    };
+}
+
+coded_enum!(Comparison {
+    Equal = 0,
+    NotEqual = 1,
+    Greater = 2,
+    GreaterOrEqual = 3,
+    Less = 4,
+    LessOrEqual = 5,
+});
+
+coded_enum!(ChangeDirection {
+    Increase = 0,
+    Decrease = 1,
+    Any = 2,
+});
+
+coded_enum!(
+    /// State of a touch sensor or brick button
+    ButtonState {
+        Released = 0,
+        Pressed = 1,
+        Bumped = 2,
+    }
+);
+
+coded_enum!(Color {
+    None = 0,
+    Black = 1,
+    Blue = 2,
+    Green = 3,
+    Yellow = 4,
+    Red = 5,
+    White = 6,
+    Brown = 7,
+});
+
+coded_enum!(BrickButton {
+    Left = 1,
+    Center = 2,
+    Right = 3,
+    Up = 4,
+    Down = 5,
+});
+
+/// Terminal of the touch sensor and brick button blocks picking the [`ButtonState`]
+pub(crate) const BUTTON_STATE_TERMINAL: &str = "Pressed\\,\\ Released\\ or\\ Bumped";
+
+/// The configured values of a wait block, taken out one at a time
+struct WaitTerminals<'a> {
+    target: &'a str,
+    terminals: HashMap<String, Literal>,
+    /// Layer of the port, once it was taken
+    layer: Option<u8>,
+}
+
+impl WaitTerminals<'_> {
+    fn take(&mut self, id: &str) -> anyhow::Result<Literal> {
+        self.terminals
+            .remove(id)
+            .context(format!("Failed finding `{id}` for {}", self.target))
+    }
+
+    fn number(&mut self, id: &str) -> anyhow::Result<f64> {
+        self.take(id)?
+            .number()
+            .context(format!("Failed parsing {id}"))
+    }
+
+    fn port(&mut self) -> anyhow::Result<Port> {
+        let (layer, port) = parse_port(self.take("Port")?.text()?)?;
+        self.layer = Some(layer);
+        Ok(port)
+    }
+
+    fn sensor(&mut self, name: &str) -> anyhow::Result<WaitSensor> {
+        if !WaitSensor::is_known(name) {
+            return Err(Cause::UnknownTarget(self.target.into()).into());
+        }
+        let port = self.terminals.remove("Port");
+        let port = port.map(|port| parse_port(port.text()?)).transpose()?;
+        if let Some((layer, _)) = port {
+            self.layer = Some(layer);
+        }
+        let port = port.map(|(_, port)| port);
+        let timer = self.terminals.remove("Timer\\ ID");
+        let timer = timer
+            .map(|t| t.integer().context("Failed parsing timer id"))
+            .transpose()?
+            .map(|t| t as u8);
+        WaitSensor::new(name, port, timer)
+    }
+}
+
+/// When a loop stops, which is picked with the loop's mode
+#[derive(Clone, Debug, PartialEq)]
+pub enum LoopCondition {
+    Unlimited,
+    Count(i32),
+    Time(f64),
+    /// Stops once the value wired into it is the same as `until`
+    Logic {
+        until: bool,
+    },
+    /// Stops once a sensor reading matches, configured like a sensor compare block
+    Sensor {
+        target: String,
+        port: Port,
+        terminals: Vec<Terminal>,
     },
 }
 
-#[derive(Debug)]
+/// How long a motor block keeps its motors running, which is picked with the block's mode
+#[derive(Clone, Debug, PartialEq)]
+pub enum MotorDuration {
+    Unlimited,
+    Rotations { rotations: f64, brake: bool },
+    Degrees { degrees: f64, brake: bool },
+    Seconds { seconds: f64, brake: bool },
+}
+
+impl MotorDuration {
+    /// Name of the terminal holding the amount the motors run for, if they stop at all
+    pub fn terminal(&self) -> Option<&'static str> {
+        match self {
+            MotorDuration::Unlimited => None,
+            MotorDuration::Rotations { .. } => Some("Rotations"),
+            MotorDuration::Degrees { .. } => Some("Degrees"),
+            MotorDuration::Seconds { .. } => Some("Seconds"),
+        }
+    }
+
+    fn new(terminal: &str, amount: f64, brake: bool) -> anyhow::Result<Self> {
+        Ok(match terminal {
+            "Rotations" => MotorDuration::Rotations {
+                rotations: amount,
+                brake,
+            },
+            "Degrees" => MotorDuration::Degrees {
+                degrees: amount,
+                brake,
+            },
+            "Seconds" => MotorDuration::Seconds {
+                seconds: amount,
+                brake,
+            },
+            _ => bail!("Unknown motor duration `{terminal}`"),
+        })
+    }
+}
+
+/// Collects the terminals of a motor block that say how long it runs for
+struct DurationTerminals<'a> {
+    /// Terminal holding the amount, `None` for blocks that run until they're told to stop
+    terminal: Option<&'a str>,
+    amount: Option<f64>,
+    brake: Option<bool>,
+}
+
+impl<'a> DurationTerminals<'a> {
+    fn new(terminal: Option<&'a str>) -> Self {
+        Self {
+            terminal,
+            amount: None,
+            brake: None,
+        }
+    }
+
+    /// Parses the terminal if it's one of the duration ones, returning whether it was
+    fn parse(&mut self, id: &str, value: &Literal) -> anyhow::Result<bool> {
+        let Some(terminal) = self.terminal else {
+            return Ok(false);
+        };
+        if id == terminal {
+            self.amount = Some(value.number().context(format!("Failed parsing {id}"))?);
+        } else if id == "Brake\\ At\\ End" {
+            self.brake = Some(value.boolean().context("Failed parsing brake at end")?);
+        } else {
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    fn build(self, block: &str) -> anyhow::Result<MotorDuration> {
+        let Some(terminal) = self.terminal else {
+            return Ok(MotorDuration::Unlimited);
+        };
+        let amount = self
+            .amount
+            .context(format!("Failed finding {terminal} for {block}"))?;
+        let brake = self
+            .brake
+            .context(format!("Failed finding brake at end for {block}"))?;
+        MotorDuration::new(terminal, amount, brake)
+    }
+}
+
+/// Splits the layer off the front of ports, which is the brick in a daisy chain, like the `1` in
+/// `1.A`
+fn split_layer(value: &str) -> anyhow::Result<(u8, &str)> {
+    let (layer, ports) = value
+        .split_once('.')
+        .context(format!("Expected a layer in front of ports `{value}`"))?;
+    let layer = layer
+        .parse()
+        .context(format!("Failed parsing layer of ports `{value}`"))?;
+    Ok((layer, ports))
+}
+
+/// Parses a single port like `1.A`, along with its layer
+fn parse_port(value: &str) -> anyhow::Result<(u8, Port)> {
+    let (layer, port) = split_layer(value)?;
+    Ok((layer, Port::parse(port)?))
+}
+
+/// Parses a pair of ports like `1.B+C`, along with their layer
+fn parse_ports(value: &str) -> anyhow::Result<(u8, (Port, Port))> {
+    let (layer, ports) = split_layer(value)?;
+    let (first, second) = ports
+        .split_once('+')
+        .context(format!("Expected two ports, found `{value}`"))?;
+    let first = Port::parse(first).context("Failed parsing first port")?;
+    let second = Port::parse(second).context("Failed parsing second port")?;
+    Ok((layer, (first, second)))
+}
+
+/// Parses the joints of a wire, like `N(n1:Value) h(120) N(n2:Speed)`, into the terminal the wire
+/// comes out of, the bends up to the first terminal it goes into along with that terminal, and
+/// the branches going into the others
+fn parse_joints(value: &str) -> anyhow::Result<(WireEnd, Vec<Joint>, WireEnd, Vec<Branch>)> {
+    let mut output = None;
+    let mut inputs = vec![];
+    let mut joints = vec![];
+    for joint in value.split(' ') {
+        // "N(n1:SequenceOut)" => ('N', "n1:SequenceOut")
+        let mut chars = joint.chars();
+        let kind = chars.next().context("Found empty joint")?;
+        let inner = chars
+            .as_str()
+            .strip_prefix('(')
+            .and_then(|s| s.strip_suffix(')'))
+            .context(format!("Joint `{joint}` is not wrapped in parentheses"))?;
+        match kind {
+            // The ends of the wire, the one it comes out of first
+            'N' if output.is_none() => {
+                ensure!(
+                    joints.is_empty(),
+                    "Wire `{value}` doesn't start at a terminal"
+                );
+                output = Some(WireEnd::parse(inner)?);
+            }
+            'N' => inputs.push(Branch {
+                joints: std::mem::take(&mut joints),
+                input: WireEnd::parse(inner)?,
+            }),
+            'h' => joints.push(Joint::Horizontal(
+                inner
+                    .parse()
+                    .context(format!("Failed parsing joint `{joint}` as number"))?,
+            )),
+            'v' => joints.push(Joint::Vertical(
+                inner
+                    .parse()
+                    .context(format!("Failed parsing joint `{joint}` as number"))?,
+            )),
+            _ => joints.push(Joint::Other(joint.to_owned())),
+        }
+    }
+    ensure!(
+        joints.is_empty(),
+        "Wire `{value}` doesn't end at a terminal"
+    );
+    let mut inputs = inputs.into_iter();
+    let (Some(output), Some(first)) = (output, inputs.next()) else {
+        bail!("Expected at least 2 ends in wire `{value}`");
+    };
+    Ok((output, first.joints, first.input, inputs.collect()))
+}
+
+/// Keeps a comment as it was written, to put it back where it was when preserving
+fn comment(text: BytesText) -> anyhow::Result<Node> {
+    let text =
+        String::from_utf8(text.into_inner().into_owned()).context("Invalid UTF-8 in comment")?;
+    Ok(Node::comment(text))
+}
+
+/// A block in a diagram, which is either a method call like a motor block or a structure like a
+/// switch
+#[derive(Clone, Debug, PartialEq)]
 pub struct Block {
-    ty: BlockType,
-    sequence_in: Option<SequenceBlock>,
-    sequence_out: Option<SequenceBlock>,
+    pub ty: BlockType,
+    pub bounds: Bounds,
+    pub sequence_in: Option<SequenceBlock>,
+    pub sequence_out: Option<SequenceBlock>,
+    /// Wires connected to the other terminals of the block, by terminal id
+    pub data_wires: HashMap<String, Id>,
+    /// The brick in a daisy chain the block's ports are on, 1 being the one running the program
+    pub layer: u8,
+    pub extra: Extra,
+}
+
+/// One of the cases of a [`BlockType::Switch`]
+#[derive(Clone, Debug, PartialEq)]
+pub struct Case {
+    pub id: Id,
+    pub bounds: Bounds,
+    /// Value the switch has to match for the case to run, like `True`
+    pub pattern: String,
+    /// Whether the case runs when no other pattern matches
+    pub default: bool,
+    /// Where the sequence enters the case, which is the `SequenceNode` named `Output`
+    pub entry: SequenceNode,
+    /// Where the sequence leaves the case, which is the `SequenceNode` named `Input`
+    pub exit: SequenceNode,
+    /// Blocks and wires of the case, whose ids can be the same as the ones in other cases
+    pub diagram: Diagram,
+    pub extra: Extra,
+}
+
+/// The ends of the sequence inside a structure like a switch case
+#[derive(Clone, Debug, PartialEq)]
+pub struct SequenceNode {
+    pub bounds: Bounds,
+    pub wire_id: Option<Id>,
+    pub extra: Extra,
+}
+
+/// Position and size of a block inside its diagram, as `x y width height`
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Bounds {
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
 }
 
-#[derive(Debug)]
+impl Bounds {
+    fn parse(s: &str) -> anyhow::Result<Self> {
+        let mut values = s.split(' ').map(|v| {
+            v.parse::<f64>()
+                .context(format!("Failed parsing bounds value `{v}` as number"))
+        });
+        let mut next = || values.next().context("Expected 4 values in bounds")?;
+        let bounds = Bounds {
+            x: next()?,
+            y: next()?,
+            width: next()?,
+            height: next()?,
+        };
+        ensure!(values.next().is_none(), "Too many values in bounds `{s}`");
+        Ok(bounds)
+    }
+}
+
+impl std::fmt::Display for Bounds {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(fmt, "{} {} {} {}", self.x, self.y, self.width, self.height)
+    }
+}
+
+/// A sequence or data wire between two terminals
+#[derive(Clone, Debug, PartialEq)]
 pub struct Wire {
-    input: Id,
-    output: Id,
+    /// The input terminal the wire goes into
+    pub input: WireEnd,
+    /// The output terminal the wire comes out of
+    pub output: WireEnd,
+    /// The bends of the wire between its two ends
+    pub joints: Vec<Joint>,
+    /// The other inputs, for data wires whose output feeds several inputs
+    pub branches: Vec<Branch>,
+    pub extra: Extra,
+}
+
+impl Wire {
+    /// Every input the wire goes into, `input` first and then the branches
+    pub fn inputs(&self) -> impl Iterator<Item = &WireEnd> {
+        std::iter::once(&self.input).chain(self.branches.iter().map(|branch| &branch.input))
+    }
+}
+
+/// Where a branched wire goes after its first input, which is written after it like
+/// `N(n1:Value) N(n2:Speed) v(40) N(n3:Speed)`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Branch {
+    /// The bends since the input before it
+    pub joints: Vec<Joint>,
+    pub input: WireEnd,
+}
+
+/// A terminal at one end of a wire, written like `N(n1:SequenceOut)`
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct WireEnd {
+    /// The block or sequence node the terminal is on
+    pub node: Id,
+    pub terminal: String,
 }
 
+impl WireEnd {
+    fn parse(s: &str) -> anyhow::Result<Self> {
+        // "n1:SequenceOut" => ("n1", "SequenceOut")
+        let (node, terminal) = s
+            .split_once(':')
+            .context(format!("Missing terminal in wire end `{s}`"))?;
+        Ok(Self {
+            node: Id(node.into()),
+            terminal: terminal.into(),
+        })
+    }
+}
+
+impl std::fmt::Display for WireEnd {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(fmt, "N({}:{})", self.node.0, self.terminal)
+    }
+}
+
+/// An element of a block diagram, in the order they appear in the file
+#[derive(Clone, Debug, PartialEq)]
+pub enum Item {
+    Block(Id),
+    Wire(Id),
+    /// An element that isn't understood, only kept when preserving
+    Node(Node),
+}
+
+/// Blocks and wires in a scope, like the root block diagram or a case of a switch. Ids are only
+/// unique inside the diagram they're in
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Diagram {
+    pub blocks: HashMap<Id, Block>,
+    pub wires: HashMap<Id, Wire>,
+    /// Order of the elements, which is kept when writing them back out
+    pub order: Vec<Item>,
+}
+
+impl Diagram {
+    /// The block paired with the block `id`, which is the structure driven by a sensor compare
+    /// block or the other way around
+    pub fn paired(&self, id: &Id) -> Option<(&Id, &Block)> {
+        let paired = self.blocks.get(id)?.ty.paired()?;
+        self.blocks.get_key_value(paired)
+    }
+
+    /// Checks that paired blocks are paired with each other
+    fn check_pairs(&self) -> anyhow::Result<()> {
+        for (id, block) in &self.blocks {
+            let Some(paired) = block.ty.paired() else {
+                continue;
+            };
+            let (_, other) = self.paired(id).context(format!(
+                "Block {id:?} is paired with missing block {paired:?}"
+            ))?;
+            ensure!(
+                other.ty.paired() == Some(id),
+                "Block {id:?} is paired with {paired:?}, which isn't paired back"
+            );
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Joint {
+    /// `h(x)`, a horizontal segment of the wire
+    Horizontal(f64),
+    /// `v(y)`, a vertical segment of the wire
+    Vertical(f64),
+    /// Any other joint, kept as it was written
+    Other(String),
+}
+
+impl std::fmt::Display for Joint {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            Joint::Horizontal(x) => write!(fmt, "h({x})"),
+            Joint::Vertical(y) => write!(fmt, "v({y})"),
+            Joint::Other(s) => write!(fmt, "{s}"),
+        }
+    }
+}
+
+/// The elements around the virtual instrument, whose start and end tags are where the comments
+/// outside of it go
+const OUTER_TAGS: [&str; 3] = ["SourceFile", "Namespace", "VirtualInstrument"];
+
 #[derive(Default)]
-pub struct FileBuilder {
+pub(crate) struct FileParser {
     decl: Option<BytesDecl<'static>>,
     version: Option<Version>,
     name: Option<String>,
-    blocks: HashMap<Id, Block>,
-    wires: HashMap<Id, Wire>,
+    diagram: Diagram,
+    /// Wires connected to the terminals of the block being parsed, taken when it's built
+    data_wires: HashMap<String, Id>,
+    /// Layer of the ports of the block being parsed, taken when it's built
+    layer: Option<u8>,
+    /// Event the last `ConfigurableMethodTerminal` that was parsed starts at, to go back and
+    /// keep it as it is when the block doesn't know it
+    terminal_start: usize,
+    /// Whether to keep what isn't understood instead of failing on it
+    preserve: bool,
+    extra: FileExtra,
+    /// Whether the block diagram was already parsed, to know where preserved elements go
+    after_diagram: bool,
+    /// How many start and end tags of the elements around the virtual instrument were read, to
+    /// know where comments outside of it go
+    outer_tags: usize,
     events: Vec<Event<'static>>,
+    /// Where each event starts in the file
+    positions: Vec<Position>,
     idx: usize,
+    /// Blocks being parsed, the innermost last, to say where errors happened
+    blocks: Vec<Id>,
+    /// Problems found so far, when they're collected instead of failing on the first one
+    diagnostics: Option<Vec<Diagnostic>>,
 }
 
-impl FileBuilder {
+impl FileParser {
     pub fn from_xml(xml: XMLReader) -> anyhow::Result<Self> {
-        let events = collect_to_vec(xml).context("Failed parsing XML file")?;
+        let (events, positions) = collect_to_vec(xml)?.into_iter().unzip();
         Ok(Self {
             events,
+            positions,
             idx: 0,
             ..Default::default()
         })
     }
 
+    /// Turns a failure into an error saying where the parser was when it happened, which is the
+    /// last element it read
+    pub fn error(&self, err: anyhow::Error) -> Error {
+        let name = self.name.as_deref().unwrap_or_default();
+        Error::parse(name, self.blocks.last().cloned(), self.position(), err)
+    }
+
+    /// Where the last element that was read starts
+    pub fn position(&self) -> Position {
+        self.positions
+            .get(self.idx.saturating_sub(1))
+            .copied()
+            .unwrap_or_default()
+    }
+
     fn next_event(&mut self) -> anyhow::Result<Event<'static>> {
         ensure!(
             self.events.len() > self.idx,
@@ -114,6 +1073,44 @@ impl FileBuilder {
         Ok(self.events[self.idx].clone())
     }
 
+    /// Keep unknown elements and attributes around instead of failing on them, so that the file
+    /// can be written back out unchanged
+    pub fn preserve(&mut self) {
+        self.preserve = true;
+    }
+
+    /// Record problems as diagnostics and keep going instead of failing on them. Blocks that
+    /// fail to parse are kept as they are, so this also preserves
+    pub fn diagnose(&mut self) {
+        self.preserve = true;
+        self.diagnostics = Some(vec![]);
+    }
+
+    /// The problems found while diagnosing
+    pub fn take_diagnostics(&mut self) -> Vec<Diagnostic> {
+        self.diagnostics.take().unwrap_or_default()
+    }
+
+    fn diagnostic(&mut self, severity: Severity, err: anyhow::Error) {
+        if self.diagnostics.is_none() {
+            return;
+        }
+        let error = self.error(err);
+        if let Some(diagnostics) = &mut self.diagnostics {
+            diagnostics.push(Diagnostic { severity, error });
+        }
+    }
+
+    /// Fails with `err` for something that isn't supported, unless it's being preserved, in
+    /// which case it's only a warning
+    fn unsupported(&mut self, err: anyhow::Error) -> anyhow::Result<()> {
+        if !self.preserve {
+            return Err(err);
+        }
+        self.diagnostic(Severity::Warning, err);
+        Ok(())
+    }
+
     pub fn parse(&mut self) -> anyhow::Result<()> {
         loop {
             match self.next_event()? {
@@ -123,6 +1120,9 @@ impl FileBuilder {
                         extract_name_from_qname(qname).context("Failed parsing start tag name")?;
                     let attributes =
                         parse_attributes(&t).context("Failed parsing start tag attributes")?;
+                    if OUTER_TAGS.contains(&name.as_str()) {
+                        self.outer_tags += 1;
+                    }
 
                     self.parse_start_tag(name, prefix, attributes)?;
                 }
@@ -130,6 +1130,9 @@ impl FileBuilder {
                     let qname = t.name();
                     let (name, prefix) =
                         extract_name_from_qname(qname).context("Failed parsing end tag name")?;
+                    if OUTER_TAGS.contains(&name.as_str()) {
+                        self.outer_tags += 1;
+                    }
 
                     self.parse_end_tag(name, prefix)?;
                 }
@@ -143,11 +1146,19 @@ impl FileBuilder {
                     self.parse_empty_tag(name, prefix, attributes)?;
                 }
                 Event::Text(t) => {
-                    let s = t.clone().into_inner().to_mut().to_vec();
-                    let s = String::from_utf8(s).unwrap();
+                    let s = t.unescape().context("Invalid text")?;
                     bail!("Unexpected Text tag: {}", s);
                 }
-                Event::Comment(_) => println!("Ignoring Comment"),
+                Event::Comment(t) if self.preserve => {
+                    let node = comment(t)?;
+                    // Inside the virtual instrument they're next to the block diagram
+                    if self.outer_tags == 3 {
+                        self.preserve_file_node(node);
+                    } else {
+                        self.extra.comments.push((self.outer_tags, node));
+                    }
+                }
+                Event::Comment(_) => {}
                 Event::CData(_) => bail!("Unexpected CData tag"),
                 Event::Decl(d) => self.decl(d.clone().into_owned())?,
                 Event::PI(_) => bail!("Unexpected Processing tag"),
@@ -169,13 +1180,15 @@ impl FileBuilder {
                 if let Some(prefix) = prefix {
                     bail!("Unexpected prefix namespace {prefix} in `SourceFile` start tag");
                 }
+                self.extra.source_file = self.preserved_attributes(&attributes);
                 let mut number = None;
                 let mut namespace = None;
                 for attr in attributes {
                     match attr.key.0.as_str() {
                         "Version" => number = Some(attr.value),
                         "xmlns" => namespace = Some(attr.value),
-                        _ => bail!("Unknown SourceFile attribute: {}", attr.key.0),
+                        _ => self
+                            .unsupported(anyhow!("Unknown SourceFile attribute: {}", attr.key.0))?,
                     }
                 }
                 let number = number.context("Missing source file version number")?;
@@ -186,79 +1199,254 @@ impl FileBuilder {
                 if let Some(prefix) = prefix {
                     bail!("Unexpected prefix namespace {prefix} in `Namespace` start tag");
                 }
+                self.extra.namespace = self.preserved_attributes(&attributes);
                 for attr in attributes {
                     if attr.key.0 == "Name" && attr.value != "Project" {
                         bail!("Unsupported namespace {} that is not project", attr.value);
                     }
                 }
             }
-            // TODO: Should this do something?
-            "VirtualInstrument" => {}
-            // TODO: Should this do something?
-            "FrontPanel" => {}
+            // Nothing in here is modeled, only kept when preserving
+            "VirtualInstrument" => {
+                self.extra.virtual_instrument = self.preserved_attributes(&attributes);
+            }
             "BlockDiagram" => {
+                self.extra.block_diagram = self.preserved_attributes(&attributes);
                 for attr in attributes {
-                    ensure!(
-                        attr.key.0 == "Name",
-                        "Unknown block diagram attribute {}",
-                        attr.key.0
-                    );
+                    if attr.key.0 != "Name" {
+                        self.unsupported(anyhow!(
+                            "Unknown block diagram attribute {}",
+                            attr.key.0
+                        ))?;
+                        continue;
+                    }
                     ensure!(
                         attr.value == "__RootDiagram__",
                         "Unknown block diagram name value {}",
                         attr.value
                     );
                 }
+                self.diagram = self
+                    .parse_diagram("BlockDiagram")
+                    .context("Failed parsing block diagram")?;
+                self.after_diagram = true;
             }
-            "StartBlock" => {
-                if let Some(prefix) = prefix {
-                    bail!("Unexpected prefix namespace `{prefix}` in `StartBlock` start tag");
-                }
-                let (id, block) = self
-                    .parse_start_block(attributes)
-                    .context("Failed parsing start block")?;
-                // Note: Don't check for duplicates here because if two start blocks are used then
-                // something bad happened
-                self.blocks.insert(id, block);
-            }
-            "ConfigurableMethodCall" => {
-                if let Some(prefix) = prefix {
-                    bail!("Unexpected prefix namespace `{prefix}` in `ConfigurableMethodCall` start tag");
+            _ => {
+                if !is_skipped(&name) {
+                    self.unsupported(anyhow!("{name} start tag not implemented"))?;
                 }
-                let (id, block) = self
-                    .parse_method_call(attributes)
-                    .context("Failed parsing method call")?;
-                if let Some(_) = self.blocks.get(&id) {
-                    bail!("Multiple blocks with id `{id:?}` used");
+                if self.preserve {
+                    let name = qualified_name(&name, prefix.as_deref());
+                    let node = self.collect_node(name, attributes, false)?;
+                    self.preserve_file_node(node);
                 }
-                self.blocks.insert(id, block);
-            }
-            // I think it's safe to ignore these, as they don't really affect the program and
-            // aren't changeable inside the software, so we can just reproduce them later.
-            "Icon" | "IconPanel" | "AnimationProperties.Animations" | "EventProperties.Events" => {}
-            _ => {
-                dump_tag(name.clone(), prefix, attributes);
-                bail!("{name} start tag not implemented");
             }
         }
         Ok(())
     }
 
+    /// Parses the blocks and wires of a diagram, up to and including the `end` tag that closes it
+    fn parse_diagram(&mut self, end: &str) -> anyhow::Result<Diagram> {
+        let mut diagram = Diagram::default();
+        loop {
+            match self.next_event()? {
+                Event::Start(t) => {
+                    let qname = t.name();
+                    let (name, prefix) =
+                        extract_name_from_qname(qname).context("Failed parsing start tag name")?;
+                    let attributes =
+                        parse_attributes(&t).context("Failed parsing start tag attributes")?;
+
+                    let parse = match name.as_str() {
+                        "StartBlock" => Self::parse_start_block,
+                        "ConfigurableMethodCall" => Self::parse_method_call,
+                        "PairedConfigurableMethodCall" => Self::parse_paired_call,
+                        "ConfigurableWhileLoop" => Self::parse_loop,
+                        "ConfigurableFlatCaseStructure" => Self::parse_case_structure,
+                        _ => {
+                            self.unsupported(anyhow!("{name} start tag not implemented"))?;
+                            let name = qualified_name(&name, prefix.as_deref());
+                            let node = self.collect_node(name, attributes, false)?;
+                            diagram.order.push(Item::Node(node));
+                            continue;
+                        }
+                    };
+                    if let Some(prefix) = prefix {
+                        bail!("Unexpected prefix namespace `{prefix}` in `{name}` start tag");
+                    }
+                    let start = self.idx;
+                    let depth = self.blocks.len();
+                    match parse(self, attributes.clone())
+                        .context(format!("Failed parsing `{name}`"))
+                    {
+                        Ok((id, block)) => {
+                            if diagram.blocks.contains_key(&id) {
+                                bail!("Multiple blocks with id `{id:?}` used");
+                            }
+                            diagram.order.push(Item::Block(id.clone()));
+                            diagram.blocks.insert(id, block);
+                        }
+                        // Keep the block as it is and carry on after it
+                        Err(err) if self.diagnostics.is_some() => {
+                            let severity = match err.downcast_ref::<Cause>() {
+                                Some(Cause::UnknownTarget(_)) => Severity::Warning,
+                                _ => Severity::Error,
+                            };
+                            self.diagnostic(severity, err);
+                            self.idx = start;
+                            self.blocks.truncate(depth);
+                            self.data_wires.clear();
+                            let node = self.collect_node(name, attributes, false)?;
+                            diagram.order.push(Item::Node(node));
+                        }
+                        Err(err) => return Err(err),
+                    }
+                }
+                Event::Empty(t) => {
+                    let qname = t.name();
+                    let (name, prefix) =
+                        extract_name_from_qname(qname).context("Failed parsing empty tag name")?;
+                    let attributes =
+                        parse_attributes(&t).context("Failed parsing empty tag attributes")?;
+
+                    match name.as_str() {
+                        "Wire" => {
+                            let (id, wire) = self
+                                .parse_wire_tag(attributes)
+                                .context("Parsing wire tag failed")?;
+                            if diagram.wires.contains_key(&id) {
+                                bail!("Found duplicate wire ids {id:?}");
+                            }
+                            diagram.order.push(Item::Wire(id.clone()));
+                            diagram.wires.insert(id, wire);
+                        }
+                        _ => {
+                            self.unsupported(anyhow!("{name} empty tag not implemented"))?;
+                            let name = qualified_name(&name, prefix.as_deref());
+                            let node = self.collect_node(name, attributes, true)?;
+                            diagram.order.push(Item::Node(node));
+                        }
+                    }
+                }
+                Event::End(t) => {
+                    let qname = t.name();
+                    let (name, _) =
+                        extract_name_from_qname(qname).context("Failed parsing end tag name")?;
+                    ensure!(name == end, "Unexpected end tag `{name}` in `{end}`");
+                    break;
+                }
+                Event::Comment(t) => {
+                    if self.preserve {
+                        diagram.order.push(Item::Node(comment(t)?));
+                    }
+                }
+                _ => bail!("Unexpected event in `{end}`"),
+            }
+        }
+        diagram.check_pairs()?;
+        Ok(diagram)
+    }
+
+    /// Reads the element that was just started and everything inside it into a [`Node`]
+    fn collect_node(
+        &mut self,
+        name: String,
+        attributes: Vec<ParsedAttribute>,
+        empty: bool,
+    ) -> anyhow::Result<Node> {
+        let attributes = attributes
+            .iter()
+            .map(|attr| (attr.qualified_key(), attr.value.clone()))
+            .collect();
+        let mut children = vec![];
+        let mut text = None;
+        if !empty {
+            loop {
+                match self.next_event()? {
+                    Event::Start(t) => {
+                        let (child, prefix) = extract_name_from_qname(t.name())
+                            .context("Failed parsing start tag name")?;
+                        let attributes =
+                            parse_attributes(&t).context("Failed parsing start tag attributes")?;
+                        let child = qualified_name(&child, prefix.as_deref());
+                        children.push(self.collect_node(child, attributes, false)?);
+                    }
+                    Event::Empty(t) => {
+                        let (child, prefix) = extract_name_from_qname(t.name())
+                            .context("Failed parsing empty tag name")?;
+                        let attributes =
+                            parse_attributes(&t).context("Failed parsing empty tag attributes")?;
+                        let child = qualified_name(&child, prefix.as_deref());
+                        children.push(self.collect_node(child, attributes, true)?);
+                    }
+                    Event::Text(t) => {
+                        text = Some(t.unescape().context("Invalid text")?.into_owned())
+                    }
+                    // The reader already checks that end tags match their start tags
+                    Event::End(_) => break,
+                    Event::Comment(t) => children.push(comment(t)?),
+                    _ => bail!("Unexpected event inside preserved element `{name}`"),
+                }
+            }
+            if children.is_empty() && text.is_none() {
+                text = Some(String::new());
+            }
+        }
+        Ok(Node {
+            name,
+            attributes,
+            children,
+            text,
+        })
+    }
+
+    fn preserve_file_node(&mut self, node: Node) {
+        if self.after_diagram {
+            self.extra.after_diagram.push(node);
+        } else {
+            self.extra.before_diagram.push(node);
+        }
+    }
+
+    /// Every attribute of an element, if they're being kept
+    fn preserved_attributes(&self, attributes: &[ParsedAttribute]) -> Vec<(String, String)> {
+        if !self.preserve {
+            return vec![];
+        }
+        attributes
+            .iter()
+            .map(|attr| (attr.qualified_key(), attr.value.clone()))
+            .collect()
+    }
+
     fn parse_start_block(
         &mut self,
         attributes: Vec<ParsedAttribute>,
     ) -> anyhow::Result<(Id, Block)> {
+        let mut extra = Extra {
+            attributes: self.preserved_attributes(&attributes),
+            ..Default::default()
+        };
         let mut id = None;
+        let mut bounds = None;
         for attr in attributes {
             match attr.key.0.as_str() {
                 "Id" => id = Some(Id(attr.value)),
                 // Ignore, because we already know it's a start block
                 "Target" => {}
-                "Bounds" => {}
-                _ => bail!("Unknown attribute in `StartBlock`: {}", attr.value),
+                "Bounds" => {
+                    bounds = Some(
+                        Bounds::parse(&attr.value).context("Failed parsing StartBlock bounds")?,
+                    )
+                }
+                _ => {
+                    self.unsupported(anyhow!("Unknown attribute in `StartBlock`: {}", attr.value))?
+                }
             }
         }
         let id = id.context("Missing id for StartBlock")?;
+        self.blocks.push(id.clone());
+        let bounds = bounds.context("Missing bounds for StartBlock")?;
 
         let event = self.next_event()?;
         let Event::Start(t) = event else {
@@ -285,188 +1473,1019 @@ impl FileBuilder {
                     bail!("Expected empty tag inside ConfigurableMethodTerminal in StartBlock tag");
                 };
         // Ignore it cuz I assume it's always the same
-        let _ = t;
+        let attributes =
+            parse_attributes(&t).context("Failed parsing terminal attributes in StartBlock")?;
+        if self.preserve {
+            extra
+                .terminals
+                .insert("Result".into(), self.preserved_attributes(&attributes));
+        }
+
+        let Event::End(t) = self.next_event()? else {
+                    bail!("Expected end tag to end ConfigurableMethodTerminal in StartBlock tag");
+                };
+        let qname = t.name();
+        let (name, prefix) = extract_name_from_qname(qname)
+            .context("Failed parsing start tag name in StartBlock")?;
+        if let Some(prefix) = prefix {
+            bail!("Unexpected prefix `{prefix}` to end ConfigurableMethodTerminal tag");
+        }
+        ensure!(
+            name == "ConfigurableMethodTerminal",
+            "Unexpected tag name `{name}` to end ConfigurableMethodTerminal"
+        );
+
+        let sequence_out = Some(
+            self.parse_sequence_terminal(SequenceBlockType::Out, &mut extra)
+                .context("Failed parsing SequenceOut in `StartBlock`")?,
+        );
+        let Event::End(t) = self.next_event()? else {
+                    bail!("Expected end tag in `StartBlock`");
+                };
+        let qname = t.name();
+        let (name, prefix) =
+            extract_name_from_qname(qname).context("Failed parsing end tag name in StartBlock")?;
+        ensure!(name == "StartBlock", "Unexpected end tag for tag `{name}`");
+        if let Some(prefix) = prefix {
+            bail!("Unexpected prefix `{prefix}` in end tag");
+        }
+        let block = Block {
+            ty: BlockType::Start,
+            bounds,
+            sequence_in: None,
+            sequence_out,
+            data_wires: std::mem::take(&mut self.data_wires),
+            layer: self.layer.take().unwrap_or(1),
+            extra,
+        };
+        self.blocks.pop();
+        Ok((id, block))
+    }
+
+    fn parse_method_call(
+        &mut self,
+        attributes: Vec<ParsedAttribute>,
+    ) -> anyhow::Result<(Id, Block)> {
+        let mut extra = Extra {
+            attributes: self.preserved_attributes(&attributes),
+            ..Default::default()
+        };
+        let mut id = None;
+        let mut bounds = None;
+        let mut ty = None;
+        for attr in attributes {
+            let name = attr.key.0;
+            match name.as_str() {
+                "Id" => id = Some(Id(attr.value)),
+                "Bounds" => {
+                    bounds = Some(
+                        Bounds::parse(&attr.value)
+                            .context("Failed parsing `ConfigurableMethodCall` bounds")?,
+                    )
+                }
+                "Target" => ty = Some(attr.value),
+                _ => self.unsupported(anyhow!(
+                    "Unexpected attribute `{name}` in `ConfigurableMethodCall`"
+                ))?,
+            }
+        }
+        let id = id.context("Failed to find id for `ConfigurableMethodCall`")?;
+        self.blocks.push(id.clone());
+        let bounds = bounds.context("Failed to find bounds for `ConfigurableMethodCall`")?;
+        let ty = ty.context("Failed to find target type for `ConfigurableMethodCall`")?;
+
+        let ty = match ty.as_str() {
+            "MoveUnlimited\\.vix" => self.parse_motor_move(None, &mut extra)?,
+            "MoveDistanceRotations\\.vix" => {
+                self.parse_motor_move(Some("Rotations"), &mut extra)?
+            }
+            "MoveDistance\\.vix" => self.parse_motor_move(Some("Degrees"), &mut extra)?,
+            "MoveTime\\.vix" => self.parse_motor_move(Some("Seconds"), &mut extra)?,
+            "MoveTankUnlimited\\.vix" => self.parse_motor_tank(None, &mut extra)?,
+            "MoveTankDistanceRotations\\.vix" => {
+                self.parse_motor_tank(Some("Rotations"), &mut extra)?
+            }
+            "MoveTankDistance\\.vix" => self.parse_motor_tank(Some("Degrees"), &mut extra)?,
+            "MoveTankTime\\.vix" => self.parse_motor_tank(Some("Seconds"), &mut extra)?,
+            "MoveTankStop\\.vix" => self.parse_motor_tank_off(&mut extra)?,
+            "MotorUnlimited\\.vix" => self.parse_motor(MotorSize::Large, None, &mut extra)?,
+            "MotorDistanceRotations\\.vix" => {
+                self.parse_motor(MotorSize::Large, Some("Rotations"), &mut extra)?
+            }
+            "MotorDistance\\.vix" => {
+                self.parse_motor(MotorSize::Large, Some("Degrees"), &mut extra)?
+            }
+            "MotorTime\\.vix" => self.parse_motor(MotorSize::Large, Some("Seconds"), &mut extra)?,
+            "MotorStop\\.vix" => self.parse_motor_off(MotorSize::Large, &mut extra)?,
+            "MediumMotorUnlimited\\.vix" => {
+                self.parse_motor(MotorSize::Medium, None, &mut extra)?
+            }
+            "MediumMotorDistanceRotations\\.vix" => {
+                self.parse_motor(MotorSize::Medium, Some("Rotations"), &mut extra)?
+            }
+            "MediumMotorDistance\\.vix" => {
+                self.parse_motor(MotorSize::Medium, Some("Degrees"), &mut extra)?
+            }
+            "MediumMotorTime\\.vix" => {
+                self.parse_motor(MotorSize::Medium, Some("Seconds"), &mut extra)?
+            }
+            "MediumMotorStop\\.vix" => self.parse_motor_off(MotorSize::Medium, &mut extra)?,
+            _ if ty.starts_with("Wait") => self.parse_wait(&ty, &mut extra)?,
+            _ if ty.starts_with("X3\\.Lib:Global") => self.parse_variable(&ty, &mut extra)?,
+            _ => {
+                self.unsupported(Cause::UnknownTarget(ty.clone()).into())?;
+                self.parse_unknown_call(ty, &mut extra)?
+            }
+        };
+        let (sequence_in, sequence_out) = self
+            .parse_method_sequence_blocks(&mut extra)
+            .context("Failed parsing sequence blocks for method")?;
+        let Event::End(t) = self.next_event()? else {
+            bail!("Expected end tag");
+        };
+        let qname = t.name();
+        let (name, prefix) = extract_name_from_qname(qname)
+            .context("Failed parsing name in ConfigurableMethodTerminal")?;
+        if let Some(prefix) = prefix {
+            bail!("Unexpected prefix `{prefix}` in ConfigurableMethodTerminal");
+        }
+        ensure!(
+            name == "ConfigurableMethodCall",
+            "Expected end tag for ConfigurableMethodCall, found `{name}`"
+        );
+
+        let block = Block {
+            ty,
+            bounds,
+            sequence_in: Some(sequence_in),
+            sequence_out: Some(sequence_out),
+            data_wires: std::mem::take(&mut self.data_wires),
+            layer: self.layer.take().unwrap_or(1),
+            extra,
+        };
+        self.blocks.pop();
+        Ok((id, block))
+    }
+
+    /// Parses any of the steering move blocks, `duration` being the terminal that says how long
+    /// the motors run for, if they stop at all
+    fn parse_motor_move(
+        &mut self,
+        duration: Option<&str>,
+        extra: &mut Extra,
+    ) -> anyhow::Result<BlockType> {
+        let mut ports = None;
+        let mut steering = None;
+        let mut speed = None;
+        let mut duration = DurationTerminals::new(duration);
+        while let Some((id, value)) = self
+            .parse_block_attribute(extra)
+            .context("Failed parsing block attribute")?
+        {
+            match id.as_str() {
+                "Ports" => ports = Some(self.ports(&value)?),
+                "Steering" => steering = Some(value.number().context("Failed parsing steering")?),
+                "Speed" => speed = Some(value.number().context("Failed parsing speed")?),
+                // Ignore cuz it's presumably always the same
+                INTERRUPTS_TERMINAL => {}
+                _ if duration.parse(&id, &value)? => {}
+                _ => self.unknown_terminal(
+                    self.terminal_start,
+                    &id,
+                    anyhow!("Unexpected block attribute `{id}` for MotorMove"),
+                    extra,
+                )?,
+            }
+        }
+        let ports = ports.context("Failed finding ports for MotorMove")?;
+        let steering = steering.context("Failed finding steering for MotorMove")?;
+        let speed = speed.context("Failed finding speed for MotorMove")?;
+        let duration = duration.build("MotorMove")?;
+
+        Ok(BlockType::MotorMove {
+            steering,
+            ports,
+            speed,
+            duration,
+        })
+    }
+
+    /// Parses any of the tank move blocks, which take a speed for each motor instead of a
+    /// steering and a speed
+    fn parse_motor_tank(
+        &mut self,
+        duration: Option<&str>,
+        extra: &mut Extra,
+    ) -> anyhow::Result<BlockType> {
+        let mut ports = None;
+        let mut left_speed = None;
+        let mut right_speed = None;
+        let mut duration = DurationTerminals::new(duration);
+        while let Some((id, value)) = self
+            .parse_block_attribute(extra)
+            .context("Failed parsing block attribute")?
+        {
+            match id.as_str() {
+                "Ports" => ports = Some(self.ports(&value)?),
+                "Speed\\ Left" => {
+                    left_speed = Some(value.number().context("Failed parsing left speed")?)
+                }
+                "Speed\\ Right" => {
+                    right_speed = Some(value.number().context("Failed parsing right speed")?)
+                }
+                INTERRUPTS_TERMINAL => {}
+                _ if duration.parse(&id, &value)? => {}
+                _ => self.unknown_terminal(
+                    self.terminal_start,
+                    &id,
+                    anyhow!("Unexpected block attribute `{id}` for MotorTank"),
+                    extra,
+                )?,
+            }
+        }
+        let ports = ports.context("Failed finding ports for MotorTank")?;
+        let left_speed = left_speed.context("Failed finding left speed for MotorTank")?;
+        let right_speed = right_speed.context("Failed finding right speed for MotorTank")?;
+        let duration = duration.build("MotorTank")?;
+
+        Ok(BlockType::MotorTank {
+            ports,
+            left_speed,
+            right_speed,
+            duration,
+        })
+    }
+
+    fn parse_motor_tank_off(&mut self, extra: &mut Extra) -> anyhow::Result<BlockType> {
+        let mut ports = None;
+        let mut brake = None;
+        while let Some((id, value)) = self
+            .parse_block_attribute(extra)
+            .context("Failed parsing block attribute")?
+        {
+            match id.as_str() {
+                "Ports" => ports = Some(self.ports(&value)?),
+                "Brake\\ At\\ End" => {
+                    brake = Some(value.boolean().context("Failed parsing brake at end")?)
+                }
+                INTERRUPTS_TERMINAL => {}
+                _ => self.unknown_terminal(
+                    self.terminal_start,
+                    &id,
+                    anyhow!("Unexpected block attribute `{id}` for MotorTankOff"),
+                    extra,
+                )?,
+            }
+        }
+        let ports = ports.context("Failed finding ports for MotorTankOff")?;
+        let brake = brake.context("Failed finding brake at end for MotorTankOff")?;
+
+        Ok(BlockType::MotorTankOff { ports, brake })
+    }
+
+    fn parse_paired_call(
+        &mut self,
+        attributes: Vec<ParsedAttribute>,
+    ) -> anyhow::Result<(Id, Block)> {
+        let mut extra = Extra {
+            attributes: self.preserved_attributes(&attributes),
+            ..Default::default()
+        };
+        let mut id = None;
+        let mut bounds = None;
+        let mut target = None;
+        let mut paired = None;
+        for attr in attributes {
+            let name = attr.key.0;
+            match name.as_str() {
+                "Id" => id = Some(Id(attr.value)),
+                "Bounds" => {
+                    bounds = Some(
+                        Bounds::parse(&attr.value)
+                            .context("Failed parsing `PairedConfigurableMethodCall` bounds")?,
+                    )
+                }
+                "Target" => target = Some(attr.value),
+                "PairedStructure" => paired = Some(Id(attr.value)),
+                _ => self.unsupported(anyhow!(
+                    "Unexpected attribute `{name}` in `PairedConfigurableMethodCall`"
+                ))?,
+            }
+        }
+        let id = id.context("Failed to find id for `PairedConfigurableMethodCall`")?;
+        self.blocks.push(id.clone());
+        let bounds = bounds.context("Failed to find bounds for `PairedConfigurableMethodCall`")?;
+        let target = target.context("Failed to find target for `PairedConfigurableMethodCall`")?;
+        let paired =
+            paired.context("Failed to find paired structure for `PairedConfigurableMethodCall`")?;
+
+        let mut port = None;
+        let mut terminals = vec![];
+        while let Some(terminal) = self
+            .parse_block_terminal(&mut extra)
+            .context("Failed parsing block terminal")?
+        {
+            match (terminal.id.as_str(), &terminal.value) {
+                ("Port", Some(value)) => port = Some(self.port(value)?),
+                (INTERRUPTS_TERMINAL, _) => {}
+                _ => terminals.push(terminal),
+            }
+        }
+        let port = port.context("Failed finding port for `PairedConfigurableMethodCall`")?;
+        let (sequence_in, sequence_out) = self
+            .parse_method_sequence_blocks(&mut extra)
+            .context("Failed parsing sequence blocks for paired method")?;
+        let Event::End(t) = self.next_event()? else {
+            bail!("Expected end tag");
+        };
+        let (name, _) = extract_name_from_qname(t.name()).context("Failed parsing end tag name")?;
+        ensure!(
+            name == "PairedConfigurableMethodCall",
+            "Expected end tag for PairedConfigurableMethodCall, found `{name}`"
+        );
+
+        let block = Block {
+            ty: BlockType::SensorCompare {
+                target,
+                port,
+                terminals,
+                paired,
+            },
+            bounds,
+            sequence_in: Some(sequence_in),
+            sequence_out: Some(sequence_out),
+            data_wires: std::mem::take(&mut self.data_wires),
+            layer: self.layer.take().unwrap_or(1),
+            extra,
+        };
+        self.blocks.pop();
+        Ok((id, block))
+    }
+
+    /// Parses a Large Motor or Medium Motor block in any mode but off
+    fn parse_motor(
+        &mut self,
+        size: MotorSize,
+        duration: Option<&str>,
+        extra: &mut Extra,
+    ) -> anyhow::Result<BlockType> {
+        let mut port = None;
+        let mut speed = None;
+        let mut duration = DurationTerminals::new(duration);
+        while let Some((id, value)) = self
+            .parse_block_attribute(extra)
+            .context("Failed parsing block attribute")?
+        {
+            match id.as_str() {
+                "Port" => port = Some(self.port(&value)?),
+                "Speed" => speed = Some(value.number().context("Failed parsing speed")?),
+                INTERRUPTS_TERMINAL => {}
+                _ if duration.parse(&id, &value)? => {}
+                _ => self.unknown_terminal(
+                    self.terminal_start,
+                    &id,
+                    anyhow!("Unexpected block attribute `{id}` for Motor"),
+                    extra,
+                )?,
+            }
+        }
+        let port = port.context("Failed finding port for Motor")?;
+        ensure!(
+            port.is_output(),
+            "Motor must use an output port, found {port}"
+        );
+        let speed = speed.context("Failed finding speed for Motor")?;
+        let duration = duration.build("Motor")?;
+
+        Ok(BlockType::Motor {
+            size,
+            port,
+            speed,
+            duration,
+        })
+    }
+
+    fn parse_motor_off(&mut self, size: MotorSize, extra: &mut Extra) -> anyhow::Result<BlockType> {
+        let mut port = None;
+        let mut brake = None;
+        while let Some((id, value)) = self
+            .parse_block_attribute(extra)
+            .context("Failed parsing block attribute")?
+        {
+            match id.as_str() {
+                "Port" => port = Some(self.port(&value)?),
+                "Brake\\ At\\ End" => {
+                    brake = Some(value.boolean().context("Failed parsing brake at end")?)
+                }
+                INTERRUPTS_TERMINAL => {}
+                _ => self.unknown_terminal(
+                    self.terminal_start,
+                    &id,
+                    anyhow!("Unexpected block attribute `{id}` for MotorOff"),
+                    extra,
+                )?,
+            }
+        }
+        let port = port.context("Failed finding port for MotorOff")?;
+        ensure!(
+            port.is_output(),
+            "MotorOff must use an output port, found {port}"
+        );
+        let brake = brake.context("Failed finding brake at end for MotorOff")?;
+
+        Ok(BlockType::MotorOff { size, port, brake })
+    }
+
+    /// Parses a variable or constant block, whose target says what it does and with what kind
+    /// of value
+    fn parse_variable(&mut self, target: &str, extra: &mut Extra) -> anyhow::Result<BlockType> {
+        let unknown = || Cause::UnknownTarget(target.into());
+        let (prefix, kind) = [VARIABLE_READ_TARGET, VARIABLE_WRITE_TARGET, CONSTANT_TARGET]
+            .into_iter()
+            .find_map(|prefix| Some((prefix, target.strip_prefix(prefix)?)))
+            .ok_or_else(unknown)?;
+        let kind = ValueKind::parse_target_name(kind).ok_or_else(unknown)?;
+        let mut name = None;
+        let mut value = None;
+        while let Some(terminal) = self
+            .parse_block_terminal(extra)
+            .context("Failed parsing block terminal")?
+        {
+            let id = terminal.id.as_str();
+            if id == VALUE_IN_TERMINAL || id == VALUE_OUT_TERMINAL {
+                ensure!(
+                    terminal.data_type == kind.data_type(),
+                    "Expected {} for `{id}` of {target}, found {}",
+                    kind.data_type(),
+                    terminal.data_type
+                );
+            }
+            match (id, terminal.value) {
+                (VARIABLE_NAME_TERMINAL, Some(v)) => name = Some(v.text()?.to_owned()),
+                (VALUE_IN_TERMINAL, Some(v)) => value = Some(v),
+                (VALUE_OUT_TERMINAL, None) => {}
+                (id, _) => self.unknown_terminal(
+                    self.terminal_start,
+                    id,
+                    anyhow!("Unexpected terminal `{id}` for {target}"),
+                    extra,
+                )?,
+            }
+        }
+        let name = || name.context(format!("Failed finding variable name for {target}"));
+        let value = || value.context(format!("Failed finding value for {target}"));
+
+        Ok(match prefix {
+            VARIABLE_READ_TARGET => BlockType::Variable {
+                name: name()?,
+                kind,
+                access: VariableAccess::Read,
+            },
+            VARIABLE_WRITE_TARGET => BlockType::Variable {
+                name: name()?,
+                kind,
+                access: VariableAccess::Write(value()?),
+            },
+            _ => BlockType::Constant {
+                kind,
+                value: value()?,
+            },
+        })
+    }
+
+    fn parse_case_structure(
+        &mut self,
+        attributes: Vec<ParsedAttribute>,
+    ) -> anyhow::Result<(Id, Block)> {
+        let mut extra = Extra {
+            attributes: self.preserved_attributes(&attributes),
+            ..Default::default()
+        };
+        let mut id = None;
+        let mut bounds = None;
+        let mut data_type = None;
+        let mut default = None;
+        let mut paired = None;
+        for attr in attributes {
+            match attr.key.0.as_str() {
+                "Id" => id = Some(Id(attr.value)),
+                "Bounds" => {
+                    bounds = Some(
+                        Bounds::parse(&attr.value)
+                            .context("Failed parsing case structure bounds")?,
+                    )
+                }
+                "DataType" => data_type = Some(DataType::parse(&attr.value)?),
+                "Default" => default = Some(Id(attr.value)),
+                "PairedConfigurableMethodCall" => paired = Some(Id(attr.value)),
+                // Only used by switches whose value comes from a wire, where it's computed
+                "UserSelectorBounds" => {}
+                _ => self.unsupported(anyhow!(
+                    "Unknown attribute in `ConfigurableFlatCaseStructure`: {}",
+                    attr.key.0
+                ))?,
+            }
+        }
+        let id = id.context("Missing id for ConfigurableFlatCaseStructure")?;
+        self.blocks.push(id.clone());
+        let bounds = bounds.context("Missing bounds for ConfigurableFlatCaseStructure")?;
+        let data_type = data_type.context("Missing data type for ConfigurableFlatCaseStructure")?;
+        let default = default.context("Missing default case for ConfigurableFlatCaseStructure")?;
+
+        let mut sequence_in = None;
+        let mut sequence_out = None;
+        let mut selector = None;
+        let mut data_wires = HashMap::new();
+        let mut cases = vec![];
+        let mut index = 0;
+        loop {
+            match self.peek_event()? {
+                Event::Empty(t) if t.name().as_ref() == b"Terminal" => {
+                    let attributes =
+                        parse_attributes(&t).context("Failed parsing terminal attributes")?;
+                    let terminal = attributes
+                        .iter()
+                        .find(|attr| attr.key.0 == "Id")
+                        .context("Missing id for terminal of ConfigurableFlatCaseStructure")?;
+                    match terminal.value.as_str() {
+                        "SequenceIn" => {
+                            sequence_in = Some(
+                                self.parse_sequence_terminal(SequenceBlockType::In, &mut extra)
+                                    .context("Failed parsing SequenceIn")?,
+                            )
+                        }
+                        "SequenceOut" => {
+                            sequence_out = Some(
+                                self.parse_sequence_terminal(SequenceBlockType::Out, &mut extra)
+                                    .context("Failed parsing SequenceOut")?,
+                            )
+                        }
+                        _ => {
+                            self.next_event()?;
+                            ensure!(selector.is_none(), "Found a second selector terminal");
+                            let terminal = self
+                                .parse_terminal_tag(&t, &mut extra)
+                                .context("Failed parsing selector terminal")?;
+                            ensure!(
+                                terminal.direction == Direction::Input
+                                    && terminal.data_type != DataType::Sequence,
+                                "Unexpected terminal `{}` in ConfigurableFlatCaseStructure",
+                                terminal.id
+                            );
+                            // Taken now, since the blocks in the cases take their own
+                            data_wires.extend(self.data_wires.drain());
+                            selector = Some(terminal);
+                        }
+                    }
+                }
+                Event::Start(t) if t.name().as_ref() == b"ConfigurableFlatCaseStructure.Case" => {
+                    self.next_event()?;
+                    let attributes =
+                        parse_attributes(&t).context("Failed parsing case attributes")?;
+                    let mut case = self.parse_case(attributes).context("Failed parsing case")?;
+                    case.default = case.id == default;
+                    cases.push(case);
+                }
+                Event::Start(t) | Event::Empty(t) if self.preserve => {
+                    let empty = matches!(self.next_event()?, Event::Empty(_));
+                    let (name, prefix) =
+                        extract_name_from_qname(t.name()).context("Failed parsing tag name")?;
+                    let attributes =
+                        parse_attributes(&t).context("Failed parsing tag attributes")?;
+                    self.unsupported(anyhow!(
+                        "Unexpected `{name}` in ConfigurableFlatCaseStructure"
+                    ))?;
+                    let name = qualified_name(&name, prefix.as_deref());
+                    let node = self.collect_node(name, attributes, empty)?;
+                    extra.children.push((index, node));
+                }
+                Event::End(t) => {
+                    self.next_event()?;
+                    let (name, _) =
+                        extract_name_from_qname(t.name()).context("Failed parsing end tag name")?;
+                    ensure!(
+                        name == "ConfigurableFlatCaseStructure",
+                        "Unexpected end tag `{name}` in ConfigurableFlatCaseStructure"
+                    );
+                    break;
+                }
+                _ => bail!("Unexpected event in ConfigurableFlatCaseStructure"),
+            }
+            index += 1;
+        }
+        ensure!(
+            cases.iter().any(|case| case.default),
+            "Default case `{default:?}` not found in ConfigurableFlatCaseStructure"
+        );
+
+        let block = Block {
+            ty: BlockType::Switch {
+                data_type,
+                paired,
+                selector,
+                cases,
+            },
+            bounds,
+            sequence_in,
+            sequence_out,
+            data_wires,
+            // Switches don't have ports
+            layer: 1,
+            extra,
+        };
+        self.blocks.pop();
+        Ok((id, block))
+    }
+
+    /// Parses a `ConfigurableFlatCaseStructure.Case` whose start tag was just read
+    fn parse_case(&mut self, attributes: Vec<ParsedAttribute>) -> anyhow::Result<Case> {
+        let extra = Extra {
+            attributes: self.preserved_attributes(&attributes),
+            ..Default::default()
+        };
+        let mut id = None;
+        let mut bounds = None;
+        let mut pattern = None;
+        for attr in attributes {
+            match attr.key.0.as_str() {
+                "Id" => id = Some(Id(attr.value)),
+                "Bounds" => {
+                    bounds = Some(Bounds::parse(&attr.value).context("Failed parsing case bounds")?)
+                }
+                "Pattern" => pattern = Some(attr.value),
+                _ => self.unsupported(anyhow!("Unknown attribute in case: {}", attr.key.0))?,
+            }
+        }
+        let id = id.context("Missing id for case")?;
+        let bounds = bounds.context("Missing bounds for case")?;
+        let pattern = pattern.context("Missing pattern for case")?;
+
+        let entry = self
+            .parse_sequence_node("Output")
+            .context("Failed parsing case entry")?;
+        let exit = self
+            .parse_sequence_node("Input")
+            .context("Failed parsing case exit")?;
+        let diagram = self.parse_diagram("ConfigurableFlatCaseStructure.Case")?;
+
+        Ok(Case {
+            id,
+            bounds,
+            pattern,
+            default: false,
+            entry,
+            exit,
+            diagram,
+            extra,
+        })
+    }
+
+    fn parse_loop(&mut self, attributes: Vec<ParsedAttribute>) -> anyhow::Result<(Id, Block)> {
+        let mut extra = Extra {
+            attributes: self.preserved_attributes(&attributes),
+            ..Default::default()
+        };
+        let mut id = None;
+        let mut bounds = None;
+        let mut interrupt_name = None;
+        for attr in attributes {
+            match attr.key.0.as_str() {
+                "Id" => id = Some(Id(attr.value)),
+                "Bounds" => {
+                    bounds = Some(Bounds::parse(&attr.value).context("Failed parsing loop bounds")?)
+                }
+                "InterruptName" => interrupt_name = Some(attr.value),
+                _ => self.unsupported(anyhow!(
+                    "Unknown attribute in `ConfigurableWhileLoop`: {}",
+                    attr.key.0
+                ))?,
+            }
+        }
+        let id = id.context("Missing id for ConfigurableWhileLoop")?;
+        self.blocks.push(id.clone());
+        let bounds = bounds.context("Missing bounds for ConfigurableWhileLoop")?;
+        let interrupt_name =
+            interrupt_name.context("Missing interrupt name for ConfigurableWhileLoop")?;
+
+        let Event::Start(t) = self.next_event()? else {
+            bail!("Expected the loop condition in ConfigurableWhileLoop");
+        };
+        let (name, _) =
+            extract_name_from_qname(t.name()).context("Failed parsing start tag name")?;
+        ensure!(
+            name == "ConfigurableWhileLoop.BuiltInMethod",
+            "Expected the loop condition in ConfigurableWhileLoop, found `{name}`"
+        );
+        let (condition_id, condition) = self
+            .parse_loop_condition(&mut extra)
+            .context("Failed parsing loop condition")?;
+        // Before the body is parsed, as its blocks take their own
+        let data_wires = std::mem::take(&mut self.data_wires);
+        let layer = self.layer.take().unwrap_or(1);
+        let Event::End(_) = self.next_event()? else {
+            bail!("Expected end of the loop condition");
+        };
+
+        let (sequence_in, sequence_out) = self
+            .parse_method_sequence_blocks(&mut extra)
+            .context("Failed parsing sequence blocks for loop")?;
+        let entry = self
+            .parse_sequence_node("Output")
+            .context("Failed parsing loop entry")?;
+        let exit = self
+            .parse_sequence_node("Input")
+            .context("Failed parsing loop exit")?;
+        let diagram = self.parse_diagram("ConfigurableWhileLoop")?;
+
+        let block = Block {
+            ty: BlockType::Loop {
+                interrupt_name,
+                condition,
+                condition_id,
+                entry,
+                exit,
+                diagram,
+            },
+            bounds,
+            sequence_in: Some(sequence_in),
+            sequence_out: Some(sequence_out),
+            data_wires,
+            layer,
+            extra,
+        };
+        self.blocks.pop();
+        Ok((id, block))
+    }
+
+    /// Parses the method call checking whether a loop should stop. Its terminals are kept in the
+    /// loop's `extra`, since they can't clash with the loop's own
+    fn parse_loop_condition(&mut self, extra: &mut Extra) -> anyhow::Result<(Id, LoopCondition)> {
+        let Event::Start(t) = self.next_event()? else {
+            bail!("Expected method call in loop condition");
+        };
+        let (name, _) =
+            extract_name_from_qname(t.name()).context("Failed parsing start tag name")?;
+        ensure!(
+            name == "ConfigurableMethodCall",
+            "Expected method call in loop condition, found `{name}`"
+        );
+        let mut id = None;
+        let mut target = None;
+        for attr in parse_attributes(&t).context("Failed parsing method call attributes")? {
+            match attr.key.0.as_str() {
+                "Id" => id = Some(Id(attr.value)),
+                "Target" => target = Some(attr.value),
+                // It isn't drawn, so always `0 0 0 0`
+                "Bounds" => {}
+                _ => self.unsupported(anyhow!(
+                    "Unknown attribute in loop condition: {}",
+                    attr.key.0
+                ))?,
+            }
+        }
+        let id = id.context("Missing id for loop condition")?;
+        let target = target.context("Missing target for loop condition")?;
+
+        let mut port = None;
+        let mut terminals = vec![];
+        while let Some(terminal) = self
+            .parse_block_terminal(extra)
+            .context("Failed parsing loop condition terminal")?
+        {
+            match (terminal.id.as_str(), &terminal.value) {
+                ("Port", Some(value)) => port = Some(self.port(value)?),
+                _ => terminals.push(terminal),
+            }
+        }
+        let value = |id: &str| {
+            terminals
+                .iter()
+                .find(|terminal| terminal.id == id)
+                .and_then(|terminal| terminal.value.as_ref())
+                .context(format!("Failed finding {id} for loop condition"))
+        };
+        let condition = match target.as_str() {
+            "X3\\.Lib:StopNever" => LoopCondition::Unlimited,
+            "X3\\.Lib:StopAfterCount" => LoopCondition::Count(
+                value("Count")?
+                    .integer()
+                    .context("Failed parsing loop count")?
+                    .try_into()
+                    .context("Loop count is out of range")?,
+            ),
+            "X3\\.Lib:StopAfterTime" => LoopCondition::Time(
+                value("Seconds")?
+                    .number()
+                    .context("Failed parsing loop time")?,
+            ),
+            "X3\\.Lib:StopOnLogic" => LoopCondition::Logic {
+                until: value("Until")?
+                    .boolean()
+                    .context("Failed parsing loop logic value")?,
+            },
+            _ if target.ends_with("Compare\\.vix") => {
+                let port = port.context("Failed finding port for loop sensor condition")?;
+                LoopCondition::Sensor {
+                    target,
+                    port,
+                    terminals,
+                }
+            }
+            _ => return Err(Cause::UnknownTarget(target).into()),
+        };
+        let Event::End(_) = self.next_event()? else {
+            bail!("Expected end of loop condition method call");
+        };
+        Ok((id, condition))
+    }
 
-        let Event::End(t) = self.next_event()? else {
-                    bail!("Expected end tag to end ConfigurableMethodTerminal in StartBlock tag");
-                };
-        let qname = t.name();
-        let (name, prefix) = extract_name_from_qname(qname)
-            .context("Failed parsing start tag name in StartBlock")?;
+    /// Parses a `SequenceNode` with the given id, along with its terminal
+    fn parse_sequence_node(&mut self, expected_id: &str) -> anyhow::Result<SequenceNode> {
+        let Event::Start(t) = self.next_event()? else {
+            bail!("Expected SequenceNode start tag");
+        };
+        let (name, prefix) =
+            extract_name_from_qname(t.name()).context("Failed parsing start tag name")?;
         if let Some(prefix) = prefix {
-            bail!("Unexpected prefix `{prefix}` to end ConfigurableMethodTerminal tag");
+            bail!("Unexpected prefix namespace {prefix} in SequenceNode tag");
         }
         ensure!(
-            name == "ConfigurableMethodTerminal",
-            "Unexpected tag name `{name}` to end ConfigurableMethodTerminal"
+            name == "SequenceNode",
+            "Expected SequenceNode, found `{name}`"
         );
+        let attributes = parse_attributes(&t).context("Failed parsing SequenceNode attributes")?;
+        let mut extra = Extra {
+            attributes: self.preserved_attributes(&attributes),
+            ..Default::default()
+        };
+        let mut bounds = None;
+        for attr in attributes {
+            match attr.key.0.as_str() {
+                "Id" => ensure!(
+                    attr.value == expected_id,
+                    "Expected SequenceNode `{expected_id}`, found `{}`",
+                    attr.value
+                ),
+                "Bounds" => {
+                    bounds = Some(
+                        Bounds::parse(&attr.value).context("Failed parsing SequenceNode bounds")?,
+                    )
+                }
+                _ => {
+                    self.unsupported(anyhow!("Unknown attribute in SequenceNode: {}", attr.key.0))?
+                }
+            }
+        }
+        let bounds = bounds.context("Missing bounds for SequenceNode")?;
 
         let Event::Empty(t) = self.next_event()? else {
-                    bail!("Expected empty tag in `StartBlock`");
-                };
-        let qname = t.name();
-        let (name, prefix) = extract_name_from_qname(qname)
-            .context("Failed parsing start tag name in StartBlock")?;
-        let attributes =
-            parse_attributes(&t).context("Failed parsing start tag attributes in StartBlock")?;
-        ensure!(name == "Terminal", "Unexpected empty tag in `StartBlock`");
-        if let Some(prefix) = prefix {
-            bail!("Unexpected prefix `{prefix}` in empty tag");
+            bail!("Expected terminal in SequenceNode");
+        };
+        let attributes = parse_attributes(&t).context("Failed parsing terminal attributes")?;
+        if self.preserve {
+            extra.terminals.insert(
+                "SequenceTerminal".into(),
+                self.preserved_attributes(&attributes),
+            );
         }
-
         let mut wire_id = None;
         for attr in attributes {
             match attr.key.0.as_str() {
                 "Id" => ensure!(
-                    attr.value == "SequenceOut",
-                    "Unexpected Id `{}` in `StartBlock` SequenceOut",
-                    attr.value
-                ),
-                "Direction" => ensure!(
-                    attr.value == "Output",
-                    "Unexpected Direction `{}` in `StartBlock` SequenceOut",
+                    attr.value == "SequenceTerminal",
+                    "Expected `SequenceTerminal` id, found `{}`",
                     attr.value
                 ),
                 "Wire" => wire_id = Some(Id(attr.value)),
-                // TODO: Should reuse this later
-                "DataType" => ensure!(
-                    attr.value
-                        == "NationalInstruments:SourceModel:DataTypes:X3SequenceWireDataType",
-                    "Unexpected DataType `{}` in `StartBlock` SequenceOut",
-                    attr.value
-                ),
-                // TODO: What even is this?
-                "Hotspot" => {}
-                "Bounds" => {}
-                _ => bail!(
-                    "Unexpected attribute `{}` for `SequenceOut` in `StartBlock`",
+                "Direction" | "DataType" | "Hotspot" | "Bounds" => {}
+                _ => self.unsupported(anyhow!(
+                    "Unexpected attribute in SequenceNode terminal: {}",
                     attr.key.0
-                ),
+                ))?,
             }
         }
+        let Event::End(_) = self.next_event()? else {
+            bail!("Expected SequenceNode end tag");
+        };
 
-        let sequence_out = Some(SequenceBlock {
-            ty: SequenceBlockType::Out,
+        Ok(SequenceNode {
+            bounds,
             wire_id,
-        });
-        let Event::End(t) = self.next_event()? else {
-                    bail!("Expected end tag in `StartBlock`");
-                };
-        let qname = t.name();
-        let (name, prefix) =
-            extract_name_from_qname(qname).context("Failed parsing end tag name in StartBlock")?;
-        ensure!(name == "StartBlock", "Unexpected end tag for tag `{name}`");
-        if let Some(prefix) = prefix {
-            bail!("Unexpected prefix `{prefix}` in end tag");
-        }
-        let block = Block {
-            ty: BlockType::Start,
-            sequence_in: None,
-            sequence_out,
-        };
-        Ok((id, block))
+            extra,
+        })
     }
 
-    fn parse_method_call(
-        &mut self,
-        attributes: Vec<ParsedAttribute>,
-    ) -> anyhow::Result<(Id, Block)> {
-        let mut id = None;
-        let mut ty = None;
-        for attr in attributes {
-            let name = attr.key.0;
-            match name.as_str() {
-                "Id" => id = Some(Id(attr.value)),
-                "Bounds" => {}
-                "Target" => ty = Some(attr.value),
-                _ => bail!("Unexpected attribute `{name}` in `ConfigurableMethodCall`"),
+    /// Parses a wait block, whose mode is in its target like `WaitGyroAngleCompare\.vix`
+    fn parse_wait(&mut self, target: &str, extra: &mut Extra) -> anyhow::Result<BlockType> {
+        let mut terminals = HashMap::new();
+        let mut starts = HashMap::new();
+        while let Some((id, value)) = self
+            .parse_block_attribute(extra)
+            .context("Failed parsing block attribute")?
+        {
+            if id != INTERRUPTS_TERMINAL {
+                starts.insert(id.clone(), self.terminal_start);
+                terminals.insert(id, value);
             }
         }
-        let id = id.context("Failed to find id for `ConfigurableMethodCall`")?;
-        let ty = ty.context("Failed to find target type for `ConfigurableMethodCall`")?;
+        let mut terminals = WaitTerminals {
+            target,
+            terminals,
+            layer: None,
+        };
 
-        let res = Ok(match ty.as_str() {
-            "MoveUnlimited\\.vix" => (id, self.parse_motor_move()?),
-            _ => bail!("Unknown call type {ty}"),
-        });
-        let Event::End(t) = self.next_event()? else {
-            bail!("Expected end tag");
+        let mode = target
+            .strip_prefix("Wait")
+            .and_then(|s| s.strip_suffix("\\.vix"))
+            .ok_or_else(|| Cause::UnknownTarget(target.into()))?;
+        let wait = if mode == "Time" {
+            Wait::Time {
+                seconds: terminals.number("Seconds")?,
+            }
+        } else if let Some(sensor) = mode.strip_suffix("Compare") {
+            match sensor {
+                "Touch" => Wait::TouchCompare {
+                    port: terminals.port()?,
+                    state: ButtonState::parse(&terminals.take(BUTTON_STATE_TERMINAL)?)?,
+                },
+                "Color" => Wait::ColorCompare {
+                    port: terminals.port()?,
+                    colors: terminals
+                        .take("Set\\ of\\ colors")?
+                        .array()?
+                        .iter()
+                        .map(Color::parse)
+                        .collect::<anyhow::Result<_>>()?,
+                },
+                "Buttons" => Wait::ButtonsCompare {
+                    buttons: terminals
+                        .take("Set\\ of\\ brick\\ button\\ IDs")?
+                        .array()?
+                        .iter()
+                        .map(BrickButton::parse)
+                        .collect::<anyhow::Result<_>>()?,
+                    state: ButtonState::parse(&terminals.take(BUTTON_STATE_TERMINAL)?)?,
+                },
+                _ => Wait::Compare {
+                    sensor: terminals.sensor(sensor)?,
+                    comparison: Comparison::parse(&terminals.take("Comparison\\ Type")?)?,
+                    threshold: terminals.number("Threshold\\ Value")?,
+                },
+            }
+        } else if let Some(sensor) = mode.strip_suffix("Change") {
+            match sensor {
+                "Touch" => Wait::TouchChange {
+                    port: terminals.port()?,
+                },
+                "Color" => Wait::ColorChange {
+                    port: terminals.port()?,
+                },
+                "Buttons" => Wait::ButtonsChange,
+                _ => Wait::Change {
+                    sensor: terminals.sensor(sensor)?,
+                    direction: ChangeDirection::parse(&terminals.take("Direction")?)?,
+                    amount: terminals.number("Amount")?,
+                },
+            }
+        } else {
+            return Err(Cause::UnknownTarget(target.into()).into());
         };
-        let qname = t.name();
-        let (name, prefix) = extract_name_from_qname(qname)
-            .context("Failed parsing name in ConfigurableMethodTerminal")?;
-        if let Some(prefix) = prefix {
-            bail!("Unexpected prefix `{prefix}` in ConfigurableMethodTerminal");
+        self.layer = terminals.layer;
+        for id in terminals.terminals.into_keys() {
+            self.unknown_terminal(
+                starts[&id],
+                &id,
+                anyhow!("Unexpected block attribute `{id}` for {target}"),
+                extra,
+            )?;
         }
-        ensure!(
-            name == "ConfigurableMethodCall",
-            "Expected end tag for ConfigurableMethodCall, found `{name}`"
-        );
-
-        res
+        Ok(BlockType::Wait(wait))
     }
 
-    fn parse_motor_move(&mut self) -> anyhow::Result<Block> {
-        let mut ports = None;
-        let mut steering = None;
-        let mut speed = None;
-        while let Some(BlockAttribute { id, value }) = self
-            .parse_block_attribute()
-            .context("Failed parsing block attribute")?
-        {
-            match id.as_str() {
-                "Ports" => {
-                    let mut iter = value.chars();
-                    iter.next();
-                    iter.next();
-                    let p1 = iter.next().context("Expected first port")?;
-                    iter.next();
-                    let p2 = iter.next().context("Expected second port")?;
-                    ports = Some((p1, p2));
-                }
-                "Steering" => {
-                    steering = Some(
-                        value
-                            .parse()
-                            .context("Failed parsing steering value as number")?,
-                    )
-                }
-                "Speed" => {
-                    speed = Some(
-                        value
-                            .parse()
-                            .context("Failed parsing speed value as number")?,
-                    )
-                }
-                // Ignore cuz it's presumably always the same
-                "InterruptsToListenFor_16B03592_CD76_4D58_8DC3_E3C3091E327A" => {}
-                _ => bail!("Unexpected block attribute `{id}` for MotorMove"),
-            }
+    /// Keeps the terminals of a call we don't know as they are
+    fn parse_unknown_call(
+        &mut self,
+        target: String,
+        extra: &mut Extra,
+    ) -> anyhow::Result<BlockType> {
+        let mut index = 0;
+        while let Event::Start(t) = self.peek_event()? {
+            self.next_event()?;
+            let (name, prefix) =
+                extract_name_from_qname(t.name()).context("Failed parsing start tag name")?;
+            let attributes = parse_attributes(&t).context("Failed parsing start tag attributes")?;
+            let name = qualified_name(&name, prefix.as_deref());
+            let node = self.collect_node(name, attributes, false)?;
+            extra.children.push((index, node));
+            index += 1;
         }
-        let ports = ports.context("Failed finding ports for MotorMove")?;
-        let steering = steering.context("Failed finding steering for MotorMove")?;
-        let speed = speed.context("Failed finding speed for MotorMove")?;
-
-        let (sequence_in, sequence_out) = self
-            .parse_method_sequence_blocks()
-            .context("Failed parsing sequence blocks for method")?;
-        let sequence_in = Some(sequence_in);
-        let sequence_out = Some(sequence_out);
-        Ok(Block {
-            sequence_in,
-            sequence_out,
-            ty: BlockType::MotorMove {
-                steering,
-                ports,
-                speed,
-            },
-        })
+        Ok(BlockType::Unknown { target })
     }
 
     fn parse_end_tag(&mut self, name: String, _prefix: Option<String>) -> anyhow::Result<()> {
         match name.as_str() {
-            // Same as line 186
-            "FrontPanel" | "BlockDiagram" => {}
+            // Its contents are skipped or preserved like any unknown element, see `is_skipped`
+            "FrontPanel" => {}
             // These are also safe to ignore, like the start tags
             "AnimationProperties.Animations"
             | "EventProperties.Events"
@@ -486,31 +2505,111 @@ impl FileBuilder {
         prefix: Option<String>,
         attributes: Vec<ParsedAttribute>,
     ) -> anyhow::Result<()> {
-        let _ = prefix;
-        let _ = attributes;
-        match name.as_str() {
-            // TODO: Should this do something?
-            "FrontPanelCanvas" => {}
-            // These are hopefully safe to ignore
-            "AnimationsContainer" | "EventContainer" => {}
-            "Wire" => {
-                let (id, wire) = self
-                    .parse_wire_tag(attributes)
-                    .context("Parsing wire tag failed")?;
-                if let Some(_) = self.wires.get(&id) {
-                    bail!("Found duplicate wire ids {id:?}");
-                }
-                self.wires.insert(id, wire);
+        if !is_skipped(&name) {
+            self.unsupported(anyhow!("{name} empty tag not implemented"))?;
+        }
+        if self.preserve {
+            let name = qualified_name(&name, prefix.as_deref());
+            let node = self.collect_node(name, attributes, true)?;
+            self.preserve_file_node(node);
+        }
+        Ok(())
+    }
+
+    /// Parses the next terminal of a block taking a configured value, keeping the ones without
+    /// a value as unknown when preserving
+    fn parse_block_attribute(
+        &mut self,
+        extra: &mut Extra,
+    ) -> anyhow::Result<Option<(String, Literal)>> {
+        loop {
+            let Some(terminal) = self.parse_block_terminal(extra)? else {
+                return Ok(None);
+            };
+            match terminal.value {
+                Some(value) => return Ok(Some((terminal.id, value))),
+                None => self.unknown_terminal(
+                    self.terminal_start,
+                    &terminal.id,
+                    anyhow!("Expected a configured value for `{}`", terminal.id),
+                    extra,
+                )?,
             }
-            _ => bail!("{name} empty tag not implemented"),
         }
+    }
+
+    /// Parses the value of a `Port` terminal, keeping its layer for the block
+    fn port(&mut self, value: &Literal) -> anyhow::Result<Port> {
+        let (layer, port) = parse_port(value.text()?)?;
+        self.layer = Some(layer);
+        Ok(port)
+    }
+
+    /// Parses the value of a `Ports` terminal, keeping its layer for the block
+    fn ports(&mut self, value: &Literal) -> anyhow::Result<(Port, Port)> {
+        let (layer, ports) = parse_ports(value.text()?)?;
+        self.layer = Some(layer);
+        Ok(ports)
+    }
+
+    /// Fails with `err` for a terminal the block doesn't know, unless it's being preserved, in
+    /// which case the terminal starting at the event `start` is kept as an unknown child of the
+    /// block
+    fn unknown_terminal(
+        &mut self,
+        start: usize,
+        id: &str,
+        err: anyhow::Error,
+        extra: &mut Extra,
+    ) -> anyhow::Result<()> {
+        self.unsupported(err)?;
+        let end = self.idx;
+        self.idx = start;
+        let Event::Start(t) = self.next_event()? else {
+            bail!("Expected the start of terminal `{id}`");
+        };
+        let (name, prefix) =
+            extract_name_from_qname(t.name()).context("Failed parsing start tag name")?;
+        let attributes = parse_attributes(&t).context("Failed parsing start tag attributes")?;
+        let name = qualified_name(&name, prefix.as_deref());
+        let node = self.collect_node(name, attributes, false)?;
+        self.idx = end;
+        // It's all in the node now, wire included
+        extra.terminals.remove(id);
+        self.data_wires.remove(id);
+        extra.children.push((self.child_index(start), node));
+        extra.children.sort_by_key(|(index, _)| *index);
         Ok(())
     }
 
-    fn parse_block_attribute(&mut self) -> anyhow::Result<Option<BlockAttribute>> {
+    /// Index of the element starting at the event `start` among the children of its parent
+    fn child_index(&self, start: usize) -> usize {
+        let mut depth = 0;
+        let mut index = 0;
+        for event in self.events[..start].iter().rev() {
+            match event {
+                Event::End(_) => depth += 1,
+                Event::Start(_) if depth == 0 => break,
+                Event::Start(_) => {
+                    depth -= 1;
+                    if depth == 0 {
+                        index += 1;
+                    }
+                }
+                Event::Empty(_) if depth == 0 => index += 1,
+                _ => {}
+            }
+        }
+        index
+    }
+
+    /// Parses the next `ConfigurableMethodTerminal` of a block, if there's one. Its wire is kept
+    /// in `data_wires` for the block to take, rather than on the returned terminal
+    fn parse_block_terminal(&mut self, extra: &mut Extra) -> anyhow::Result<Option<Terminal>> {
         let Event::Start(t) = self.peek_event()? else {
             return Ok(None);
         };
+        self.terminal_start = self.idx;
         // Skip it since it's what we want
         self.next_event()?;
 
@@ -527,18 +2626,21 @@ impl FileBuilder {
             "Unexpected start tag `{name}` where ConfigurableMethodTerminal was expected"
         );
         ensure!(
-            attributes.len() == 1,
-            "Expected only 1 attribute in ConfigurableMethodTerminal, found {}",
+            attributes.len() <= 1,
+            "Expected at most 1 attribute in ConfigurableMethodTerminal, found {}",
             attributes.len()
         );
-        let value = {
-            let attr = attributes.pop().unwrap();
-            let name = attr.key.0;
-            ensure!(
-                name == "ConfiguredValue",
-                "Expected attribute ConfiguredValue, found `{name}`"
-            );
-            attr.value
+        // Outputs don't have a value
+        let value = match attributes.pop() {
+            Some(attr) => {
+                let name = attr.key.0;
+                ensure!(
+                    name == "ConfiguredValue",
+                    "Expected attribute ConfiguredValue, found `{name}`"
+                );
+                Some(attr.value)
+            }
+            None => None,
         };
         let Event::Empty(t) = self.peek_event()? else {
             bail!("Expected empty tag after ConfigurableMethodTerminal tag");
@@ -546,82 +2648,99 @@ impl FileBuilder {
         // Same thing, we already know it so skip it
         self.next_event()?;
 
-        let qname = t.name();
-        let (name, prefix) = extract_name_from_qname(qname)
-            .context("Failed parsing name in ConfigurableMethodTerminal")?;
-        let attributes = parse_attributes(&t)
-            .context("Failed parsing attributes in ConfigurableMethodTerminal")?;
-
-        if let Some(prefix) = prefix {
-            bail!("Unexpected prefix `{prefix}` in ConfigurableMethodTerminal");
-        }
-        ensure!(
-            name == "Terminal",
-            "Expected `Terminal` empty tag, found `{name}`"
-        );
-        let mut id = None;
-        for attr in attributes {
-            let name = attr.key.0;
-            match name.as_str() {
-                "Id" => id = Some(attr.value),
-                "Direction" | "DataType" | "Hotspot" | "Bounds" => {}
-                _ => bail!("Unexpected attribute `{name}` in Terminal"),
-            }
-        }
-        let id = id.context("Failed to find id in Terminal")?;
+        let terminal = self.parse_terminal_tag(&t, extra)?;
         let Event::End(_) = self.next_event()? else {
             bail!("Expected ConfigurableMethodTerminal end tag, found other");
         };
 
-        Ok(Some(BlockAttribute { id, value }))
-    }
-
-    fn parse_method_sequence_blocks(&mut self) -> anyhow::Result<(SequenceBlock, SequenceBlock)> {
-        let Event::Empty(t) = self.next_event()? else {
-            bail!("Expected empty tag for parsing sequence block");
+        // Ports look like `1.B+C` even though their terminals say they're numbers
+        let id = &terminal.id;
+        let value = match value {
+            Some(value) if id == "Port" || id == "Ports" => Some(Literal::String(value)),
+            Some(value) => Some(
+                Literal::parse(&terminal.data_type, &value)
+                    .context(format!("Failed parsing value of `{id}`"))?,
+            ),
+            None => None,
         };
+        Ok(Some(Terminal { value, ..terminal }))
+    }
 
-        let qname = t.name();
+    /// Parses a `Terminal` empty tag that was just read, other than a sequence one. Its wire is
+    /// kept in `data_wires` for the block to take, rather than on the returned terminal
+    fn parse_terminal_tag(
+        &mut self,
+        t: &BytesStart,
+        extra: &mut Extra,
+    ) -> anyhow::Result<Terminal> {
         let (name, prefix) =
-            extract_name_from_qname(qname).context("Failed parsing empty tag name")?;
-        let attributes = parse_attributes(&t).context("Failed parsing empty tag attributes")?;
+            extract_name_from_qname(t.name()).context("Failed parsing name of terminal")?;
+        let attributes = parse_attributes(t).context("Failed parsing attributes of terminal")?;
+
         if let Some(prefix) = prefix {
-            bail!("Unexpected prefix namespace {prefix} in `ConfigurableMethodCall` sequence tag");
+            bail!("Unexpected prefix `{prefix}` in Terminal");
         }
         ensure!(
             name == "Terminal",
-            "Expected tag name Terminal, found `{name}`"
+            "Expected `Terminal` empty tag, found `{name}`"
         );
-        let mut wire_id = None;
+        let preserved = self.preserved_attributes(&attributes);
+        let mut id = None;
+        let mut direction = None;
+        let mut wire = None;
+        let mut data_type = None;
         for attr in attributes {
             let name = attr.key.0;
             match name.as_str() {
-                "Id" => ensure!(
-                    attr.value == "SequenceIn",
-                    "Expected `SequenceIn` id, found `{}`",
-                    attr.value
-                ),
-                "Direction" => ensure!(
-                    attr.value == "Input",
-                    "Expected `Input` direction, found `{}`",
-                    attr.value
-                ),
-                "Wire" => wire_id = Some(Id(attr.value)),
-                "DataType" => ensure!(
-                    attr.value
-                        == "NationalInstruments:SourceModel:DataTypes:X3SequenceWireDataType",
-                    "Expected `Input` direction, found `{}`",
-                    attr.value
-                ),
+                "Id" => id = Some(attr.value),
+                "Direction" => direction = Some(Direction::parse(&attr.value)?),
+                "Wire" => wire = Some(Id(attr.value)),
+                "DataType" => data_type = Some(DataType::parse(&attr.value)?),
                 "Hotspot" | "Bounds" => {}
-                _ => bail!("Unexpected sequence attribute: {name}"),
+                _ => self.unsupported(anyhow!("Unexpected attribute `{name}` in Terminal"))?,
             }
         }
-        let sequence_in = SequenceBlock {
-            ty: SequenceBlockType::In,
-            wire_id,
-        };
+        let id = id.context("Failed to find id in Terminal")?;
+        let direction = direction.context(format!("Failed to find direction of `{id}`"))?;
+        let data_type = data_type.context(format!("Failed to find data type of `{id}`"))?;
+        if self.preserve {
+            extra.terminals.insert(id.clone(), preserved);
+        }
+        if let Some(wire) = wire {
+            self.data_wires.insert(id.clone(), wire);
+        }
+        Ok(Terminal {
+            visible: direction == Direction::Input,
+            id,
+            direction,
+            data_type,
+            value: None,
+            wire: None,
+        })
+    }
+
+    fn parse_method_sequence_blocks(
+        &mut self,
+        extra: &mut Extra,
+    ) -> anyhow::Result<(SequenceBlock, SequenceBlock)> {
+        let sequence_in = self
+            .parse_sequence_terminal(SequenceBlockType::In, extra)
+            .context("Failed parsing SequenceIn")?;
+        let sequence_out = self
+            .parse_sequence_terminal(SequenceBlockType::Out, extra)
+            .context("Failed parsing SequenceOut")?;
+        Ok((sequence_in, sequence_out))
+    }
 
+    fn parse_sequence_terminal(
+        &mut self,
+        ty: SequenceBlockType,
+        extra: &mut Extra,
+    ) -> anyhow::Result<SequenceBlock> {
+        let (expected_id, expected_direction) = match ty {
+            SequenceBlockType::In => ("SequenceIn", "Input"),
+            SequenceBlockType::Out => ("SequenceOut", "Output"),
+        };
         let Event::Empty(t) = self.next_event()? else {
             bail!("Expected empty tag for parsing sequence block");
         };
@@ -631,104 +2750,77 @@ impl FileBuilder {
             extract_name_from_qname(qname).context("Failed parsing empty tag name")?;
         let attributes = parse_attributes(&t).context("Failed parsing empty tag attributes")?;
         if let Some(prefix) = prefix {
-            bail!("Unexpected prefix namespace {prefix} in `ConfigurableMethodCall` sequence tag");
+            bail!("Unexpected prefix namespace {prefix} in sequence tag");
         }
         ensure!(
             name == "Terminal",
             "Expected tag name Terminal, found `{name}`"
         );
+        if self.preserve {
+            extra.terminals.insert(
+                expected_id.to_owned(),
+                self.preserved_attributes(&attributes),
+            );
+        }
         let mut wire_id = None;
         for attr in attributes {
             let name = attr.key.0;
             match name.as_str() {
                 "Id" => ensure!(
-                    attr.value == "SequenceOut",
-                    "Expected `SequenceOut` id, found `{}`",
+                    attr.value == expected_id,
+                    "Expected `{expected_id}` id, found `{}`",
                     attr.value
                 ),
                 "Direction" => ensure!(
-                    attr.value == "Output",
-                    "Expected `Output` direction, found `{}`",
+                    attr.value == expected_direction,
+                    "Expected `{expected_direction}` direction, found `{}`",
                     attr.value
                 ),
                 "Wire" => wire_id = Some(Id(attr.value)),
                 "DataType" => ensure!(
-                    attr.value
-                        == "NationalInstruments:SourceModel:DataTypes:X3SequenceWireDataType",
-                    "Expected `Input` direction, found `{}`",
+                    attr.value == SEQUENCE_DATA_TYPE,
+                    "Expected sequence data type, found `{}`",
                     attr.value
                 ),
                 "Hotspot" | "Bounds" => {}
-                _ => bail!("Unexpected sequence attribute: {name}"),
+                _ => self.unsupported(anyhow!("Unexpected sequence attribute: {name}"))?,
             }
         }
-        let sequence_out = SequenceBlock {
-            ty: SequenceBlockType::Out,
-            wire_id,
-        };
-        Ok((sequence_in, sequence_out))
+        Ok(SequenceBlock { ty, wire_id })
     }
 
     fn parse_wire_tag(&mut self, attributes: Vec<ParsedAttribute>) -> anyhow::Result<(Id, Wire)> {
+        let extra = Extra {
+            attributes: self.preserved_attributes(&attributes),
+            ..Default::default()
+        };
         let mut id = None;
-        let mut seq_out = None;
-        let mut seq_in = None;
+        let mut ends = None;
         for attr in attributes {
             let name = attr.key.0.as_str();
             match name {
                 "Id" => id = Some(Id(attr.value)),
                 "Joints" => {
-                    let s = self
-                        .parse_joints(attr.value)
-                        .context("Failed parsing joints")?;
-                    seq_in = Some(s.0);
-                    seq_out = Some(s.1);
+                    ends = Some(parse_joints(&attr.value).context("Failed parsing joints")?)
                 }
-                _ => bail!("Unexpected attribute {name} in wire"),
+                _ => self.unsupported(anyhow!("Unexpected attribute {name} in wire"))?,
             }
         }
-        let seq_in = seq_in.context("Failed finding input")?;
-        let seq_out = seq_out.context("Failed finding output")?;
+        let (output, joints, input, branches) = ends.context("Failed finding joints")?;
         let id = id.context("Failed finding id")?;
         let wire = Wire {
-            input: Id(seq_in),
-            output: Id(seq_out),
+            input,
+            output,
+            joints,
+            branches,
+            extra,
         };
         Ok((id, wire))
     }
 
-    fn parse_joints(&mut self, val: String) -> anyhow::Result<(String, String)> {
-        let iter = val
-            .split(' ')
-            // "N(n1:sequenceout)" => ("N", "(n1:sequenceout)")
-            .map(|s| (s.get(0..1).unwrap(), s.get(1..).unwrap()))
-            // I assume the ones holding "N" are the ones which have sequences,
-            // and the others, like h or w, have the other joints
-            .filter(|(c, _)| *c == "N")
-            // "(n1:sequenceout)" => ("n1", "sequenceout")
-            // TODO: Propagate error instead of panicking
-            .map(|(_, s)| {
-                let idx = s.find(':').unwrap();
-                let idx_paren = s.find(')').unwrap();
-                (s.get(1..idx).unwrap(), s.get((idx + 1)..idx_paren).unwrap())
-            });
-        let mut seq_in = None;
-        let mut seq_out = None;
-        for (id, val) in iter {
-            match val {
-                "SequenceOut" => seq_out = Some(id),
-                "SequenceIn" => seq_in = Some(id),
-                _ => bail!("Unexpected value for joint: {val}"),
-            }
-        }
-        let seq_in = seq_in.context("Expected input joint")?.to_owned();
-        let seq_out = seq_out.context("Expected output joint")?.to_owned();
-        Ok((seq_in, seq_out))
-    }
-
     pub fn name(&mut self, name: String) -> anyhow::Result<()> {
         if self.name.is_some() {
-            bail!("Setting builder name twice");
+            bail!("Setting parser name twice");
         }
         self.name = Some(name);
         Ok(())
@@ -737,7 +2829,7 @@ impl FileBuilder {
     fn version(&mut self, version: Version) -> anyhow::Result<()> {
         if self.version.is_some() {
             bail!(
-                "Setting builder version twice. Old {:?}, new {:?}",
+                "Setting parser version twice. Old {:?}, new {:?}",
                 self.version.clone().unwrap(),
                 version
             );
@@ -749,7 +2841,7 @@ impl FileBuilder {
     fn decl(&mut self, decl: BytesDecl<'static>) -> anyhow::Result<()> {
         if self.decl.is_some() {
             bail!(
-                "Setting builder decl twice. Old {:?}, new {:?}",
+                "Setting parser decl twice. Old {:?}, new {:?}",
                 self.decl.clone().unwrap(),
                 decl
             );
@@ -766,8 +2858,198 @@ impl FileBuilder {
             name,
             version,
             decl,
-            blocks: self.blocks,
-            wires: self.wires,
+            diagram: self.diagram,
+            extra: self.preserve.then_some(self.extra),
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{BlockType, DataType, Direction, Error, Id, Literal, Severity};
+    use crate::ev3::project::File;
+    use std::fs;
+
+    #[test]
+    fn switch_cases_have_their_own_scope() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/examples/2blockandif.ev3p");
+        let file = File::new_preserving("2blockandif.ev3p", fs::read(path).unwrap()).unwrap();
+        let block = &file.diagram.blocks[&Id("n0".into())];
+        let BlockType::Switch { cases, .. } = &block.ty else {
+            panic!("Expected a switch, found {:?}", block.ty);
+        };
+
+        let patterns = cases
+            .iter()
+            .map(|case| (case.pattern.as_str(), case.default))
+            .collect::<Vec<_>>();
+        assert_eq!(patterns, [("True", true), ("False", false)]);
+        for case in cases {
+            // Both cases reuse the same ids
+            assert!(case.diagram.blocks.contains_key(&Id("n11".into())));
+            assert!(case.diagram.wires.contains_key(&Id("w4".into())));
+            assert!(case.diagram.wires.contains_key(&Id("w5".into())));
+        }
+    }
+
+    #[test]
+    fn sensor_compare_resolves_to_its_switch() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/examples/2blockandif.ev3p");
+        let file = File::new("2blockandif.ev3p", fs::read(path).unwrap()).unwrap();
+        let compare = &file.diagram.blocks[&Id("n6".into())];
+        let BlockType::SensorCompare { terminals, .. } = &compare.ty else {
+            panic!("Expected a sensor compare block, found {:?}", compare.ty);
+        };
+        let ids = terminals.iter().map(|t| t.id.as_str()).collect::<Vec<_>>();
+        assert_eq!(
+            ids,
+            ["Pressed\\,\\ Released\\ or\\ Bumped", "Result", "Value"]
+        );
+
+        let (id, switch) = file.diagram.paired(&Id("n6".into())).unwrap();
+        assert_eq!(id, &Id("n0".into()));
+        assert!(matches!(switch.ty, BlockType::Switch { .. }));
+    }
+
+    #[test]
+    fn blocks_expose_typed_terminals() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/examples/2blockandif.ev3p");
+        let file = File::new("2blockandif.ev3p", fs::read(path).unwrap()).unwrap();
+        let terminals = file.diagram.blocks[&Id("n6".into())].terminals();
+        let port = terminals.iter().find(|t| t.id == "Port").unwrap();
+        assert_eq!(port.value, Some(Literal::String("1.1".into())));
+        let state = terminals
+            .iter()
+            .find(|t| t.id == "Pressed\\,\\ Released\\ or\\ Bumped")
+            .unwrap();
+        assert_eq!(state.direction, Direction::Input);
+        assert!(matches!(state.value, Some(Literal::Single(_))));
+        let sequence_in = terminals.iter().find(|t| t.id == "SequenceIn").unwrap();
+        assert_eq!(sequence_in.data_type, DataType::Sequence);
+        assert!(sequence_in.wire.is_some());
+    }
+
+    #[test]
+    fn wired_switches_take_their_value_from_a_wire() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/examples/wiredswitch.ev3p");
+        let file = File::new("wiredswitch.ev3p", fs::read(path).unwrap()).unwrap();
+        let switch = &file.diagram.blocks[&Id("n0".into())];
+        let BlockType::Switch {
+            selector: Some(selector),
+            ..
+        } = &switch.ty
+        else {
+            panic!("Expected a wired switch, found {:?}", switch.ty);
+        };
+        assert_eq!(
+            (selector.direction, &selector.data_type),
+            (Direction::Input, &DataType::Single)
+        );
+        let terminal = switch.terminals().into_iter().find(|t| t.id == selector.id);
+        assert_eq!(terminal.unwrap().wire, Some(Id("w5".into())));
+        assert!(file.validate().is_empty());
+        assert_eq!(
+            File::new("wiredswitch.ev3p", file.to_bytes()).unwrap(),
+            file
+        );
+    }
+
+    #[test]
+    fn speeds_can_be_fractions() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/examples/2blocks.ev3p");
+        let contents = fs::read_to_string(path).unwrap();
+        let contents = contents.replacen("ConfiguredValue=\"50\"", "ConfiguredValue=\"37.5\"", 1);
+        let file = File::new("2blocks.ev3p", contents.into_bytes()).unwrap();
+        let block = &file.diagram.blocks[&Id("n2".into())];
+        let BlockType::MotorMove { speed, .. } = block.ty else {
+            panic!("Expected a move steering block, found {:?}", block.ty);
+        };
+        assert_eq!(speed, 37.5);
+        assert_eq!(File::new("2blocks.ev3p", file.to_bytes()).unwrap(), file);
+    }
+
+    #[test]
+    fn errors_say_where_they_happened() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/examples/2blocks.ev3p");
+        let contents = fs::read_to_string(path).unwrap();
+        let contents = contents.replace("MoveDistanceRotations", "PlaySound");
+        let err = File::new("2blocks.ev3p", contents.clone().into_bytes()).unwrap_err();
+        let Error::UnknownTarget {
+            file,
+            block,
+            target,
+            position,
+        } = err
+        else {
+            panic!("Expected an unknown target, found {err:?}");
+        };
+        assert_eq!(file, "2blocks.ev3p");
+        assert_eq!(block, Id("n4".into()));
+        assert_eq!(target, "PlaySound\\.vix");
+        assert_eq!((position.line, position.column), (32, 17));
+        assert!(contents[position.offset..].starts_with("<ConfigurableMethodCall Id=\"n4\""));
+
+        let truncated = contents.replace("</ConfigurableMethodCall>", "</Oops>");
+        let err = File::new("2blocks.ev3p", truncated.into_bytes()).unwrap_err();
+        assert!(matches!(err, Error::Xml { .. }), "{err:?}");
+        assert!(err.to_string().starts_with("2blocks.ev3p:"), "{err}");
+    }
+
+    #[test]
+    fn rejects_duplicate_block_ids() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/examples/2blocks.ev3p");
+        let contents = fs::read_to_string(path).unwrap();
+        let start = contents.find("<StartBlock").unwrap();
+        let end = contents.find("</StartBlock>").unwrap() + "</StartBlock>".len();
+        let twice = format!(
+            "{}{}{}",
+            &contents[..end],
+            &contents[start..end],
+            &contents[end..]
+        );
+        let err = File::new("2blocks.ev3p", twice.into_bytes()).unwrap_err();
+        assert!(
+            format!("{err:#}").contains("Multiple blocks with id"),
+            "{err:#}"
+        );
+    }
+
+    #[test]
+    fn diagnosing_keeps_going() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/examples/2blocks.ev3p");
+        let contents = fs::read_to_string(path).unwrap();
+        let contents = contents
+            .replace("MoveDistanceRotations", "PlaySound")
+            .replacen("ConfiguredValue=\"50\"", "ConfiguredValue=\"fast\"", 1)
+            .replace("<Wire Id=\"w5\"", "<Wire Color=\"Red\" Id=\"w5\"");
+        let (file, diagnostics) = File::diagnose("2blocks.ev3p", contents.clone().into_bytes());
+        let found: Vec<_> = diagnostics
+            .iter()
+            .map(|diagnostic| match &diagnostic.error {
+                Error::UnknownTarget { block, target, .. } => {
+                    (diagnostic.severity, Some(block.clone()), target.clone())
+                }
+                Error::Invalid { block, .. } => (diagnostic.severity, block.clone(), String::new()),
+                err => panic!("Unexpected {err:?}"),
+            })
+            .collect();
+        assert_eq!(
+            found,
+            [
+                (Severity::Error, Some(Id::new("n2")), String::new()),
+                (
+                    Severity::Warning,
+                    Some(Id::new("n4")),
+                    "PlaySound\\.vix".into()
+                ),
+                (Severity::Warning, None, String::new()),
+            ]
+        );
+        // Everything that went wrong is kept as it was, only the line endings change
+        let written = String::from_utf8(file.unwrap().to_bytes()).unwrap();
+        assert_eq!(
+            written.replace("\r\n", "\n").trim_end(),
+            contents.trim_end()
+        );
+    }
+}
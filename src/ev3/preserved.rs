@@ -0,0 +1,72 @@
+//! Parts of a file the parser doesn't model. They're only kept when parsing in preserving mode,
+//! so that writing the file back out reproduces them as they were.
+
+use std::collections::HashMap;
+
+/// Name of the nodes holding a comment, which can't be the name of an element
+const COMMENT: &str = "!--";
+
+/// An XML element that isn't modeled, along with everything inside it, or a comment
+#[derive(Clone, Debug, PartialEq)]
+pub struct Node {
+    /// Name of the element, including its prefix, like `fpruntime:FrontPanelCanvas`. Comments
+    /// are named `!--`, with what they say as their text
+    pub name: String,
+    pub attributes: Vec<(String, String)>,
+    pub children: Vec<Node>,
+    /// Text inside the element, which is empty when it has an end tag but nothing in it
+    pub text: Option<String>,
+}
+
+impl Node {
+    /// A comment like `<!-- text -->`, where `text` is everything between the dashes
+    pub fn comment(text: impl Into<String>) -> Self {
+        Self {
+            name: COMMENT.into(),
+            attributes: vec![],
+            children: vec![],
+            text: Some(text.into()),
+        }
+    }
+
+    pub fn is_comment(&self) -> bool {
+        self.name == COMMENT
+    }
+}
+
+/// Whatever wasn't modeled from an element that was
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Extra {
+    /// Every attribute of the element in document order, including the modeled ones so that the
+    /// order can be reproduced
+    pub attributes: Vec<(String, String)>,
+    /// Attributes of each `Terminal` inside the element, by terminal id
+    pub terminals: HashMap<String, Vec<(String, String)>>,
+    /// Unknown child elements, with their index among all the children of the element
+    pub children: Vec<(usize, Node)>,
+}
+
+impl Extra {
+    /// Preserved attributes of the terminal with the given id
+    pub fn terminal(&self, id: &str) -> &[(String, String)] {
+        self.terminals
+            .get(id)
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+}
+
+/// The document level elements around the block diagram
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FileExtra {
+    pub source_file: Vec<(String, String)>,
+    pub namespace: Vec<(String, String)>,
+    pub virtual_instrument: Vec<(String, String)>,
+    pub block_diagram: Vec<(String, String)>,
+    /// Elements next to the block diagram, like `FrontPanel` before it and `Icon` after it
+    pub before_diagram: Vec<Node>,
+    pub after_diagram: Vec<Node>,
+    /// Comments outside of `VirtualInstrument`, with how many of the start and end tags of
+    /// `SourceFile`, `Namespace` and `VirtualInstrument` come before them
+    pub comments: Vec<(usize, Node)>,
+}
@@ -1,38 +1,106 @@
-use super::parser::{Block, FileBuilder, Id, Wire};
+use super::error::{Diagnostic, Error, Result, Severity};
+use super::manifest::{Manifest, MANIFEST};
+use super::parser::{Diagram, FileParser};
+use super::preserved::FileExtra;
+use super::writer::FileWriter;
 use crate::utils::VecReadWrapper;
-use anyhow::{bail, Context};
 use quick_xml::{events::BytesDecl, reader::Reader};
-use std::collections::HashMap;
+use std::borrow::Cow;
 use std::fs;
-use std::io::Read;
+use std::io::{Read, Seek, Write};
+use std::path::Path;
+use zip::{write::FileOptions, CompressionMethod};
 
-#[derive(Clone, Default, Debug)]
+/// Version of the EV3 software that saved a file, from its `SourceFile` element
+#[derive(Clone, Default, Debug, PartialEq)]
 pub struct Version {
     pub number: String,
     pub namespace: String,
 }
 
-#[derive(Debug)]
+/// A program in a project, which is one of its `.ev3p` files
+#[derive(Debug, PartialEq)]
 pub struct File {
     pub decl: BytesDecl<'static>,
     pub version: Version,
     pub name: String,
-    pub blocks: HashMap<Id, Block>,
-    pub wires: HashMap<Id, Wire>,
+    /// The root block diagram
+    pub diagram: Diagram,
+    /// Everything around the block diagram, only kept when preserving
+    pub extra: Option<FileExtra>,
 }
 
 impl File {
-    fn new(name: &str, contents: Vec<u8>) -> anyhow::Result<Self> {
+    /// Parses the contents of a `.ev3p` file, failing on anything that isn't understood
+    pub fn new(name: &str, contents: Vec<u8>) -> Result<Self> {
+        Self::parse(name, contents, false)
+    }
+
+    /// Parses the file keeping everything that isn't understood, so that writing it back out
+    /// reproduces it exactly. Whitespace between elements isn't kept, the file is written with
+    /// the windows line endings and 4 space indents the EV3 software uses, so that's only byte
+    /// for byte for files it saved
+    pub fn new_preserving(name: &str, contents: Vec<u8>) -> Result<Self> {
+        Self::parse(name, contents, true)
+    }
+
+    /// Parses the file like [`File::new_preserving`], recording every problem along the way
+    /// instead of stopping at the first one. The file is missing when one of them was something
+    /// parsing couldn't get past, which is then the last diagnostic
+    pub fn diagnose(name: &str, contents: Vec<u8>) -> (Option<Self>, Vec<Diagnostic>) {
+        let mut parser = match Self::parser(name, contents) {
+            Ok(parser) => parser,
+            Err(error) => return (None, vec![fatal(error)]),
+        };
+        parser.diagnose();
+        match Self::build(name, parser) {
+            (Ok(file), diagnostics) => (Some(file), diagnostics),
+            (Err(error), mut diagnostics) => {
+                diagnostics.push(fatal(error));
+                (None, diagnostics)
+            }
+        }
+    }
+
+    fn parse(name: &str, contents: Vec<u8>, preserve: bool) -> Result<Self> {
+        let mut parser = Self::parser(name, contents)?;
+        if preserve {
+            parser.preserve();
+        }
+        Self::build(name, parser).0
+    }
+
+    fn parser(name: &str, contents: Vec<u8>) -> Result<FileParser> {
         let wrapper = VecReadWrapper::new(contents);
         let mut xml = Reader::from_reader(wrapper);
         xml.trim_text(true);
-        let mut builder = FileBuilder::from_xml(xml)?;
-        builder.name(name.into())?;
-        builder.parse().context("Failed parsing file contents")?;
-        builder.build().context("Failed building file struct")
+        FileParser::from_xml(xml).map_err(|err| Error::parse(name, None, Default::default(), err))
+    }
+
+    /// Parses the file with the parser, along with the problems it diagnosed
+    fn build(name: &str, mut parser: FileParser) -> (Result<Self>, Vec<Diagnostic>) {
+        let parsed = parser
+            .name(name.into())
+            .and_then(|()| parser.parse())
+            .map_err(|err| parser.error(err));
+        let diagnostics = parser.take_diagnostics();
+        let file = parsed.and_then(|()| {
+            let end = parser.position();
+            parser
+                .build()
+                .map_err(|err| Error::parse(name, None, end, err))
+        });
+        (file, diagnostics)
+    }
+
+    /// Writes the file back out as the contents of a `.ev3p` file
+    pub fn to_bytes(&self) -> Vec<u8> {
+        FileWriter::write(self)
     }
 }
 
+/// A `.ev3` project, which is a zip archive holding the programs along with the project's
+/// details
 pub struct Project {
     title: String,
     description: String,
@@ -43,99 +111,266 @@ pub struct Project {
     activity: String,
     /// ????
     activity_assets: Vec<u8>,
-    /// I assume there's no need to parse this, we don't change it
-    project: String,
+    manifest: Manifest,
     files: Vec<File>,
+    /// The other files in the archive, like sounds and images, kept as they are
+    assets: Vec<(String, Vec<u8>)>,
 }
 
 impl Project {
-    pub fn output_file(&self, fname: &str) -> anyhow::Result<()> {
-        let _ = fname;
-        let _ = &self.title;
-        let _ = &self.description;
-        let _ = &self.year;
-        let _ = &self.thumbnail;
-        let _ = &self.activity;
-        let _ = &self.activity_assets;
-        let _ = &self.project;
+    /// Reads a `.ev3` project, failing on anything in its programs that isn't understood
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Self::open_with(path.as_ref(), false)
+    }
+
+    /// Reads the project keeping everything in its programs that isn't understood, so that
+    /// blocks that aren't supported yet survive being written back out. Like with
+    /// [`File::new_preserving`], the programs are reformatted the way the EV3 software saves them
+    pub fn open_preserving(path: impl AsRef<Path>) -> Result<Self> {
+        Self::open_with(path.as_ref(), true)
+    }
+
+    fn open_with(path: &Path, preserve: bool) -> Result<Self> {
+        let file = fs::File::open(path).map_err(Error::io(path))?;
+        Self::read(file, preserve)
+    }
+
+    /// Saves the project as a `.ev3` file
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let file = fs::File::create(path).map_err(Error::io(path))?;
+        self.write(file)
+    }
+
+    pub fn title(&self) -> &str {
+        &self.title
+    }
+
+    pub fn set_title(&mut self, title: impl Into<String>) {
+        self.title = title.into();
+    }
+
+    pub fn description(&self) -> &str {
+        &self.description
+    }
+
+    pub fn set_description(&mut self, description: impl Into<String>) {
+        self.description = description.into();
+    }
+
+    pub fn year(&self) -> usize {
+        self.year
+    }
+
+    pub fn set_year(&mut self, year: usize) {
+        self.year = year;
+    }
+
+    /// The project's thumbnail, as a PNG
+    pub fn thumbnail(&self) -> &[u8] {
+        &self.thumbnail
+    }
+
+    pub fn set_thumbnail(&mut self, thumbnail: Vec<u8>) {
+        self.thumbnail = thumbnail;
+    }
+
+    /// The programs, in the order they're in the archive
+    pub fn files(&self) -> &[File] {
+        &self.files
+    }
+
+    /// The programs, for changing them directly. The manifest is brought up to date with them
+    /// when the project is written
+    pub fn files_mut(&mut self) -> &mut Vec<File> {
+        &mut self.files
+    }
+
+    /// Adds a program, replacing the one with the same name if there is one
+    pub fn add_file(&mut self, file: File) {
+        match self.file_mut(&file.name) {
+            Some(existing) => *existing = file,
+            None => self.files.push(file),
+        }
+        self.sync_manifest();
+    }
+
+    /// Removes the program with the given name
+    pub fn remove_file(&mut self, name: &str) -> Option<File> {
+        let index = self.files.iter().position(|file| file.name == name)?;
+        let file = self.files.remove(index);
+        self.sync_manifest();
+        Some(file)
+    }
+
+    /// Renames a program, replacing the one that already had the new name if there is one.
+    /// Returns whether there was a program to rename
+    pub fn rename_file(&mut self, name: &str, new_name: impl Into<String>) -> bool {
+        let new_name = new_name.into();
+        if self.file(name).is_none() {
+            return false;
+        }
+        if name != new_name {
+            self.files.retain(|file| file.name != new_name);
+        }
+        self.file_mut(name).unwrap().name = new_name.clone();
+        self.manifest.rename_program(name, new_name);
+        self.sync_manifest();
+        true
+    }
+
+    /// The project's `Project.lvprojx`, listing its programs, variables and settings
+    pub fn manifest(&self) -> &Manifest {
+        &self.manifest
+    }
+
+    /// The manifest, for changing its variables and settings. The programs it lists are kept
+    /// in sync with the project's
+    pub fn manifest_mut(&mut self) -> &mut Manifest {
+        &mut self.manifest
+    }
+
+    fn sync_manifest(&mut self) {
+        self.manifest
+            .sync(self.files.iter().map(|file| file.name.as_str()));
+    }
+
+    /// The program with the given name, like `Program.ev3p`
+    pub fn file(&self, name: &str) -> Option<&File> {
+        self.files.iter().find(|file| file.name == name)
+    }
+
+    pub fn file_mut(&mut self, name: &str) -> Option<&mut File> {
+        self.files.iter_mut().find(|file| file.name == name)
+    }
+
+    /// The files in the archive that are neither programs nor the project's details, by name
+    pub fn assets(&self) -> &[(String, Vec<u8>)] {
+        &self.assets
+    }
+
+    /// Writes the project as a zip archive
+    pub fn write(&self, writer: impl Write + Seek) -> Result<()> {
+        let mut zip = zip::ZipWriter::new(writer);
+        let options = FileOptions::default().compression_method(CompressionMethod::Deflated);
+        for (name, contents) in self.entries() {
+            zip.start_file(name, options)?;
+            zip.write_all(&contents).map_err(Error::io(name))?;
+        }
+        zip.finish()?;
+        Ok(())
+    }
+
+    /// Writes the files of the project to a directory, as if the archive was extracted there
+    pub fn write_dir(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        fs::create_dir_all(path).map_err(Error::io(path))?;
+        for (name, contents) in self.entries() {
+            let file = path.join(name);
+            fs::write(&file, contents).map_err(Error::io(&file))?;
+        }
+        Ok(())
+    }
+
+    /// The files in the archive with their contents
+    fn entries(&self) -> Vec<(&str, Cow<[u8]>)> {
+        // Same order the EV3 software uses when saving a project
+        let mut entries: Vec<(&str, Cow<[u8]>)> = vec![
+            ("Activity.x3a", self.activity.as_bytes().into()),
+            ("ActivityAssets.laz", self.activity_assets.as_slice().into()),
+            (
+                "___CopyrightYear",
+                self.year.to_string().into_bytes().into(),
+            ),
+            ("___ProjectDescription", self.description.as_bytes().into()),
+            ("___ProjectThumbnail", self.thumbnail.as_slice().into()),
+            ("___ProjectTitle", self.title.as_bytes().into()),
+        ];
         for f in &self.files {
-            let _ = &f.decl;
-            let _ = &f.version.number;
-            let _ = &f.version.namespace;
-            let _ = &f.name;
-            let _ = &f.blocks;
+            entries.push((&f.name, f.to_bytes().into()));
         }
-        bail!("Outputting the project not yet implemented")
+        for (name, contents) in &self.assets {
+            entries.push((name, contents.as_slice().into()));
+        }
+        let mut manifest = self.manifest.clone();
+        manifest.sync(self.files.iter().map(|file| file.name.as_str()));
+        entries.push((MANIFEST, manifest.to_bytes().into()));
+        entries
+    }
+
+    /// Reads a project from a zip archive, keeping what isn't understood if `preserve` is set
+    pub fn read(reader: impl Read + Seek, preserve: bool) -> Result<Self> {
+        Self::from_entries(read_entries(reader)?, preserve)
+    }
+
+    /// Reads a project from a directory holding the files of its archive, like one
+    /// [`Project::write_dir`] wrote
+    pub fn read_dir(path: impl AsRef<Path>, preserve: bool) -> Result<Self> {
+        Self::from_entries(read_dir_entries(path.as_ref())?, preserve)
+    }
+
+    /// Diagnoses every program in a `.ev3` project with [`File::diagnose`], to find everything
+    /// in them that isn't supported at once. Only problems with the archive itself fail
+    pub fn diagnose(path: impl AsRef<Path>) -> Result<Vec<Diagnostic>> {
+        let path = path.as_ref();
+        let file = fs::File::open(path).map_err(Error::io(path))?;
+        Ok(diagnose_entries(read_entries(file)?))
     }
-    pub fn get_project_from_zip(filename: &str) -> anyhow::Result<Self> {
-        let file = fs::File::open(filename)?;
-        let mut zip = zip::ZipArchive::new(file).context("Failed to read zip file")?;
 
+    /// Like [`Project::diagnose`], for a directory holding the files of a project
+    pub fn diagnose_dir(path: impl AsRef<Path>) -> Result<Vec<Diagnostic>> {
+        Ok(diagnose_entries(read_dir_entries(path.as_ref())?))
+    }
+
+    fn from_entries(entries: Vec<(String, Vec<u8>)>, preserve: bool) -> Result<Self> {
         let mut title = None;
         let mut description = None;
         let mut year = None;
         let mut thumbnail = None;
         let mut activity_assets = None;
         let mut activity = None;
-        let mut project = None;
+        let mut manifest = None;
         let mut files = vec![];
+        let mut assets = vec![];
 
-        for i in 0..zip.len() {
-            let mut z = zip.by_index(i).context("Zip library doesn't work lol")?;
-
-            let name = z
-                .enclosed_name()
-                .context("Name was invalid")?
-                .to_str()
-                .unwrap()
-                .to_owned();
-
-            let mut bytes = vec![];
-            z.read_to_end(&mut bytes)?;
-
+        for (name, bytes) in entries {
             match name.as_str() {
                 "___CopyrightYear" => {
                     year = Some(
-                        bytes
-                            .iter()
-                            .fold(0, |acc, &digit| acc * 10 + (digit - 48) as usize),
+                        text(&name, bytes)?
+                            .parse()
+                            .map_err(|err| Error::InvalidEntry {
+                                entry: name,
+                                message: format!("{err}"),
+                            })?,
                     )
                 }
-                "___ProjectDescription" => {
-                    description =
-                        Some(String::from_utf8(bytes).context("Invalid description data")?)
-                }
-                "___ProjectTitle" => {
-                    title = Some(String::from_utf8(bytes).context("Invalid project title")?)
-                }
+                "___ProjectDescription" => description = Some(text(&name, bytes)?),
+                "___ProjectTitle" => title = Some(text(&name, bytes)?),
                 "___ProjectThumbnail" => thumbnail = Some(bytes),
                 "ActivityAssets.laz" => activity_assets = Some(bytes),
-                "Activity.x3a" => {
-                    activity = Some(String::from_utf8(bytes).context("Invalid activity(?) data")?)
-                }
-                "Project.lvprojx" => {
-                    project = Some(String::from_utf8(bytes).context("Invalid project file")?)
-                }
-
-                _ => {
+                "Activity.x3a" => activity = Some(text(&name, bytes)?),
+                MANIFEST => manifest = Some(Manifest::new(bytes)?),
+                _ if is_program(&name) => {
                     let name = name.as_str();
-                    let file = File::new(name, bytes).context(format!("Failed parsing {name}"))?;
-                    println!("Parsed file:");
-                    println!("{file:#?}");
+                    let file = if preserve {
+                        File::new_preserving(name, bytes)
+                    } else {
+                        File::new(name, bytes)
+                    }?;
                     files.push(file);
                 }
+                _ => assets.push((name, bytes)),
             }
         }
-        let title = title.context("Found no title")?;
-        let description = description.context("Found no description")?;
-        let year = year.context("Found no year")?;
-        let thumbnail = thumbnail.context("Found no thumbnail")?;
-        let activity = activity.context("Found no activity")?;
-        let activity_assets = activity_assets.context("Found no activity_assets")?;
-        let project = project.context("Found no project")?;
-        println!("Found title `{}`", title);
-        println!("Found description `{}`", description);
-        println!("Found year {}", year);
+        let missing = |entry: &str| Error::MissingEntry(entry.into());
+        let title = title.ok_or_else(|| missing("___ProjectTitle"))?;
+        let description = description.ok_or_else(|| missing("___ProjectDescription"))?;
+        let year = year.ok_or_else(|| missing("___CopyrightYear"))?;
+        let thumbnail = thumbnail.ok_or_else(|| missing("___ProjectThumbnail"))?;
+        let activity = activity.ok_or_else(|| missing("Activity.x3a"))?;
+        let activity_assets = activity_assets.ok_or_else(|| missing("ActivityAssets.laz"))?;
+        let manifest = manifest.ok_or_else(|| missing(MANIFEST))?;
 
         Ok(Self {
             title,
@@ -145,7 +380,117 @@ impl Project {
             activity,
             activity_assets,
             files,
-            project,
+            manifest,
+            assets,
         })
     }
 }
+
+/// Whether the file in the archive is a program, the others being the project's details and
+/// its assets
+fn is_program(name: &str) -> bool {
+    name.ends_with(".ev3p")
+}
+
+/// The files in a zip archive with their contents
+fn read_entries(reader: impl Read + Seek) -> Result<Vec<(String, Vec<u8>)>> {
+    let mut zip = zip::ZipArchive::new(reader)?;
+    let mut entries = vec![];
+    for i in 0..zip.len() {
+        let mut z = zip.by_index(i)?;
+
+        let name = z
+            .enclosed_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| Error::InvalidEntry {
+                entry: z.name().into(),
+                message: "Invalid file name".into(),
+            })?
+            .to_owned();
+
+        let mut bytes = vec![];
+        z.read_to_end(&mut bytes).map_err(Error::io(&name))?;
+        entries.push((name, bytes));
+    }
+    Ok(entries)
+}
+
+/// The files in a directory with their contents, sorted by name
+fn read_dir_entries(path: &Path) -> Result<Vec<(String, Vec<u8>)>> {
+    let mut entries = vec![];
+    for entry in fs::read_dir(path).map_err(Error::io(path))? {
+        let entry = entry.map_err(Error::io(path))?;
+        if !entry
+            .file_type()
+            .map_err(Error::io(entry.path()))?
+            .is_file()
+        {
+            continue;
+        }
+        let name = entry
+            .file_name()
+            .into_string()
+            .map_err(|name| Error::InvalidEntry {
+                entry: name.to_string_lossy().into(),
+                message: "Invalid file name".into(),
+            })?;
+        let bytes = fs::read(entry.path()).map_err(Error::io(entry.path()))?;
+        entries.push((name, bytes));
+    }
+    // The directory's order is arbitrary, so at least make it the same every time
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(entries)
+}
+
+/// Everything diagnosed in the programs among the entries, in order
+fn diagnose_entries(entries: Vec<(String, Vec<u8>)>) -> Vec<Diagnostic> {
+    entries
+        .into_iter()
+        .filter(|(name, _)| is_program(name))
+        .flat_map(|(name, bytes)| File::diagnose(&name, bytes).1)
+        .collect()
+}
+
+fn fatal(error: Error) -> Diagnostic {
+    Diagnostic {
+        severity: Severity::Error,
+        error,
+    }
+}
+
+/// The contents of a file in the archive that holds text
+fn text(entry: &str, bytes: Vec<u8>) -> Result<String> {
+    String::from_utf8(bytes).map_err(|err| Error::InvalidEntry {
+        entry: entry.into(),
+        message: format!("{err}"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_entries, Project};
+    use std::fs;
+    use std::io::{Cursor, Write};
+    use zip::write::FileOptions;
+
+    #[test]
+    fn keeps_files_that_arent_programs() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/examples/2blocks.ev3");
+        let mut entries = read_entries(fs::File::open(path).unwrap()).unwrap();
+        entries.push(("Hello.rsf".into(), vec![1, 2, 3]));
+        let mut zip = zip::ZipWriter::new(Cursor::new(vec![]));
+        for (name, contents) in &entries {
+            zip.start_file(name, FileOptions::default()).unwrap();
+            zip.write_all(contents).unwrap();
+        }
+        let archive = zip.finish().unwrap();
+
+        let project = Project::read(archive, false).unwrap();
+        assert_eq!(project.files().len(), 1);
+        assert_eq!(project.assets(), [("Hello.rsf".into(), vec![1, 2, 3])]);
+        let mut saved = Cursor::new(vec![]);
+        project.write(&mut saved).unwrap();
+        let saved = read_entries(saved).unwrap();
+        assert!(saved.contains(&("Hello.rsf".into(), vec![1, 2, 3])));
+    }
+}
@@ -0,0 +1,517 @@
+//! The inputs and outputs of blocks, which is where wires connect. Every block lists its terminals
+//! the same way, so tools can look at any block without knowing what kind it is.
+
+use super::parser::{
+    Block, BlockType, Bounds, DataType, Id, LoopCondition, MotorDuration, Port, SequenceBlock,
+    SequenceBlockType, VariableAccess, Wait, WaitSensor, BUTTON_STATE_TERMINAL,
+};
+use anyhow::{bail, ensure, Context};
+
+/// Terminal every method call has for the Loop Interrupt blocks it listens to
+pub(crate) const INTERRUPTS_TERMINAL: &str =
+    "InterruptsToListenFor_16B03592_CD76_4D58_8DC3_E3C3091E327A";
+/// Terminals of the variable and constant blocks, the name being set in the block's header
+pub(crate) const VARIABLE_NAME_TERMINAL: &str = "name";
+pub(crate) const VALUE_IN_TERMINAL: &str = "valueIn";
+pub(crate) const VALUE_OUT_TERMINAL: &str = "valueOut";
+
+/// How wide the EV3 software draws a block without any visible terminals, like a start block
+const BLOCK_WIDTH: f64 = 70.0;
+/// Where the visible terminals of a block are drawn, side by side along its bottom
+const TERMINAL_LEFT: f64 = 54.0;
+const TERMINAL_SPACING: f64 = 31.0;
+const TERMINAL_SIZE: (f64, f64) = (30.0, 27.0);
+const TERMINAL_TOP: f64 = 56.0;
+
+/// Where the block's visible terminal with the index is drawn, relative to the block
+pub(crate) fn terminal_bounds(index: usize) -> Bounds {
+    Bounds {
+        x: TERMINAL_LEFT + TERMINAL_SPACING * index as f64,
+        y: TERMINAL_TOP,
+        width: TERMINAL_SIZE.0,
+        height: TERMINAL_SIZE.1,
+    }
+}
+
+/// How wide a block that isn't a structure is drawn, which fits its visible terminals
+pub(crate) fn block_width(visible: usize) -> f64 {
+    BLOCK_WIDTH + TERMINAL_SPACING * visible as f64
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Input,
+    Output,
+}
+
+impl Direction {
+    pub(crate) fn parse(value: &str) -> anyhow::Result<Self> {
+        Ok(match value {
+            "Input" => Direction::Input,
+            "Output" => Direction::Output,
+            _ => bail!("Unknown terminal direction `{value}`"),
+        })
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Direction::Input => "Input",
+            Direction::Output => "Output",
+        }
+    }
+}
+
+/// The value configured on an input, typed after the terminal's [`DataType`]
+#[derive(Clone, Debug, PartialEq)]
+pub enum Literal {
+    Single(f64),
+    Boolean(bool),
+    Int32(i32),
+    /// Also used for ports like `1.B+C`, even though their terminals say they're `Single`
+    String(String),
+    /// Like `[1,3]`
+    Array(Vec<Literal>),
+}
+
+impl Literal {
+    pub(crate) fn parse(data_type: &DataType, value: &str) -> anyhow::Result<Self> {
+        Ok(match data_type {
+            DataType::Single => Literal::Single(
+                value
+                    .parse()
+                    .context(format!("Failed parsing `{value}` as number"))?,
+            ),
+            DataType::Boolean => Literal::Boolean(match value {
+                "True" => true,
+                "False" => false,
+                _ => bail!("Expected `True` or `False`, found `{value}`"),
+            }),
+            DataType::Int32 => Literal::Int32(
+                value
+                    .parse()
+                    .context(format!("Failed parsing `{value}` as integer"))?,
+            ),
+            DataType::String => Literal::String(value.into()),
+            DataType::Array(item) => {
+                let inner = value
+                    .strip_prefix('[')
+                    .and_then(|s| s.strip_suffix(']'))
+                    .context(format!("Array `{value}` is not wrapped in brackets"))?;
+                Literal::Array(
+                    inner
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|item| !item.is_empty())
+                        .map(|value| Self::parse(item, value))
+                        .collect::<anyhow::Result<_>>()?,
+                )
+            }
+            DataType::Sequence => bail!("Sequence terminals don't take a value"),
+        })
+    }
+
+    /// The value as a number, for `Single` and `Int32` values
+    pub fn number(&self) -> anyhow::Result<f64> {
+        match self {
+            Literal::Single(value) => Ok(*value),
+            Literal::Int32(value) => Ok(*value as f64),
+            _ => bail!("Expected a number, found `{self}`"),
+        }
+    }
+
+    /// The value as a whole number, for numbers without a fractional part
+    pub(crate) fn integer(&self) -> anyhow::Result<isize> {
+        let value = self.number()?;
+        ensure!(
+            value.fract() == 0.0,
+            "Expected a whole number, found `{self}`"
+        );
+        Ok(value as isize)
+    }
+
+    pub fn boolean(&self) -> anyhow::Result<bool> {
+        match self {
+            Literal::Boolean(value) => Ok(*value),
+            _ => bail!("Expected `True` or `False`, found `{self}`"),
+        }
+    }
+
+    pub fn text(&self) -> anyhow::Result<&str> {
+        match self {
+            Literal::String(value) => Ok(value),
+            _ => bail!("Expected text, found `{self}`"),
+        }
+    }
+
+    pub fn array(&self) -> anyhow::Result<&[Literal]> {
+        match self {
+            Literal::Array(items) => Ok(items),
+            _ => bail!("Expected an array, found `{self}`"),
+        }
+    }
+}
+
+impl std::fmt::Display for Literal {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            Literal::Single(value) => write!(fmt, "{value}"),
+            Literal::Boolean(true) => write!(fmt, "True"),
+            Literal::Boolean(false) => write!(fmt, "False"),
+            Literal::Int32(value) => write!(fmt, "{value}"),
+            Literal::String(value) => write!(fmt, "{value}"),
+            Literal::Array(items) => {
+                write!(fmt, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(fmt, ",")?;
+                    }
+                    write!(fmt, "{item}")?;
+                }
+                write!(fmt, "]")
+            }
+        }
+    }
+}
+
+/// An input or output of a block, like `Speed` or `SequenceOut`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Terminal {
+    pub id: String,
+    pub direction: Direction,
+    pub data_type: DataType,
+    /// The configured value, which outputs and sequence terminals don't have
+    pub value: Option<Literal>,
+    /// The wire connected to the terminal, if any
+    pub wire: Option<Id>,
+    /// Whether the EV3 software draws the terminal on the block, which gives it real bounds
+    pub(crate) visible: bool,
+}
+
+impl Terminal {
+    pub(crate) fn input(id: &str, data_type: DataType, value: Literal) -> Self {
+        Self {
+            id: id.into(),
+            direction: Direction::Input,
+            data_type,
+            value: Some(value),
+            wire: None,
+            visible: true,
+        }
+    }
+
+    /// An output, which the modeled blocks never draw
+    pub(crate) fn output(id: &str, data_type: DataType) -> Self {
+        Self {
+            id: id.into(),
+            direction: Direction::Output,
+            data_type,
+            value: None,
+            wire: None,
+            visible: false,
+        }
+    }
+
+    /// The terminal picking the ports of a block, like `1.A` or `1.B+C`. It's set in the block's
+    /// header instead of being drawn
+    /// The ports of a block, `layer` being the brick in a daisy chain they're on
+    pub(crate) fn ports(id: &str, layer: u8, ports: String) -> Self {
+        Self::input(
+            id,
+            DataType::Single,
+            Literal::String(format!("{layer}.{ports}")),
+        )
+        .hidden()
+    }
+
+    pub(crate) fn hidden(self) -> Self {
+        Self {
+            visible: false,
+            ..self
+        }
+    }
+
+    /// Draws an output on the block, like the value a variable block reads
+    fn drawn(self) -> Self {
+        Self {
+            visible: true,
+            ..self
+        }
+    }
+
+    fn sequence(sequence: &SequenceBlock) -> Self {
+        let (id, direction) = match sequence.ty {
+            SequenceBlockType::In => ("SequenceIn", Direction::Input),
+            SequenceBlockType::Out => ("SequenceOut", Direction::Output),
+        };
+        Self {
+            id: id.into(),
+            direction,
+            data_type: DataType::Sequence,
+            value: None,
+            wire: sequence.wire_id.clone(),
+            visible: true,
+        }
+    }
+}
+
+/// The terminals saying how long a motor block runs for
+fn duration_terminals(duration: &MotorDuration) -> Vec<Terminal> {
+    let (amount, brake) = match duration {
+        MotorDuration::Unlimited => return vec![],
+        MotorDuration::Rotations { rotations, brake } => (rotations, brake),
+        MotorDuration::Degrees { degrees, brake } => (degrees, brake),
+        MotorDuration::Seconds { seconds, brake } => (seconds, brake),
+    };
+    let terminal = duration.terminal().unwrap();
+    vec![
+        Terminal::input(terminal, DataType::Single, Literal::Single(*amount)),
+        Terminal::input(
+            "Brake\\ At\\ End",
+            DataType::Boolean,
+            Literal::Boolean(*brake),
+        ),
+    ]
+}
+
+fn code_array(codes: impl Iterator<Item = u8>) -> Literal {
+    Literal::Array(codes.map(|code| Literal::Single(code.into())).collect())
+}
+
+fn code(id: &str, code: u8) -> Terminal {
+    Terminal::input(id, DataType::Single, Literal::Single(code.into()))
+}
+
+/// The terminals of a wait block, without the interrupts
+fn wait_terminals(wait: &Wait, layer: u8) -> Vec<Terminal> {
+    let port = |port: &Port| Terminal::ports("Port", layer, port.to_string());
+    let sensor_terminal = |sensor: &WaitSensor| match sensor {
+        WaitSensor::Timer(timer) => code("Timer\\ ID", *timer),
+        _ => port(&sensor.port().unwrap()),
+    };
+    let colors = DataType::Array(Box::new(DataType::Single));
+    match wait {
+        Wait::Time { seconds } => vec![Terminal::input(
+            "Seconds",
+            DataType::Single,
+            Literal::Single(*seconds),
+        )],
+        Wait::Compare {
+            sensor,
+            comparison,
+            threshold,
+        } => vec![
+            sensor_terminal(sensor),
+            code("Comparison\\ Type", comparison.code()),
+            Terminal::input(
+                "Threshold\\ Value",
+                DataType::Single,
+                Literal::Single(*threshold),
+            ),
+        ],
+        Wait::Change {
+            sensor,
+            direction,
+            amount,
+        } => vec![
+            sensor_terminal(sensor),
+            code("Direction", direction.code()),
+            Terminal::input("Amount", DataType::Single, Literal::Single(*amount)),
+        ],
+        Wait::TouchCompare { port: p, state } => {
+            vec![port(p), code(BUTTON_STATE_TERMINAL, state.code())]
+        }
+        Wait::TouchChange { port: p } | Wait::ColorChange { port: p } => vec![port(p)],
+        Wait::ColorCompare { port: p, colors: c } => vec![
+            port(p),
+            Terminal::input(
+                "Set\\ of\\ colors",
+                colors,
+                code_array(c.iter().map(|c| c.code())),
+            ),
+        ],
+        Wait::ButtonsCompare { buttons, state } => vec![
+            Terminal::input(
+                "Set\\ of\\ brick\\ button\\ IDs",
+                colors,
+                code_array(buttons.iter().map(|b| b.code())),
+            ),
+            code(BUTTON_STATE_TERMINAL, state.code()),
+        ],
+        Wait::ButtonsChange => vec![],
+    }
+}
+
+/// The terminals of the method call checking whether a loop should stop
+fn loop_condition_terminals(condition: &LoopCondition, layer: u8) -> Vec<Terminal> {
+    let result = Terminal::output("Result", DataType::Boolean);
+    match condition {
+        LoopCondition::Unlimited => vec![result],
+        LoopCondition::Count(count) => vec![
+            Terminal::input("Count", DataType::Int32, Literal::Int32(*count)),
+            result,
+        ],
+        LoopCondition::Time(seconds) => vec![
+            Terminal::input("Seconds", DataType::Single, Literal::Single(*seconds)),
+            result,
+        ],
+        LoopCondition::Logic { until } => vec![
+            Terminal::input("Until", DataType::Boolean, Literal::Boolean(*until)),
+            result,
+        ],
+        LoopCondition::Sensor {
+            port, terminals, ..
+        } => {
+            let mut all = vec![Terminal::ports("Port", layer, port.to_string())];
+            all.extend(terminals.iter().cloned());
+            all
+        }
+    }
+}
+
+impl Block {
+    /// The `ConfigurableMethodTerminal`s of the block in the order they're written, with their
+    /// wires. A loop's are the ones of its condition, and switches don't have any
+    pub(crate) fn method_terminals(&self) -> Vec<Terminal> {
+        let interrupts =
+            || Terminal::input(INTERRUPTS_TERMINAL, DataType::Int32, Literal::Int32(0)).hidden();
+        let layer = self.layer;
+        let mut terminals = match &self.ty {
+            BlockType::Start => vec![Terminal::output("Result", DataType::Boolean)],
+            BlockType::MotorMove {
+                ports,
+                steering,
+                speed,
+                duration,
+            } => {
+                let mut terminals = vec![
+                    Terminal::ports("Ports", layer, format!("{}+{}", ports.0, ports.1)),
+                    Terminal::input("Steering", DataType::Single, Literal::Single(*steering)),
+                    Terminal::input("Speed", DataType::Single, Literal::Single(*speed)),
+                ];
+                terminals.extend(duration_terminals(duration));
+                terminals.push(interrupts());
+                terminals
+            }
+            BlockType::MotorTank {
+                ports,
+                left_speed,
+                right_speed,
+                duration,
+            } => {
+                let mut terminals = vec![
+                    Terminal::ports("Ports", layer, format!("{}+{}", ports.0, ports.1)),
+                    Terminal::input(
+                        "Speed\\ Left",
+                        DataType::Single,
+                        Literal::Single(*left_speed),
+                    ),
+                    Terminal::input(
+                        "Speed\\ Right",
+                        DataType::Single,
+                        Literal::Single(*right_speed),
+                    ),
+                ];
+                terminals.extend(duration_terminals(duration));
+                terminals.push(interrupts());
+                terminals
+            }
+            BlockType::Motor {
+                port,
+                speed,
+                duration,
+                ..
+            } => {
+                let mut terminals = vec![
+                    Terminal::ports("Port", layer, port.to_string()),
+                    Terminal::input("Speed", DataType::Single, Literal::Single(*speed)),
+                ];
+                terminals.extend(duration_terminals(duration));
+                terminals.push(interrupts());
+                terminals
+            }
+            BlockType::MotorTankOff { ports, brake } => vec![
+                Terminal::ports("Ports", layer, format!("{}+{}", ports.0, ports.1)),
+                Terminal::input(
+                    "Brake\\ At\\ End",
+                    DataType::Boolean,
+                    Literal::Boolean(*brake),
+                ),
+                interrupts(),
+            ],
+            BlockType::MotorOff { port, brake, .. } => vec![
+                Terminal::ports("Port", layer, port.to_string()),
+                Terminal::input(
+                    "Brake\\ At\\ End",
+                    DataType::Boolean,
+                    Literal::Boolean(*brake),
+                ),
+                interrupts(),
+            ],
+            BlockType::SensorCompare {
+                port, terminals, ..
+            } => {
+                let mut all = vec![Terminal::ports("Port", layer, port.to_string())];
+                let (inputs, outputs): (Vec<_>, Vec<_>) = terminals
+                    .iter()
+                    .cloned()
+                    .partition(|terminal| terminal.direction == Direction::Input);
+                all.extend(inputs);
+                all.push(interrupts());
+                // The reading goes straight to the paired structure, so the outputs aren't drawn
+                all.extend(outputs);
+                all
+            }
+            BlockType::Wait(wait) => {
+                let mut terminals = wait_terminals(wait, layer);
+                terminals.push(interrupts());
+                terminals
+            }
+            BlockType::Loop { condition, .. } => loop_condition_terminals(condition, layer),
+            BlockType::Variable { name, kind, access } => {
+                let name = Terminal::input(
+                    VARIABLE_NAME_TERMINAL,
+                    DataType::String,
+                    Literal::String(name.clone()),
+                );
+                let value = match access {
+                    VariableAccess::Read => {
+                        Terminal::output(VALUE_OUT_TERMINAL, kind.data_type()).drawn()
+                    }
+                    VariableAccess::Write(value) => {
+                        Terminal::input(VALUE_IN_TERMINAL, kind.data_type(), value.clone())
+                    }
+                };
+                vec![name.hidden(), value]
+            }
+            // The value is set in the block itself
+            BlockType::Constant { kind, value } => vec![
+                Terminal::input(VALUE_IN_TERMINAL, kind.data_type(), value.clone()).hidden(),
+                Terminal::output(VALUE_OUT_TERMINAL, kind.data_type()).drawn(),
+            ],
+            // The unknown block's terminals were preserved as unknown children
+            BlockType::Switch { .. } | BlockType::Unknown { .. } => vec![],
+        };
+        for terminal in &mut terminals {
+            terminal.wire = self.data_wires.get(&terminal.id).cloned();
+        }
+        terminals
+    }
+
+    /// Every terminal of the block, including the sequence ones and the selector of a switch
+    pub fn terminals(&self) -> Vec<Terminal> {
+        let mut terminals = self.method_terminals();
+        if let BlockType::Switch {
+            selector: Some(selector),
+            ..
+        } = &self.ty
+        {
+            terminals.push(Terminal {
+                wire: self.data_wires.get(&selector.id).cloned(),
+                ..selector.clone()
+            });
+        }
+        terminals.extend(self.sequence_in.iter().map(Terminal::sequence));
+        terminals.extend(self.sequence_out.iter().map(Terminal::sequence));
+        terminals
+    }
+}
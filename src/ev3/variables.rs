@@ -0,0 +1,235 @@
+//! The project's global variables, and the blocks reading and writing them
+
+use super::manifest::Variable;
+use super::parser::{Block, BlockType, Diagram, Id, VariableAccess};
+use super::project::{File, Project};
+
+/// A block reading or writing a variable, see [`Project::variable_uses`]
+#[derive(Clone, Debug, PartialEq)]
+pub struct VariableUse<'a> {
+    /// Name of the program the block is in
+    pub file: &'a str,
+    /// The block's id after the ids of the structures and cases it's in, like `n0/D5/n11`
+    pub path: Vec<Id>,
+    pub block: &'a Block,
+    pub access: &'a VariableAccess,
+}
+
+impl File {
+    /// Every variable block in the program by its path, including the ones that never run
+    pub fn variable_blocks(&self) -> Vec<(Vec<Id>, &Block)> {
+        let mut blocks = vec![];
+        collect_variable_blocks(&self.diagram, &mut vec![], &mut blocks);
+        blocks
+    }
+}
+
+fn collect_variable_blocks<'a>(
+    diagram: &'a Diagram,
+    scope: &mut Vec<Id>,
+    blocks: &mut Vec<(Vec<Id>, &'a Block)>,
+) {
+    let mut ids: Vec<_> = diagram.blocks.keys().collect();
+    ids.sort_by(|a, b| a.cmp_natural(b));
+    for id in ids {
+        let block = &diagram.blocks[id];
+        scope.push(id.clone());
+        match &block.ty {
+            BlockType::Variable { .. } => blocks.push((scope.clone(), block)),
+            BlockType::Switch { cases, .. } => {
+                for case in cases {
+                    scope.push(case.id.clone());
+                    collect_variable_blocks(&case.diagram, scope, blocks);
+                    scope.pop();
+                }
+            }
+            BlockType::Loop { diagram, .. } => collect_variable_blocks(diagram, scope, blocks),
+            _ => {}
+        }
+        scope.pop();
+    }
+}
+
+impl Project {
+    /// The global variables the programs share, from the manifest
+    pub fn variables(&self) -> &[Variable] {
+        &self.manifest().variables
+    }
+
+    pub fn variable(&self, name: &str) -> Option<&Variable> {
+        self.variables()
+            .iter()
+            .find(|variable| variable.name == name)
+    }
+
+    /// Adds a variable the programs can use. Returns false, leaving the project as it was, when
+    /// there's already one with the same name
+    pub fn add_variable(&mut self, variable: Variable) -> bool {
+        if self.variable(&variable.name).is_some() {
+            return false;
+        }
+        self.manifest_mut().variables.push(variable);
+        true
+    }
+
+    /// Every block reading or writing the variable, going through the programs in order
+    pub fn variable_uses(&self, name: &str) -> Vec<VariableUse<'_>> {
+        let mut uses = vec![];
+        for file in self.files() {
+            for (path, block) in file.variable_blocks() {
+                let BlockType::Variable {
+                    name: used, access, ..
+                } = &block.ty
+                else {
+                    continue;
+                };
+                if used == name {
+                    uses.push(VariableUse {
+                        file: &file.name,
+                        path,
+                        block,
+                        access,
+                    });
+                }
+            }
+        }
+        uses
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ev3::builder::FileBuilder;
+    use crate::ev3::manifest::{Manifest, Variable};
+    use crate::ev3::parser::{BlockType, DataType, Id, ValueKind, VariableAccess};
+    use crate::ev3::project::{File, Project};
+    use crate::ev3::terminal::Literal;
+    use std::fs;
+
+    #[test]
+    fn finds_readers_and_writers() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/examples/2blocks.ev3");
+        let mut project = Project::open(path).unwrap();
+        assert!(project.add_variable(Variable {
+            name: "Speed".into(),
+            kind: ValueKind::Numeric,
+        }));
+        let variable = |access| BlockType::Variable {
+            name: "Speed".into(),
+            kind: ValueKind::Numeric,
+            access,
+        };
+        let file = FileBuilder::new()
+            .start()
+            .block(variable(VariableAccess::Write(Literal::Single(50.0))))
+            .and_then(|builder| {
+                builder.block(BlockType::Constant {
+                    kind: ValueKind::Boolean,
+                    value: Literal::Boolean(true),
+                })
+            })
+            .and_then(|builder| builder.block(variable(VariableAccess::Read)))
+            .unwrap()
+            .build("Variables.ev3p");
+        assert_eq!(File::new("Variables.ev3p", file.to_bytes()).unwrap(), file);
+        project.add_file(file);
+
+        let saved =
+            std::env::temp_dir().join(format!("mindstormer-variables-{}.ev3", std::process::id()));
+        project.save(&saved).unwrap();
+        let reopened = Project::open(&saved);
+        std::fs::remove_file(&saved).unwrap();
+        let project = reopened.unwrap();
+        assert_eq!(project.variable("Speed").unwrap().kind, ValueKind::Numeric);
+        let uses: Vec<_> = project
+            .variable_uses("Speed")
+            .into_iter()
+            .map(|u| (u.file, u.path, u.access.clone()))
+            .collect();
+        assert_eq!(
+            uses,
+            [
+                (
+                    "Variables.ev3p",
+                    vec![Id::new("n2")],
+                    VariableAccess::Write(Literal::Single(50.0))
+                ),
+                ("Variables.ev3p", vec![Id::new("n6")], VariableAccess::Read),
+            ]
+        );
+    }
+
+    #[test]
+    fn only_takes_the_kinds_of_values_the_ev3_has() {
+        // Written by hand in the format of the other examples, since there's no program with
+        // variables saved by the EV3 software yet
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/examples/variables.ev3p");
+        let contents = fs::read_to_string(path).unwrap();
+        let file = File::new("variables.ev3p", contents.clone().into_bytes()).unwrap();
+        let types: Vec<_> = ["n2", "n4", "n6"]
+            .into_iter()
+            .map(|id| file.diagram.blocks[&Id::new(id)].ty.clone())
+            .collect();
+        assert_eq!(
+            types,
+            [
+                BlockType::Constant {
+                    kind: ValueKind::Numeric,
+                    value: Literal::Single(75.0),
+                },
+                BlockType::Variable {
+                    name: "Speed".into(),
+                    kind: ValueKind::Numeric,
+                    access: VariableAccess::Write(Literal::Single(0.0)),
+                },
+                BlockType::Variable {
+                    name: "Stopped".into(),
+                    kind: ValueKind::BooleanArray,
+                    access: VariableAccess::Read,
+                },
+            ]
+        );
+        assert!(file.validate().is_empty());
+        assert_eq!(File::new("variables.ev3p", file.to_bytes()).unwrap(), file);
+
+        assert_eq!(ValueKind::from_data_type(&DataType::Int32), None);
+        let int32 = contents
+            .replace("GlobalSetNumeric", "GlobalSetInt32")
+            .replace("\"Single\"", "\"Int32\"");
+        assert!(File::new("variables.ev3p", int32.into_bytes()).is_err());
+    }
+
+    #[test]
+    fn names_are_escaped() {
+        let name = "Left & \"Right\"";
+        let file = FileBuilder::new()
+            .start()
+            .block(BlockType::Variable {
+                name: name.into(),
+                kind: ValueKind::String,
+                access: VariableAccess::Write(Literal::String("<none>".into())),
+            })
+            .unwrap()
+            .build("Escaped.ev3p");
+        let bytes = file.to_bytes();
+        let written = String::from_utf8_lossy(&bytes);
+        assert!(written.contains("Left &amp; &quot;Right&quot;"));
+        assert!(written.contains("&lt;none&gt;"));
+        let parsed = File::new("Escaped.ev3p", bytes).unwrap();
+        assert_eq!(parsed, file);
+        assert_eq!(parsed.variable_blocks().len(), 1);
+
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/examples/2blocks.ev3");
+        let mut project = Project::open(path).unwrap();
+        project.add_variable(Variable {
+            name: name.into(),
+            kind: ValueKind::String,
+        });
+        let manifest = project.manifest().to_bytes();
+        assert!(
+            String::from_utf8_lossy(&manifest).contains("Name=\"Left &amp; &quot;Right&quot;\"")
+        );
+        let manifest = Manifest::new(manifest).unwrap();
+        assert_eq!(manifest.variables[0].name, name);
+    }
+}
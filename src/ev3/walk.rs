@@ -0,0 +1,120 @@
+//! Walking a program in the order its blocks run, going into the diagrams of structures
+
+use super::graph::SequenceGraph;
+use super::parser::{Block, BlockType, Case, Diagram, Id};
+use super::project::File;
+
+/// A diagram inside a structure, which the walk goes into after the structure's block
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Scope<'a> {
+    /// One of the cases of the switch `switch`, they're gone into in order
+    Case { switch: &'a Id, case: &'a Case },
+    /// The body of the loop `id`, which is gone into once even though it runs repeatedly
+    Loop { id: &'a Id, block: &'a Block },
+}
+
+/// What walking a program comes across
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Step<'a> {
+    /// A block runs
+    Block { id: &'a Id, block: &'a Block },
+    /// The walk goes into a diagram inside the structure it just came across
+    Enter(Scope<'a>),
+    /// The walk leaves the diagram it last went into
+    Exit(Scope<'a>),
+}
+
+/// Iterator over the [`Step`]s of a program, see [`File::walk`]
+pub struct Walk<'a> {
+    steps: std::vec::IntoIter<Step<'a>>,
+}
+
+impl<'a> Iterator for Walk<'a> {
+    type Item = Step<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.steps.next()
+    }
+}
+
+impl File {
+    /// Walks the blocks in the order they run, from each start block in turn following the
+    /// sequence wires. Structures are followed by their diagrams, between [`Step::Enter`] and
+    /// [`Step::Exit`]. Blocks the sequence doesn't get to aren't walked
+    pub fn walk(&self) -> Walk<'_> {
+        let mut steps = vec![];
+        walk_diagram(&self.diagram, &self.sequence(), &mut steps);
+        Walk {
+            steps: steps.into_iter(),
+        }
+    }
+}
+
+fn walk_diagram<'a>(diagram: &'a Diagram, graph: &SequenceGraph, steps: &mut Vec<Step<'a>>) {
+    for id in graph.order() {
+        let Some((id, block)) = diagram.blocks.get_key_value(&id) else {
+            continue;
+        };
+        steps.push(Step::Block { id, block });
+        let graphs = graph.nested.get(id).map(Vec::as_slice).unwrap_or_default();
+        match &block.ty {
+            BlockType::Switch { cases, .. } => {
+                for (case, graph) in cases.iter().zip(graphs) {
+                    let scope = Scope::Case { switch: id, case };
+                    steps.push(Step::Enter(scope));
+                    walk_diagram(&case.diagram, graph, steps);
+                    steps.push(Step::Exit(scope));
+                }
+            }
+            BlockType::Loop { diagram, .. } => {
+                for graph in graphs {
+                    let scope = Scope::Loop { id, block };
+                    steps.push(Step::Enter(scope));
+                    walk_diagram(diagram, graph, steps);
+                    steps.push(Step::Exit(scope));
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Scope, Step};
+    use crate::ev3::project::File;
+    use std::fs;
+
+    #[test]
+    fn walks_into_switches() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/examples/2blockandif.ev3p");
+        let file = File::new("2blockandif.ev3p", fs::read(path).unwrap()).unwrap();
+        let steps: Vec<_> = file
+            .walk()
+            .map(|step| match step {
+                Step::Block { id, .. } => id.to_string(),
+                Step::Enter(Scope::Case { case, .. }) => format!("enter {}", case.pattern),
+                Step::Exit(Scope::Case { case, .. }) => format!("exit {}", case.pattern),
+                Step::Enter(Scope::Loop { .. }) | Step::Exit(Scope::Loop { .. }) => {
+                    panic!("Found a loop")
+                }
+            })
+            .collect();
+        assert_eq!(
+            steps,
+            [
+                "n1",
+                "n2",
+                "n4",
+                "n6",
+                "n0",
+                "enter True",
+                "n11",
+                "exit True",
+                "enter False",
+                "n11",
+                "exit False",
+            ]
+        );
+    }
+}
@@ -0,0 +1,1117 @@
+use super::parser::{
+    Block, BlockType, Case, DataType, Diagram, Id, Item, LoopCondition, MotorDuration, MotorSize,
+    SequenceBlock, SequenceBlockType, SequenceNode, VariableAccess, Wait, Wire, CONSTANT_TARGET,
+    SEQUENCE_DATA_TYPE, VARIABLE_READ_TARGET, VARIABLE_WRITE_TARGET,
+};
+use super::preserved::{Extra, FileExtra, Node};
+use super::project::File;
+use super::terminal::{terminal_bounds, Terminal};
+use quick_xml::events::BytesDecl;
+use std::collections::{HashSet, VecDeque};
+
+const BOM: &str = "\u{feff}";
+// The EV3 software writes windows line endings and 4 space indents, so do the same
+const NEWLINE: &str = "\r\n";
+const INDENT: &str = "    ";
+
+const VIRTUAL_INSTRUMENT_ATTRIBUTES: &[(&str, &str)] = &[
+    ("IsTopLevel", "false"),
+    ("IsReentrant", "false"),
+    ("Version", "1.0.2.0"),
+    ("OverridingModelDefinitionType", "X3VIDocument"),
+    ("xmlns", "http://www.ni.com/VirtualInstrument.xsd"),
+];
+
+const FRONT_PANEL_CANVAS_ATTRIBUTES: &[(&str, &str)] = &[
+    ("xmlns", "http://schemas.microsoft.com/winfx/2006/xaml/presentation"),
+    ("xmlns:x", "http://schemas.microsoft.com/winfx/2006/xaml"),
+    ("xmlns:fpruntime", "clr-namespace:NationalInstruments.LabVIEW.FrontPanelRuntime;assembly=NationalInstruments.LabVIEW.FrontPanelRuntime"),
+    ("xmlns:Model", "clr-namespace:NationalInstruments.SourceModel.Designer;assembly=NationalInstruments.SourceModel"),
+    ("x:Name", "FrontPanel"),
+    ("Model:DesignerSurfaceProperties.CanSnapToObjects", "True"),
+    ("Model:DesignerSurfaceProperties.SnapToObjects", "True"),
+    ("Model:DesignerSurfaceProperties.ShowSnaplines", "True"),
+    ("Model:DesignerSurfaceProperties.ShowControlAdorners", "True"),
+    ("Width", "640"),
+    ("Height", "480"),
+];
+
+const ICON_PANEL_ATTRIBUTES: &[(&str, &str)] = &[
+    ("xmlns", "http://schemas.microsoft.com/winfx/2006/xaml/presentation"),
+    ("xmlns:x", "http://schemas.microsoft.com/winfx/2006/xaml"),
+    ("xmlns:Model0", "clr-namespace:NationalInstruments.LabVIEW.VI.Design;assembly=NationalInstruments.LabVIEW.VI.SourceModel"),
+    ("xmlns:fpruntime", "clr-namespace:NationalInstruments.LabVIEW.FrontPanelRuntime;assembly=NationalInstruments.LabVIEW.FrontPanelRuntime"),
+    ("Height", "56"),
+    ("Width", "56"),
+    ("Background", "#FFE0E0E0"),
+];
+
+/// Target of a wait block, which names its mode like `WaitGyroAngleCompare\.vix`
+fn wait_target(wait: &Wait) -> String {
+    match wait {
+        Wait::Time { .. } => "WaitTime\\.vix".into(),
+        Wait::Compare { sensor, .. } => format!("Wait{}Compare\\.vix", sensor.name()),
+        Wait::Change { sensor, .. } => format!("Wait{}Change\\.vix", sensor.name()),
+        Wait::TouchCompare { .. } => "WaitTouchCompare\\.vix".into(),
+        Wait::TouchChange { .. } => "WaitTouchChange\\.vix".into(),
+        Wait::ColorCompare { .. } => "WaitColorCompare\\.vix".into(),
+        Wait::ColorChange { .. } => "WaitColorChange\\.vix".into(),
+        Wait::ButtonsCompare { .. } => "WaitButtonsCompare\\.vix".into(),
+        Wait::ButtonsChange => "WaitButtonsChange\\.vix".into(),
+    }
+}
+
+/// Escapes what can't be written as it is in XML, along with the quotes around attribute values
+fn escape(text: &str, attribute: bool) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' if attribute => escaped.push_str("&quot;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// An attribute the writer produces, and how it's merged with what was preserved by the parser
+#[derive(Clone, Copy)]
+pub(crate) enum Value<'a> {
+    /// Always wins over the preserved value, and is left out entirely when it's `None`
+    Model(Option<&'a str>),
+    /// Only used when nothing was preserved for it
+    Default(&'a str),
+}
+
+/// Merges the attributes the writer produces with the preserved ones, keeping the preserved order
+/// so that unchanged elements are written back exactly as they were read
+pub(crate) fn merge_attributes<'a>(
+    attributes: &[(&'a str, Value<'a>)],
+    preserved: &'a [(String, String)],
+) -> Vec<(&'a str, &'a str)> {
+    let mut merged = vec![];
+    for (key, value) in preserved {
+        match attributes.iter().find(|(k, _)| k == key) {
+            Some((_, Value::Model(Some(v)))) => merged.push((key.as_str(), *v)),
+            Some((_, Value::Model(None))) => {}
+            Some((_, Value::Default(_))) | None => merged.push((key.as_str(), value.as_str())),
+        }
+    }
+    for (key, value) in attributes {
+        if preserved.iter().any(|(k, _)| k == key) {
+            continue;
+        }
+        match value {
+            Value::Model(Some(v)) | Value::Default(v) => merged.push((key, v)),
+            Value::Model(None) => {}
+        }
+    }
+    merged
+}
+
+/// Unknown children of an element that is being written, waiting for their turn
+struct Frame {
+    children: VecDeque<(usize, Node)>,
+    /// Index of the next child of the element
+    index: usize,
+}
+
+pub struct FileWriter {
+    out: String,
+    depth: usize,
+    frames: Vec<Frame>,
+}
+
+impl FileWriter {
+    /// Serializes `file` into the contents of a `.ev3p` file
+    pub fn write(file: &File) -> Vec<u8> {
+        let mut writer = FileWriter {
+            out: String::new(),
+            depth: 0,
+            frames: vec![],
+        };
+        writer.write_file(file);
+        writer.out.into_bytes()
+    }
+
+    /// Serializes a document that's kept as a tree of elements, like the project's manifest
+    pub fn write_document(decl: &BytesDecl, root: &Node) -> Vec<u8> {
+        let mut writer = FileWriter {
+            out: String::new(),
+            depth: 0,
+            frames: vec![],
+        };
+        writer.write_decl(decl);
+        writer.write_node(root);
+        writer.out.into_bytes()
+    }
+
+    fn write_decl(&mut self, decl: &BytesDecl) {
+        self.out.push_str(BOM);
+        self.out.push_str("<?");
+        self.out.push_str(&String::from_utf8_lossy(decl));
+        self.out.push_str("?>");
+    }
+
+    fn write_file(&mut self, file: &File) {
+        self.write_decl(&file.decl);
+
+        let default_extra = FileExtra::default();
+        let extra = file.extra.as_ref().unwrap_or(&default_extra);
+        self.outer_comments(extra, 0);
+        self.start(
+            "SourceFile",
+            &merge_attributes(
+                &[
+                    ("Version", Value::Model(Some(&file.version.number))),
+                    ("xmlns", Value::Model(Some(&file.version.namespace))),
+                ],
+                &extra.source_file,
+            ),
+        );
+        self.outer_comments(extra, 1);
+        self.start(
+            "Namespace",
+            &merge_attributes(&[("Name", Value::Model(Some("Project")))], &extra.namespace),
+        );
+        self.outer_comments(extra, 2);
+        let virtual_instrument = VIRTUAL_INSTRUMENT_ATTRIBUTES
+            .iter()
+            .map(|(k, v)| (*k, Value::Default(v)))
+            .collect::<Vec<_>>();
+        self.start(
+            "VirtualInstrument",
+            &merge_attributes(&virtual_instrument, &extra.virtual_instrument),
+        );
+
+        match &file.extra {
+            Some(extra) => {
+                for node in &extra.before_diagram {
+                    self.node(node);
+                }
+            }
+            None => {
+                self.start("FrontPanel", &[]);
+                self.empty("fpruntime:FrontPanelCanvas", FRONT_PANEL_CANVAS_ATTRIBUTES);
+                self.end("FrontPanel");
+            }
+        }
+
+        self.start(
+            "BlockDiagram",
+            &merge_attributes(
+                &[("Name", Value::Model(Some("__RootDiagram__")))],
+                &extra.block_diagram,
+            ),
+        );
+        self.write_diagram(&file.diagram);
+        self.end("BlockDiagram");
+
+        match &file.extra {
+            Some(extra) => {
+                for node in &extra.after_diagram {
+                    self.node(node);
+                }
+            }
+            None => {
+                self.start("Icon", &[]);
+                self.start("Model0:IconPanel", ICON_PANEL_ATTRIBUTES);
+                self.start("fpruntime:AnimationProperties.Animations", &[]);
+                self.empty("fpruntime:AnimationsContainer", &[]);
+                self.end("fpruntime:AnimationProperties.Animations");
+                self.start("fpruntime:EventProperties.Events", &[]);
+                self.empty("fpruntime:EventContainer", &[]);
+                self.end("fpruntime:EventProperties.Events");
+                self.end("Model0:IconPanel");
+                self.end("Icon");
+            }
+        }
+
+        self.end("VirtualInstrument");
+        self.outer_comments(extra, 4);
+        self.end("Namespace");
+        self.outer_comments(extra, 5);
+        self.end("SourceFile");
+        self.outer_comments(extra, 6);
+    }
+
+    /// Writes the comments that were preserved after `tags` of the start and end tags around the
+    /// virtual instrument
+    fn outer_comments(&mut self, extra: &FileExtra, tags: usize) {
+        for (_, comment) in extra.comments.iter().filter(|(at, _)| *at == tags) {
+            self.write_node(comment);
+        }
+    }
+
+    fn write_diagram(&mut self, diagram: &Diagram) {
+        let mut written_blocks = HashSet::new();
+        let mut written_wires = HashSet::new();
+        for item in &diagram.order {
+            match item {
+                Item::Block(id) => {
+                    if let Some(block) = diagram.blocks.get(id) {
+                        self.write_block(id, block);
+                        written_blocks.insert(id);
+                    }
+                }
+                Item::Wire(id) => {
+                    if let Some(wire) = diagram.wires.get(id) {
+                        self.write_wire(id, wire);
+                        written_wires.insert(id);
+                    }
+                }
+                Item::Node(node) => self.node(node),
+            }
+        }
+
+        // Whatever was added after parsing goes at the end
+        let mut blocks = diagram
+            .blocks
+            .iter()
+            .filter(|(id, _)| !written_blocks.contains(id))
+            .collect::<Vec<_>>();
+        blocks.sort_by(|(a, _), (b, _)| a.cmp_natural(b));
+        for (id, block) in blocks {
+            self.write_block(id, block);
+        }
+        let mut wires = diagram
+            .wires
+            .iter()
+            .filter(|(id, _)| !written_wires.contains(id))
+            .collect::<Vec<_>>();
+        wires.sort_by(|(a, _), (b, _)| a.cmp_natural(b));
+        for (id, wire) in wires {
+            self.write_wire(id, wire);
+        }
+    }
+
+    fn write_wire(&mut self, id: &Id, wire: &Wire) {
+        let mut joints = vec![wire.output.to_string()];
+        joints.extend(wire.joints.iter().map(|j| j.to_string()));
+        joints.push(wire.input.to_string());
+        for branch in &wire.branches {
+            joints.extend(branch.joints.iter().map(|j| j.to_string()));
+            joints.push(branch.input.to_string());
+        }
+        let joints = joints.join(" ");
+        self.empty(
+            "Wire",
+            &merge_attributes(
+                &[
+                    ("Id", Value::Model(Some(&id.0))),
+                    ("Joints", Value::Model(Some(&joints))),
+                ],
+                &wire.extra.attributes,
+            ),
+        );
+    }
+
+    fn write_block(&mut self, id: &Id, block: &Block) {
+        match &block.ty {
+            BlockType::Switch {
+                data_type,
+                paired,
+                selector,
+                cases,
+            } => {
+                self.write_switch(
+                    id,
+                    block,
+                    data_type,
+                    paired.as_ref(),
+                    selector.as_ref(),
+                    cases,
+                );
+                return;
+            }
+            BlockType::Loop {
+                interrupt_name,
+                condition_id,
+                entry,
+                exit,
+                diagram,
+                ..
+            } => {
+                self.start_with(
+                    "ConfigurableWhileLoop",
+                    &merge_attributes(
+                        &[
+                            ("Id", Value::Model(Some(&id.0))),
+                            ("Bounds", Value::Model(Some(&block.bounds.to_string()))),
+                            ("InterruptName", Value::Model(Some(interrupt_name))),
+                        ],
+                        &block.extra.attributes,
+                    ),
+                    &block.extra.children,
+                );
+                self.write_loop_condition(condition_id, block);
+                if let Some(sequence_in) = &block.sequence_in {
+                    self.write_sequence_terminal(sequence_in, block);
+                }
+                if let Some(sequence_out) = &block.sequence_out {
+                    self.write_sequence_terminal(sequence_out, block);
+                }
+                self.write_sequence_node("Output", entry);
+                self.write_sequence_node("Input", exit);
+                self.write_diagram(diagram);
+                self.end("ConfigurableWhileLoop");
+                return;
+            }
+            _ => {}
+        }
+        let bounds = block.bounds.to_string();
+        let wait;
+        let variable;
+        let (tag, target) = match &block.ty {
+            BlockType::Start => ("StartBlock", "X3\\.Lib:StartBlockTest"),
+            BlockType::MotorMove { duration, .. } => {
+                let target = match duration {
+                    MotorDuration::Unlimited => "MoveUnlimited\\.vix",
+                    MotorDuration::Rotations { .. } => "MoveDistanceRotations\\.vix",
+                    MotorDuration::Degrees { .. } => "MoveDistance\\.vix",
+                    MotorDuration::Seconds { .. } => "MoveTime\\.vix",
+                };
+                ("ConfigurableMethodCall", target)
+            }
+            BlockType::MotorTank { duration, .. } => {
+                let target = match duration {
+                    MotorDuration::Unlimited => "MoveTankUnlimited\\.vix",
+                    MotorDuration::Rotations { .. } => "MoveTankDistanceRotations\\.vix",
+                    MotorDuration::Degrees { .. } => "MoveTankDistance\\.vix",
+                    MotorDuration::Seconds { .. } => "MoveTankTime\\.vix",
+                };
+                ("ConfigurableMethodCall", target)
+            }
+            BlockType::Motor { size, duration, .. } => {
+                let target = match (size, duration) {
+                    (MotorSize::Large, MotorDuration::Unlimited) => "MotorUnlimited\\.vix",
+                    (MotorSize::Large, MotorDuration::Rotations { .. }) => {
+                        "MotorDistanceRotations\\.vix"
+                    }
+                    (MotorSize::Large, MotorDuration::Degrees { .. }) => "MotorDistance\\.vix",
+                    (MotorSize::Large, MotorDuration::Seconds { .. }) => "MotorTime\\.vix",
+                    (MotorSize::Medium, MotorDuration::Unlimited) => "MediumMotorUnlimited\\.vix",
+                    (MotorSize::Medium, MotorDuration::Rotations { .. }) => {
+                        "MediumMotorDistanceRotations\\.vix"
+                    }
+                    (MotorSize::Medium, MotorDuration::Degrees { .. }) => {
+                        "MediumMotorDistance\\.vix"
+                    }
+                    (MotorSize::Medium, MotorDuration::Seconds { .. }) => "MediumMotorTime\\.vix",
+                };
+                ("ConfigurableMethodCall", target)
+            }
+            BlockType::MotorTankOff { .. } => ("ConfigurableMethodCall", "MoveTankStop\\.vix"),
+            BlockType::MotorOff { size, .. } => {
+                let target = match size {
+                    MotorSize::Large => "MotorStop\\.vix",
+                    MotorSize::Medium => "MediumMotorStop\\.vix",
+                };
+                ("ConfigurableMethodCall", target)
+            }
+            BlockType::SensorCompare { target, .. } => {
+                ("PairedConfigurableMethodCall", target.as_str())
+            }
+            BlockType::Wait(w) => {
+                wait = wait_target(w);
+                ("ConfigurableMethodCall", wait.as_str())
+            }
+            BlockType::Variable { kind, access, .. } => {
+                let prefix = match access {
+                    VariableAccess::Read => VARIABLE_READ_TARGET,
+                    VariableAccess::Write(_) => VARIABLE_WRITE_TARGET,
+                };
+                variable = format!("{prefix}{}", kind.target_name());
+                ("ConfigurableMethodCall", variable.as_str())
+            }
+            BlockType::Constant { kind, .. } => {
+                variable = format!("{CONSTANT_TARGET}{}", kind.target_name());
+                ("ConfigurableMethodCall", variable.as_str())
+            }
+            BlockType::Switch { .. } | BlockType::Loop { .. } => {
+                unreachable!("Structures are written separately")
+            }
+            BlockType::Unknown { target } => ("ConfigurableMethodCall", target.as_str()),
+        };
+
+        self.start_with(
+            tag,
+            &merge_attributes(
+                &[
+                    ("Id", Value::Model(Some(&id.0))),
+                    ("Bounds", Value::Model(Some(&bounds))),
+                    ("Target", Value::Model(Some(target))),
+                    (
+                        "PairedStructure",
+                        Value::Model(block.ty.paired().map(|id| id.0.as_str())),
+                    ),
+                ],
+                &block.extra.attributes,
+            ),
+            &block.extra.children,
+        );
+        self.write_terminals(block.method_terminals(), &block.extra);
+        if let Some(sequence_in) = &block.sequence_in {
+            self.write_sequence_terminal(sequence_in, block);
+        }
+        if let Some(sequence_out) = &block.sequence_out {
+            self.write_sequence_terminal(sequence_out, block);
+        }
+        self.end(tag);
+    }
+
+    /// Writes the `ConfigurableMethodTerminal`s of a block, laying out the visible ones
+    fn write_terminals(&mut self, terminals: Vec<Terminal>, extra: &Extra) {
+        let mut visible = 0;
+        for terminal in terminals {
+            let terminal_bounds = if terminal.visible {
+                visible += 1;
+                terminal_bounds(visible - 1).to_string()
+            } else {
+                "0 0 0 0".into()
+            };
+            match &terminal.value {
+                Some(value) => self.start(
+                    "ConfigurableMethodTerminal",
+                    &[("ConfiguredValue", &value.to_string())],
+                ),
+                None => self.start("ConfigurableMethodTerminal", &[]),
+            }
+            self.empty(
+                "Terminal",
+                &merge_attributes(
+                    &[
+                        ("Id", Value::Model(Some(&terminal.id))),
+                        ("Direction", Value::Model(Some(terminal.direction.as_str()))),
+                        (
+                            "Wire",
+                            Value::Model(terminal.wire.as_ref().map(|id| id.0.as_str())),
+                        ),
+                        (
+                            "DataType",
+                            Value::Model(Some(&terminal.data_type.to_string())),
+                        ),
+                        ("Hotspot", Value::Default("0.5 1")),
+                        ("Bounds", Value::Default(&terminal_bounds)),
+                    ],
+                    extra.terminal(&terminal.id),
+                ),
+            );
+            self.end("ConfigurableMethodTerminal");
+        }
+    }
+
+    fn write_loop_condition(&mut self, id: &Id, block: &Block) {
+        let BlockType::Loop { condition, .. } = &block.ty else {
+            unreachable!("Only loops have a condition");
+        };
+        let target = match condition {
+            LoopCondition::Unlimited => "X3\\.Lib:StopNever",
+            LoopCondition::Count(_) => "X3\\.Lib:StopAfterCount",
+            LoopCondition::Time(_) => "X3\\.Lib:StopAfterTime",
+            LoopCondition::Logic { .. } => "X3\\.Lib:StopOnLogic",
+            LoopCondition::Sensor { target, .. } => target.as_str(),
+        };
+        self.start(
+            "ConfigurableWhileLoop.BuiltInMethod",
+            &[("CallType", "StopCondition")],
+        );
+        self.start(
+            "ConfigurableMethodCall",
+            &[("Id", &id.0), ("Bounds", "0 0 0 0"), ("Target", target)],
+        );
+        self.write_terminals(block.method_terminals(), &block.extra);
+        self.end("ConfigurableMethodCall");
+        self.end("ConfigurableWhileLoop.BuiltInMethod");
+    }
+
+    fn write_switch(
+        &mut self,
+        id: &Id,
+        block: &Block,
+        data_type: &DataType,
+        paired: Option<&Id>,
+        selector: Option<&Terminal>,
+        cases: &[Case],
+    ) {
+        let bounds = block.bounds.to_string();
+        let default = cases
+            .iter()
+            .find(|case| case.default)
+            .map(|case| case.id.0.as_str());
+        self.start_with(
+            "ConfigurableFlatCaseStructure",
+            &merge_attributes(
+                &[
+                    ("Id", Value::Model(Some(&id.0))),
+                    ("Bounds", Value::Model(Some(&bounds))),
+                    ("DataType", Value::Model(Some(&data_type.to_string()))),
+                    ("UserSelectorBounds", Value::Default("0 0 0 0")),
+                    ("Default", Value::Model(default)),
+                    (
+                        "PairedConfigurableMethodCall",
+                        Value::Model(paired.map(|id| id.0.as_str())),
+                    ),
+                ],
+                &block.extra.attributes,
+            ),
+            &block.extra.children,
+        );
+        if let Some(sequence_in) = &block.sequence_in {
+            self.write_sequence_terminal(sequence_in, block);
+        }
+        if let Some(sequence_out) = &block.sequence_out {
+            self.write_sequence_terminal(sequence_out, block);
+        }
+        if let Some(selector) = selector {
+            let wire = block.data_wires.get(&selector.id);
+            self.empty(
+                "Terminal",
+                &merge_attributes(
+                    &[
+                        ("Id", Value::Model(Some(&selector.id))),
+                        ("Direction", Value::Model(Some(selector.direction.as_str()))),
+                        ("Wire", Value::Model(wire.map(|id| id.0.as_str()))),
+                        (
+                            "DataType",
+                            Value::Model(Some(&selector.data_type.to_string())),
+                        ),
+                        ("Hotspot", Value::Default("0 0.5")),
+                        ("Bounds", Value::Default("0 0 0 0")),
+                    ],
+                    block.extra.terminal(&selector.id),
+                ),
+            );
+        }
+        for case in cases {
+            let bounds = case.bounds.to_string();
+            self.start(
+                "ConfigurableFlatCaseStructure.Case",
+                &merge_attributes(
+                    &[
+                        ("Id", Value::Model(Some(&case.id.0))),
+                        ("Bounds", Value::Model(Some(&bounds))),
+                        ("Pattern", Value::Model(Some(&case.pattern))),
+                    ],
+                    &case.extra.attributes,
+                ),
+            );
+            self.write_sequence_node("Output", &case.entry);
+            self.write_sequence_node("Input", &case.exit);
+            self.write_diagram(&case.diagram);
+            self.end("ConfigurableFlatCaseStructure.Case");
+        }
+        self.end("ConfigurableFlatCaseStructure");
+    }
+
+    fn write_sequence_node(&mut self, id: &str, node: &SequenceNode) {
+        // The sequence comes out of the `Output` node and goes into the `Input` one
+        let (direction, hotspot) = match id {
+            "Output" => ("Output", "1 0.5"),
+            _ => ("Input", "0 0.5"),
+        };
+        let bounds = node.bounds.to_string();
+        self.start(
+            "SequenceNode",
+            &merge_attributes(
+                &[
+                    ("Id", Value::Model(Some(id))),
+                    ("Bounds", Value::Model(Some(&bounds))),
+                ],
+                &node.extra.attributes,
+            ),
+        );
+        self.empty(
+            "Terminal",
+            &merge_attributes(
+                &[
+                    ("Id", Value::Model(Some("SequenceTerminal"))),
+                    ("Direction", Value::Model(Some(direction))),
+                    (
+                        "Wire",
+                        Value::Model(node.wire_id.as_ref().map(|id| id.0.as_str())),
+                    ),
+                    ("DataType", Value::Model(Some(SEQUENCE_DATA_TYPE))),
+                    ("Hotspot", Value::Default(hotspot)),
+                    ("Bounds", Value::Default("0 0 18 18")),
+                ],
+                node.extra.terminal("SequenceTerminal"),
+            ),
+        );
+        self.end("SequenceNode");
+    }
+
+    fn write_sequence_terminal(&mut self, sequence: &SequenceBlock, block: &Block) {
+        // Sequence terminals sit in the middle of the left and right edges
+        let y = (block.bounds.height / 2.0).floor() - 12.0;
+        let (id, direction, hotspot, terminal_bounds) = match sequence.ty {
+            SequenceBlockType::In => ("SequenceIn", "Input", "0 0.5", format!("0 {y} 18 18")),
+            // The sequence carries on through the paired structure instead
+            SequenceBlockType::Out if matches!(block.ty, BlockType::SensorCompare { .. }) => {
+                ("SequenceOut", "Output", "1 0.5", "0 0 0 0".into())
+            }
+            SequenceBlockType::Out => (
+                "SequenceOut",
+                "Output",
+                "1 0.5",
+                format!("{} {y} 18 18", block.bounds.width - 18.0),
+            ),
+        };
+        self.empty(
+            "Terminal",
+            &merge_attributes(
+                &[
+                    ("Id", Value::Model(Some(id))),
+                    ("Direction", Value::Model(Some(direction))),
+                    (
+                        "Wire",
+                        Value::Model(sequence.wire_id.as_ref().map(|id| id.0.as_str())),
+                    ),
+                    ("DataType", Value::Model(Some(SEQUENCE_DATA_TYPE))),
+                    ("Hotspot", Value::Default(hotspot)),
+                    ("Bounds", Value::Default(&terminal_bounds)),
+                ],
+                block.extra.terminal(id),
+            ),
+        );
+    }
+
+    fn newline(&mut self) {
+        self.out.push_str(NEWLINE);
+        for _ in 0..self.depth {
+            self.out.push_str(INDENT);
+        }
+    }
+
+    fn open_tag(&mut self, name: &str, attributes: &[(&str, &str)]) {
+        self.newline();
+        self.out.push('<');
+        self.out.push_str(name);
+        for (key, value) in attributes {
+            self.out.push(' ');
+            self.out.push_str(key);
+            self.out.push_str("=\"");
+            self.out.push_str(&escape(value, true));
+            self.out.push('"');
+        }
+    }
+
+    /// Called before writing any child of the current element, to write the unknown children
+    /// that came before it
+    fn next_child(&mut self) {
+        let Some(frame) = self.frames.last_mut() else {
+            return;
+        };
+        let mut nodes = vec![];
+        while frame
+            .children
+            .front()
+            .is_some_and(|(index, _)| *index <= frame.index)
+        {
+            nodes.push(frame.children.pop_front().unwrap().1);
+            frame.index += 1;
+        }
+        frame.index += 1;
+        for node in nodes {
+            self.write_node(&node);
+        }
+    }
+
+    fn start(&mut self, name: &str, attributes: &[(&str, &str)]) {
+        self.start_with(name, attributes, &[]);
+    }
+
+    /// Starts an element, placing the unknown `children` back where they were
+    fn start_with(&mut self, name: &str, attributes: &[(&str, &str)], children: &[(usize, Node)]) {
+        self.next_child();
+        self.open_tag(name, attributes);
+        self.out.push('>');
+        self.depth += 1;
+        self.frames.push(Frame {
+            children: children.iter().cloned().collect(),
+            index: 0,
+        });
+    }
+
+    fn empty(&mut self, name: &str, attributes: &[(&str, &str)]) {
+        self.next_child();
+        self.open_tag(name, attributes);
+        self.out.push_str(" />");
+    }
+
+    fn end(&mut self, name: &str) {
+        if let Some(frame) = self.frames.pop() {
+            for (_, node) in frame.children {
+                self.write_node(&node);
+            }
+        }
+        self.depth -= 1;
+        self.newline();
+        self.out.push_str("</");
+        self.out.push_str(name);
+        self.out.push('>');
+    }
+
+    /// Writes a preserved element as a child of the current element
+    fn node(&mut self, node: &Node) {
+        self.next_child();
+        self.write_node(node);
+    }
+
+    fn write_node(&mut self, node: &Node) {
+        if node.is_comment() {
+            self.newline();
+            self.out.push_str("<!--");
+            self.out.push_str(node.text.as_deref().unwrap_or_default());
+            self.out.push_str("-->");
+            return;
+        }
+        let attributes = node
+            .attributes
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect::<Vec<_>>();
+        self.open_tag(&node.name, &attributes);
+        if let Some(text) = &node.text {
+            self.out.push('>');
+            self.out.push_str(&escape(text, false));
+            self.out.push_str("</");
+            self.out.push_str(&node.name);
+            self.out.push('>');
+            return;
+        }
+        if node.children.is_empty() {
+            self.out.push_str(" />");
+            return;
+        }
+        self.out.push('>');
+        self.depth += 1;
+        for child in &node.children {
+            self.write_node(child);
+        }
+        self.depth -= 1;
+        self.newline();
+        self.out.push_str("</");
+        self.out.push_str(&node.name);
+        self.out.push('>');
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FileWriter;
+    use crate::ev3::parser::{
+        Block, BlockType, Bounds, BrickButton, ButtonState, ChangeDirection, Color, Comparison,
+        DataType, Diagram, Id, Item, LoopCondition, Port, SequenceNode, Wait, WaitSensor, Wire,
+        WireEnd,
+    };
+    use crate::ev3::project::File;
+    use crate::ev3::terminal::{Literal, Terminal};
+    use std::fs;
+    use std::io::Read;
+
+    #[test]
+    fn examples_round_trip() {
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/examples");
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            let name = path.file_name().unwrap().to_str().unwrap();
+            if !name.ends_with(".ev3p") {
+                continue;
+            }
+
+            let parsed = File::new(name, fs::read(&path).unwrap()).unwrap();
+            let written = FileWriter::write(&parsed);
+            let reparsed = File::new(name, written).unwrap();
+            assert_eq!(parsed, reparsed, "`{name}` changed after writing it out");
+        }
+    }
+
+    #[test]
+    fn archives_round_trip_byte_for_byte() {
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/examples");
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension() != Some("ev3".as_ref()) {
+                continue;
+            }
+
+            let mut zip = zip::ZipArchive::new(fs::File::open(&path).unwrap()).unwrap();
+            for i in 0..zip.len() {
+                let mut entry = zip.by_index(i).unwrap();
+                let name = entry.name().to_owned();
+                if !name.ends_with(".ev3p") {
+                    continue;
+                }
+                let mut contents = vec![];
+                entry.read_to_end(&mut contents).unwrap();
+
+                let parsed = File::new_preserving(&name, contents.clone()).unwrap();
+                let written = FileWriter::write(&parsed);
+                assert!(
+                    written == contents,
+                    "`{name}` in `{}` changed after writing it out",
+                    path.display()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn comments_round_trip() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/examples/2blocks.ev3");
+        let mut zip = zip::ZipArchive::new(fs::File::open(path).unwrap()).unwrap();
+        let mut contents = String::new();
+        zip.by_name("Program1.ev3p")
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        // Comments in front of an element, indented like it
+        let comment = |contents: &mut String, before: &str, text: &str| {
+            let at = contents.find(before).unwrap();
+            let line = contents[..at].rfind("\r\n").unwrap();
+            let indent = contents[line..at].to_owned();
+            contents.insert_str(line, &format!("{indent}<!--{text}-->"));
+        };
+        comment(&mut contents, "<StartBlock", " Runs first ");
+        comment(
+            &mut contents,
+            "<fpruntime:FrontPanelCanvas",
+            "Nothing on the panel",
+        );
+        comment(&mut contents, "<SourceFile", " Saved by hand ");
+        comment(&mut contents, "<Namespace", "");
+        comment(&mut contents, "<BlockDiagram", " The program ");
+        let end = "</VirtualInstrument>";
+        let after = format!("{end}\r\n        <!-- After the program -->");
+        contents = contents.replace(end, &after);
+        contents.push_str("\r\n<!-- The end -->");
+
+        let parsed = File::new_preserving("Program1.ev3p", contents.clone().into_bytes()).unwrap();
+        assert_eq!(FileWriter::write(&parsed), contents.as_bytes());
+        // They're skipped when nothing is preserved
+        let parsed = File::new("Program1.ev3p", contents.into_bytes()).unwrap();
+        assert!(!FileWriter::write(&parsed).windows(4).any(|w| w == b"<!--"));
+    }
+
+    #[test]
+    fn unknown_terminals_round_trip() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/examples/2blocks.ev3");
+        let mut zip = zip::ZipArchive::new(fs::File::open(path).unwrap()).unwrap();
+        let mut contents = String::new();
+        zip.by_name("Program1.ev3p")
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        let terminal = |value: &str, attributes: &str| {
+            let indent = "\r\n                    ";
+            format!(
+                "{indent}<ConfigurableMethodTerminal{value}>\
+                 {indent}    <Terminal {attributes} Hotspot=\"0.5 1\" Bounds=\"0 0 0 0\" />\
+                 {indent}</ConfigurableMethodTerminal>"
+            )
+        };
+        let steering = contents
+            .find("<ConfigurableMethodTerminal ConfiguredValue=\"0\">")
+            .unwrap();
+        let line = contents[..steering].rfind("\r\n").unwrap();
+        let acceleration = terminal(
+            " ConfiguredValue=\"10\"",
+            "Id=\"Acceleration\" Direction=\"Input\" DataType=\"Single\"",
+        );
+        contents.insert_str(line, &acceleration);
+        let speed = contents.find("Id=\"Speed\"").unwrap();
+        let line = speed
+            + contents[speed..]
+                .find("</ConfigurableMethodTerminal>")
+                .unwrap();
+        let done = terminal("", "Id=\"Done\" Direction=\"Output\" DataType=\"Boolean\"");
+        contents.insert_str(line + "</ConfigurableMethodTerminal>".len(), &done);
+
+        assert!(File::new("Program1.ev3p", contents.clone().into_bytes()).is_err());
+        let parsed = File::new_preserving("Program1.ev3p", contents.clone().into_bytes()).unwrap();
+        let block = &parsed.diagram.blocks[&Id("n2".into())];
+        assert!(matches!(block.ty, BlockType::MotorMove { .. }));
+        assert_eq!(block.extra.children.len(), 2);
+        assert_eq!(FileWriter::write(&parsed), contents.as_bytes());
+    }
+
+    /// A loop around a copy of the example's motor block, whose id is the same as outside of it
+    fn looped(motor: &Block, condition: LoopCondition) -> BlockType {
+        let mut body = Diagram::default();
+        body.blocks.insert(Id("n2".into()), motor.clone());
+        body.order.push(Item::Block(Id("n2".into())));
+        let node = |x| SequenceNode {
+            bounds: Bounds {
+                x,
+                y: 40.0,
+                width: 18.0,
+                height: 18.0,
+            },
+            wire_id: None,
+            extra: Default::default(),
+        };
+        BlockType::Loop {
+            interrupt_name: "01".into(),
+            condition,
+            condition_id: Id("n5".into()),
+            entry: node(0.0),
+            exit: node(200.0),
+            diagram: body,
+        }
+    }
+
+    /// Checks a block round trips after the blocks of an example, in the place of a copy of its
+    /// motor block, which is passed to `ty` to make the block from
+    fn assert_round_trips(ty: impl Fn(&Block) -> BlockType) {
+        let path = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/examples/1block-connected.ev3p"
+        );
+        let mut file = File::new("blocks.ev3p", fs::read(path).unwrap()).unwrap();
+        let mut block = file.diagram.blocks[&Id("n2".into())].clone();
+        block.ty = ty(&block);
+        file.diagram.blocks.insert(Id("n4".into()), block);
+        file.diagram.order.push(Item::Block(Id("n4".into())));
+
+        let written = FileWriter::write(&file);
+        let reparsed = File::new("blocks.ev3p", written).unwrap();
+        assert_eq!(file, reparsed);
+    }
+
+    #[test]
+    fn loops_round_trip() {
+        let touch = vec![
+            Terminal::input(
+                "Pressed\\,\\ Released\\ or\\ Bumped",
+                DataType::Single,
+                Literal::Single(1.0),
+            ),
+            Terminal::output("Result", DataType::Boolean),
+        ];
+        let conditions = [
+            LoopCondition::Unlimited,
+            LoopCondition::Count(10),
+            LoopCondition::Time(2.5),
+            LoopCondition::Logic { until: true },
+            LoopCondition::Sensor {
+                target: "TouchCompare\\.vix".into(),
+                port: Port::One,
+                terminals: touch,
+            },
+        ];
+        for condition in conditions {
+            assert_round_trips(|motor| looped(motor, condition.clone()));
+        }
+    }
+
+    #[test]
+    fn waits_round_trip() {
+        let waits = [
+            Wait::Time { seconds: 1.5 },
+            Wait::Compare {
+                sensor: WaitSensor::GyroAngle(Port::Two),
+                comparison: Comparison::GreaterOrEqual,
+                threshold: 90.0,
+            },
+            Wait::Compare {
+                sensor: WaitSensor::Timer(3),
+                comparison: Comparison::Greater,
+                threshold: 5.0,
+            },
+            Wait::Change {
+                sensor: WaitSensor::MotorDegrees(Port::C),
+                direction: ChangeDirection::Any,
+                amount: 30.0,
+            },
+            Wait::TouchCompare {
+                port: Port::One,
+                state: ButtonState::Bumped,
+            },
+            Wait::TouchChange { port: Port::One },
+            Wait::ColorCompare {
+                port: Port::Three,
+                colors: vec![Color::Black, Color::Red],
+            },
+            Wait::ColorChange { port: Port::Three },
+            Wait::ButtonsCompare {
+                buttons: vec![BrickButton::Center],
+                state: ButtonState::Pressed,
+            },
+            Wait::ButtonsChange,
+        ];
+        for wait in waits {
+            assert_round_trips(|_| BlockType::Wait(wait.clone()));
+        }
+    }
+
+    #[test]
+    fn data_wires_round_trip() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/examples/2blockandif.ev3p");
+        let mut file = File::new("2blockandif.ev3p", fs::read(path).unwrap()).unwrap();
+        let wire = Id("w20".into());
+        let blocks = &mut file.diagram.blocks;
+        let touch = blocks.get_mut(&Id("n6".into())).unwrap();
+        touch.data_wires.insert("Value".into(), wire.clone());
+        let motor = blocks.get_mut(&Id("n4".into())).unwrap();
+        motor.data_wires.insert("Speed".into(), wire.clone());
+        let end = |node: &str, terminal: &str| WireEnd {
+            node: Id(node.into()),
+            terminal: terminal.into(),
+        };
+        let data = Wire {
+            input: end("n4", "Speed"),
+            output: end("n6", "Value"),
+            joints: vec![],
+            branches: vec![],
+            extra: Default::default(),
+        };
+        file.diagram.wires.insert(wire.clone(), data);
+        file.diagram.order.push(Item::Wire(wire));
+
+        let written = FileWriter::write(&file);
+        let text = String::from_utf8(written.clone()).unwrap();
+        assert!(text.contains(r#"<Wire Id="w20" Joints="N(n6:Value) N(n4:Speed)" />"#));
+        assert!(text.contains(r#"<Terminal Id="Speed" Direction="Input" Wire="w20""#));
+        let reparsed = File::new("2blockandif.ev3p", written).unwrap();
+        assert_eq!(file, reparsed);
+    }
+
+    #[test]
+    fn branched_wires_round_trip() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/examples/2blockandif.ev3p");
+        let contents = fs::read_to_string(path).unwrap();
+        // The touch sensor's reading sets the speed of both motor blocks before it
+        let joints = "N(n6:Value) v(20) h(-372) N(n2:Speed) v(0) h(132) N(n4:Speed)";
+        let contents = contents
+            .replacen(
+                r#"<Terminal Id="Speed" Direction="Input""#,
+                r#"<Terminal Id="Speed" Direction="Input" Wire="w20""#,
+                2,
+            )
+            .replace(
+                r#"<Terminal Id="Value" Direction="Output""#,
+                r#"<Terminal Id="Value" Direction="Output" Wire="w20""#,
+            )
+            .replace(
+                "</BlockDiagram>",
+                &format!(
+                    "    <Wire Id=\"w20\" Joints=\"{joints}\" />\n            </BlockDiagram>"
+                ),
+            );
+        let file = File::new("2blockandif.ev3p", contents.into_bytes()).unwrap();
+        let wire = &file.diagram.wires[&Id("w20".into())];
+        let inputs: Vec<_> = wire.inputs().map(|end| end.node.as_str()).collect();
+        assert_eq!(inputs, ["n2", "n4"]);
+        assert_eq!(wire.branches[0].joints.len(), 2);
+        assert!(file.validate().is_empty());
+
+        let written = FileWriter::write(&file);
+        let text = String::from_utf8(written.clone()).unwrap();
+        assert!(text.contains(&format!(r#"<Wire Id="w20" Joints="{joints}" />"#)));
+        let reparsed = File::new("2blockandif.ev3p", written).unwrap();
+        assert_eq!(file, reparsed);
+    }
+}
@@ -0,0 +1,48 @@
+//! Reads, edits and writes LEGO Mindstorms EV3 projects.
+//!
+//! A [`Project`] is a `.ev3` archive holding programs, each of which is a [`File`] whose blocks
+//! live in a [`Diagram`]. Structures like switches and loops have diagrams of their own.
+//!
+//! ```
+//! use mindstormer::{BlockType, Project};
+//!
+//! let mut project = Project::open("examples/2blocks.ev3")?;
+//! project.set_title("Renamed");
+//! for file in project.files() {
+//!     for (id, block) in &file.diagram.blocks {
+//!         if let BlockType::MotorMove { speed, .. } = &block.ty {
+//!             println!("{id} drives at {speed}");
+//!         }
+//!     }
+//! }
+//! # let path = std::env::temp_dir().join("mindstormer-doc.ev3");
+//! project.save(&path)?;
+//! # anyhow::Ok(())
+//! ```
+//!
+//! Parsing fails on anything that isn't understood. [`Project::open_preserving`] keeps it
+//! instead, so that projects using unsupported blocks can still be edited and saved.
+//!
+//! [`File::walk`] goes through the blocks in the order they run, and [`File::validate`] checks
+//! that they're wired together properly. Blocks can be inserted, removed and moved around with
+//! [`File::insert_after`] and friends, which keep the wiring intact, and [`File::lay_out`] tidies
+//! them up afterwards.
+
+mod ev3;
+mod utils;
+
+pub use ev3::builder::FileBuilder;
+pub use ev3::error::{Diagnostic, Error, Position, Result, Severity};
+pub use ev3::graph::{Problem, ProblemKind, SequenceGraph};
+pub use ev3::manifest::{Manifest, Reference, Variable};
+pub use ev3::parser::{
+    Block, BlockType, Bounds, Branch, BrickButton, ButtonState, Case, ChangeDirection, Color,
+    Comparison, DataType, Diagram, Id, Item, Joint, LoopCondition, MotorDuration, MotorSize, Port,
+    SequenceBlock, SequenceBlockType, SequenceNode, ValueKind, VariableAccess, Wait, WaitSensor,
+    Wire, WireEnd,
+};
+pub use ev3::preserved::{Extra, FileExtra, Node};
+pub use ev3::project::{File, Project, Version};
+pub use ev3::terminal::{Direction, Literal, Terminal};
+pub use ev3::variables::VariableUse;
+pub use ev3::walk::{Scope, Step, Walk};
@@ -1,10 +1,283 @@
-mod ev3;
-mod utils;
+use anyhow::{bail, Context};
+use mindstormer::{BlockType, Diagnostic, Diagram, Error, File, Item, Project, VariableAccess};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
 
-use ev3::project::Project;
+const USAGE: &str = "\
+Usage: mindstormer <command> [--preserve] <input> [output]
+
+Commands:
+    info <project> [output]      Print the title, description and year of a project
+    list <project> [output]      List the programs in a project
+    dump <input> [output]        Print the blocks and wires of every program
+    extract <project> <dir>      Extract a project to a directory
+    pack <dir> <project>         Build a project from a directory
+    convert <input> <output>     Convert between formats, picked by extension: `.ev3` for
+                                 projects, `.ev3p` for single programs, anything else for
+                                 extracted projects
+    check <input> [output]       List every problem in the programs instead of stopping at
+                                 the first, along with the blocks that aren't supported
+    floating <input> [output]    List the blocks that never run because they aren't wired
+                                 to a start block
+
+Inputs can be `.ev3` projects or directories they were extracted to, and `dump`, `convert`,
+`check` and `floating` also take `.ev3p` programs. Outputs default to the standard output when they're
+optional. `--preserve` keeps what isn't understood instead of failing on it, which `extract`
+and `pack` always do.";
 
 fn main() -> anyhow::Result<()> {
-    let project = Project::get_project_from_zip("examples/1block.ev3")?;
-    project.output_file("out.ev3")?;
+    let mut preserve = false;
+    let mut args = vec![];
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            "--preserve" => preserve = true,
+            "-h" | "--help" => {
+                println!("{USAGE}");
+                return Ok(());
+            }
+            _ => args.push(arg),
+        }
+    }
+    let Some((command, paths)) = args.split_first() else {
+        bail!("Missing command\n\n{USAGE}");
+    };
+    let (input, output) = match paths {
+        [input] => (input.as_str(), None),
+        [input, output] => (input.as_str(), Some(output.as_str())),
+        _ => bail!("Expected an input and an optional output\n\n{USAGE}"),
+    };
+    let output_required = || output.context(format!("Missing output for {command}\n\n{USAGE}"));
+
+    match command.as_str() {
+        "info" => {
+            let project = open_project(input, preserve)?;
+            let info = format!(
+                "Title: {}\nDescription: {}\nYear: {}\n",
+                project.title(),
+                project.description(),
+                project.year()
+            );
+            print_or_write(output, &info)
+        }
+        "list" => {
+            let project = open_project(input, preserve)?;
+            let mut list = String::new();
+            for file in project.files() {
+                writeln!(list, "{}", file.name)?;
+            }
+            print_or_write(output, &list)
+        }
+        "dump" => {
+            let mut dump = String::new();
+            for file in open_programs(input, preserve)? {
+                writeln!(dump, "{}", file.name)?;
+                dump_diagram(&mut dump, &file.diagram, 1)?;
+            }
+            print_or_write(output, &dump)
+        }
+        "extract" => Ok(open_project(input, true)?.write_dir(output_required()?)?),
+        "pack" => Ok(Project::read_dir(input, true)?.save(output_required()?)?),
+        "convert" => convert(input, output_required()?, preserve),
+        "check" => print_or_write(output, &check(input)?),
+        "floating" => {
+            let mut list = String::new();
+            for file in open_programs(input, preserve)? {
+                for path in file.floating_blocks() {
+                    let path: Vec<_> = path.iter().map(|id| id.as_str()).collect();
+                    writeln!(list, "{}: {}", file.name, path.join("/"))?;
+                }
+            }
+            print_or_write(output, &list)
+        }
+        _ => bail!("Unknown command `{command}`\n\n{USAGE}"),
+    }
+}
+
+fn is_program(path: &str) -> bool {
+    path.ends_with(".ev3p")
+}
+
+/// Opens a project from a `.ev3` archive or a directory it was extracted to
+fn open_project(path: &str, preserve: bool) -> anyhow::Result<Project> {
+    let project = if Path::new(path).is_dir() {
+        Project::read_dir(path, preserve)
+    } else if is_program(path) {
+        bail!("Expected a project, found the program {path}")
+    } else if preserve {
+        Project::open_preserving(path)
+    } else {
+        Project::open(path)
+    };
+    Ok(project?)
+}
+
+/// The name and contents of a `.ev3p` program
+fn read_program(path: &str) -> anyhow::Result<(&str, Vec<u8>)> {
+    let contents = fs::read(path).context(format!("Failed reading {path}"))?;
+    let name = Path::new(path)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or(path);
+    Ok((name, contents))
+}
+
+fn open_program(path: &str, preserve: bool) -> anyhow::Result<File> {
+    let (name, contents) = read_program(path)?;
+    let file = if preserve {
+        File::new_preserving(name, contents)
+    } else {
+        File::new(name, contents)
+    };
+    Ok(file?)
+}
+
+/// The programs of a project, or the program itself for `.ev3p` files
+fn open_programs(path: &str, preserve: bool) -> anyhow::Result<Vec<File>> {
+    if is_program(path) {
+        return Ok(vec![open_program(path, preserve)?]);
+    }
+    let mut project = open_project(path, preserve)?;
+    Ok(std::mem::take(project.files_mut()))
+}
+
+fn convert(input: &str, output: &str, preserve: bool) -> anyhow::Result<()> {
+    match (is_program(input), is_program(output)) {
+        (true, true) => {
+            let file = open_program(input, preserve)?;
+            fs::write(output, file.to_bytes()).context(format!("Failed writing {output}"))
+        }
+        (false, false) => {
+            let project = open_project(input, preserve)?;
+            if output.ends_with(".ev3") {
+                project.save(output)?;
+            } else {
+                project.write_dir(output)?;
+            }
+            Ok(())
+        }
+        (true, false) => bail!("Can't convert the program {input} to a project"),
+        (false, true) => {
+            bail!("Can't convert a project to the program {output}, extract it instead")
+        }
+    }
+}
+
+/// Every diagnostic, followed by how many blocks there are of each target that isn't supported
+fn check(input: &str) -> anyhow::Result<String> {
+    let diagnostics: Vec<Diagnostic> = if is_program(input) {
+        let (name, contents) = read_program(input)?;
+        File::diagnose(name, contents).1
+    } else if Path::new(input).is_dir() {
+        Project::diagnose_dir(input)?
+    } else {
+        Project::diagnose(input)?
+    };
+    let mut report = String::new();
+    let mut targets = BTreeMap::new();
+    for diagnostic in &diagnostics {
+        writeln!(report, "{diagnostic}")?;
+        if let Error::UnknownTarget { target, .. } = &diagnostic.error {
+            *targets.entry(target.as_str()).or_insert(0) += 1;
+        }
+    }
+    if !targets.is_empty() {
+        writeln!(report, "\nUnsupported targets:")?;
+        for (target, count) in targets {
+            writeln!(report, "    {target} ({count})")?;
+        }
+    }
+    Ok(report)
+}
+
+fn print_or_write(output: Option<&str>, contents: &str) -> anyhow::Result<()> {
+    match output {
+        Some(path) => fs::write(path, contents).context(format!("Failed writing {path}")),
+        None => {
+            print!("{contents}");
+            Ok(())
+        }
+    }
+}
+
+fn block_name(ty: &BlockType) -> String {
+    match ty {
+        BlockType::Start => "Start".into(),
+        BlockType::MotorMove { .. } => "MoveSteering".into(),
+        BlockType::MotorTank { .. } => "MoveTank".into(),
+        BlockType::Motor { size, .. } => format!("{size:?}Motor"),
+        BlockType::MotorTankOff { .. } => "MoveTankOff".into(),
+        BlockType::MotorOff { size, .. } => format!("{size:?}MotorOff"),
+        BlockType::Switch { data_type, .. } => format!("Switch on {data_type}"),
+        BlockType::SensorCompare { target, .. } => format!("SensorCompare {target}"),
+        BlockType::Wait(_) => "Wait".into(),
+        BlockType::Loop { .. } => "Loop".into(),
+        BlockType::Variable {
+            name,
+            access: VariableAccess::Read,
+            ..
+        } => format!("Read {name}"),
+        BlockType::Variable {
+            name,
+            access: VariableAccess::Write(_),
+            ..
+        } => format!("Write {name}"),
+        BlockType::Constant { value, .. } => format!("Constant {value}"),
+        BlockType::Unknown { target } => format!("Unknown {target}"),
+    }
+}
+
+/// Writes the blocks of a diagram with their terminals, then its wires, nesting the diagrams of
+/// structures under them
+fn dump_diagram(out: &mut String, diagram: &Diagram, depth: usize) -> anyhow::Result<()> {
+    let indent = "    ".repeat(depth);
+    for item in &diagram.order {
+        let Item::Block(id) = item else {
+            continue;
+        };
+        let block = &diagram.blocks[id];
+        writeln!(
+            out,
+            "{indent}{id} {} at {}",
+            block_name(&block.ty),
+            block.bounds
+        )?;
+        for terminal in block.terminals() {
+            // Every method call has it, and it's always 0
+            if terminal.id.starts_with("InterruptsToListenFor") {
+                continue;
+            }
+            match (&terminal.value, &terminal.wire) {
+                (_, Some(wire)) => writeln!(out, "{indent}    {} -> {wire}", terminal.id)?,
+                (Some(value), None) => writeln!(out, "{indent}    {} = {value}", terminal.id)?,
+                (None, None) => {}
+            }
+        }
+        match &block.ty {
+            BlockType::Switch { cases, .. } => {
+                for case in cases {
+                    let default = if case.default { " (default)" } else { "" };
+                    writeln!(out, "{indent}    case {}{default}", case.pattern)?;
+                    dump_diagram(out, &case.diagram, depth + 2)?;
+                }
+            }
+            BlockType::Loop {
+                condition, diagram, ..
+            } => {
+                writeln!(out, "{indent}    until {condition:?}")?;
+                dump_diagram(out, diagram, depth + 2)?;
+            }
+            _ => {}
+        }
+    }
+    for item in &diagram.order {
+        let Item::Wire(id) = item else {
+            continue;
+        };
+        let wire = &diagram.wires[id];
+        let inputs: Vec<_> = wire.inputs().map(|input| input.to_string()).collect();
+        writeln!(out, "{indent}{id} {} -> {}", wire.output, inputs.join(", "))?;
+    }
     Ok(())
 }
@@ -9,6 +9,11 @@ impl VecReadWrapper {
     pub fn new(buf: Vec<u8>) -> Self {
         Self { buf, start: 0 }
     }
+
+    /// Everything that's being read, including what already was
+    pub fn contents(&self) -> &[u8] {
+        &self.buf
+    }
 }
 
 impl Read for VecReadWrapper {
@@ -38,6 +43,7 @@ impl BufRead for VecReadWrapper {
 
 pub mod xml {
     use super::VecReadWrapper;
+    use crate::ev3::error::{Cause, Position};
     use anyhow::Context;
     use quick_xml::{
         events::{
@@ -49,19 +55,78 @@ pub mod xml {
     };
     pub type XMLReader = Reader<VecReadWrapper>;
 
-    pub fn collect_to_vec(mut reader: XMLReader) -> anyhow::Result<Vec<Event<'static>>> {
+    const BOM: &[u8] = b"\xEF\xBB\xBF";
+
+    /// Finds the line and column of byte offsets, which have to come in order
+    struct Locator {
+        offset: usize,
+        line: usize,
+        line_start: usize,
+    }
+
+    impl Locator {
+        fn locate(&mut self, contents: &[u8], offset: usize) -> Position {
+            let offset = offset.min(contents.len());
+            for (i, &b) in contents[self.offset..offset].iter().enumerate() {
+                if b == b'\n' {
+                    self.line += 1;
+                    self.line_start = self.offset + i + 1;
+                }
+            }
+            self.offset = offset;
+            let column = String::from_utf8_lossy(&contents[self.line_start..offset])
+                .chars()
+                .count()
+                + 1;
+            Position {
+                line: self.line,
+                column,
+                offset,
+            }
+        }
+    }
+
+    /// Reads all the events, along with where each of them starts
+    pub fn collect_to_vec(
+        mut reader: XMLReader,
+    ) -> anyhow::Result<Vec<(Event<'static>, Position)>> {
+        // The reader skips the byte order mark without counting it
+        let bom = if reader.get_ref().contents().starts_with(BOM) {
+            BOM.len()
+        } else {
+            0
+        };
+        // Nobody sees the mark, so columns start after it
+        let mut locator = Locator {
+            offset: bom,
+            line: 1,
+            line_start: bom,
+        };
         let mut result = vec![];
         loop {
+            // Text is trimmed, so skip the whitespace the event starts with
+            let offset = reader.buffer_position() + bom;
+            let contents = reader.get_ref().contents();
+            let offset = offset
+                + contents[offset..]
+                    .iter()
+                    .take_while(|b| b.is_ascii_whitespace())
+                    .count();
+            let position = locator.locate(contents, offset);
             let mut buf = vec![];
-            let event = reader
-                .read_event_into(&mut buf)
-                .context("File had invalid xml")?
-                .into_owned();
+            let event = match reader.read_event_into(&mut buf) {
+                Ok(event) => event.into_owned(),
+                Err(source) => {
+                    let offset = reader.buffer_position() + bom;
+                    let position = locator.locate(reader.get_ref().contents(), offset);
+                    return Err(Cause::Xml { position, source }.into());
+                }
+            };
             if let Event::Eof = event {
-                result.push(Event::Eof);
+                result.push((Event::Eof, position));
                 break Ok(result);
             } else {
-                result.push(event);
+                result.push((event, position));
             }
         }
     }
@@ -76,17 +141,32 @@ pub mod xml {
         Ok((name, prefix))
     }
 
-    #[derive(Debug)]
+    /// Joins a name and its prefix back together, like `fpruntime:FrontPanelCanvas`
+    pub fn qualified_name(name: &str, prefix: Option<&str>) -> String {
+        match prefix {
+            Some(prefix) => format!("{prefix}:{name}"),
+            None => name.to_owned(),
+        }
+    }
+
+    #[derive(Clone, Debug)]
     pub struct ParsedAttribute {
         pub key: (String, Option<String>),
         pub value: String,
     }
 
     impl ParsedAttribute {
+        pub fn qualified_key(&self) -> String {
+            qualified_name(&self.key.0, self.key.1.as_deref())
+        }
+
         fn parse(attr: &Attribute) -> anyhow::Result<ParsedAttribute> {
             let (name, prefix) = extract_name_from_qname(attr.key)?;
-            let value = String::from_utf8(attr.value.clone().into_owned())
-                .context(format!("Invalid UTF-8 in {name} tag value"))?;
+            // Kept as plain text, the writer escapes it again
+            let value = attr
+                .unescape_value()
+                .context(format!("Invalid {name} tag value"))?
+                .into_owned();
             Ok(ParsedAttribute {
                 key: (name, prefix),
                 value,